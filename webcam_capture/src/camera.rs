@@ -157,7 +157,11 @@ impl Camera {
                         match prediction_service.predict(frame_data).await {
                             Ok(predictions) => {
                                 let elapsed = start.elapsed().as_millis();
-                                metrics.record_prediction_duration(elapsed as u64, "camera");
+                                metrics.record_prediction_duration(
+                                    elapsed as u64,
+                                    "camera",
+                                    prediction_service.transport().as_str(),
+                                );
                                 fps_prediction_frame_count.fetch_add(1, Ordering::Relaxed);
 
                                 let mut lock = predictions_lock2.lock().await;
@@ -1,12 +1,29 @@
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+use std::{path::PathBuf, time::Duration};
 
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct Config {
     pub server: ServerConfig,
     #[serde(deserialize_with = "deserialize_log_level")]
     pub log_level: LogLevel,
     pub prediction_service: PredictionServiceConfig,
     pub camera: CameraConfig,
+    #[serde(default)]
+    pub tracing: TracingConfig,
+}
+
+impl Serialize for LogLevel {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+/// Keeps secrets out of `--print-config`
+fn serialize_redacted<S: serde::Serializer>(
+    value: &Option<String>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    value.as_ref().map(|_| "<redacted>").serialize(serializer)
 }
 
 fn deserialize_log_level<'de, D>(deserializer: D) -> Result<LogLevel, D::Error>
@@ -17,7 +34,7 @@ where
     s.try_into().map_err(serde::de::Error::custom)
 }
 
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct ServerConfig {
     pub host: String,
     pub port: u16,
@@ -29,19 +46,109 @@ impl ServerConfig {
     }
 }
 
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct PredictionServiceConfig {
     pub host: String,
     pub port: u16,
+    pub tls: Option<PredictionServiceTlsConfig>,
+    /// API key sent as a bearer token, required when the service has auth enabled
+    #[serde(serialize_with = "serialize_redacted")]
+    pub auth_token: Option<String>,
+    /// Deadline for each call, so a stuck server cannot stall the camera loop
+    #[serde(default = "default_request_timeout_ms")]
+    pub request_timeout_ms: u64,
+    /// Socket shared with a co-located prediction service, `host` and `port`
+    /// are used when it cannot be reached
+    #[serde(default)]
+    pub unix_socket: Option<PathBuf>,
+    /// Asks the service not to cache the camera frames, which are rarely
+    /// byte-identical and would only evict the results of other clients
+    #[serde(default)]
+    pub skip_result_cache: bool,
+}
+
+fn default_request_timeout_ms() -> u64 {
+    1000
 }
 
 impl PredictionServiceConfig {
+    pub fn get_request_timeout(&self) -> Duration {
+        Duration::from_millis(self.request_timeout_ms)
+    }
+
     pub fn get_address(&self) -> String {
-        format!("http://{}:{}", self.host, self.port)
+        let scheme = if self.tls.is_some() { "https" } else { "http" };
+        format!("{}://{}:{}", scheme, self.host, self.port)
+    }
+
+    pub fn get_unix_address(&self) -> Option<String> {
+        self.unix_socket
+            .as_ref()
+            .map(|path| format!("unix://{}", path.display()))
+    }
+}
+
+/// CA used to verify the prediction service, plus an optional client
+/// certificate and key for mutual TLS.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct PredictionServiceTlsConfig {
+    pub ca_file: PathBuf,
+    #[serde(default)]
+    pub cert_file: Option<PathBuf>,
+    #[serde(default)]
+    pub key_file: Option<PathBuf>,
+    /// Name to verify the server certificate against, defaults to `host`
+    #[serde(default)]
+    pub domain: Option<String>,
+    /// How often the files are checked for changes, 0 disables reloading
+    #[serde(default = "default_tls_reload_interval_secs")]
+    pub reload_interval_secs: u64,
+}
+
+impl PredictionServiceTlsConfig {
+    pub fn get_files(&self) -> Vec<&PathBuf> {
+        std::iter::once(&self.ca_file)
+            .chain(&self.cert_file)
+            .chain(&self.key_file)
+            .collect()
     }
 }
 
-#[derive(Clone, Deserialize, Debug)]
+fn default_tls_reload_interval_secs() -> u64 {
+    30
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct TracingConfig {
+    /// Export spans to the collector set by `OTEL_EXPORTER_OTLP_ENDPOINT`
+    #[serde(default = "default_otlp_enabled")]
+    pub otlp: bool,
+    /// Share of frames whose prediction is traced. Every frame starts a trace, so
+    /// the default keeps the export well below the camera frame rate. Raise it while
+    /// debugging, e.g. `tracing.sample_ratio: 1.0` in the profile or
+    /// `WC_TRACING__SAMPLE_RATIO=1.0`
+    #[serde(default = "default_trace_sample_ratio")]
+    pub sample_ratio: f64,
+}
+
+impl Default for TracingConfig {
+    fn default() -> Self {
+        Self {
+            otlp: default_otlp_enabled(),
+            sample_ratio: default_trace_sample_ratio(),
+        }
+    }
+}
+
+fn default_otlp_enabled() -> bool {
+    true
+}
+
+fn default_trace_sample_ratio() -> f64 {
+    0.01
+}
+
+#[derive(Clone, Deserialize, Serialize, Debug)]
 pub struct CameraConfig {
     #[serde(default = "default_stream_fps")]
     pub stream_fps: u64,
@@ -71,36 +178,6 @@ impl CameraConfig {
     }
 }
 
-#[derive(Debug, Deserialize, Clone)]
-pub enum Environment {
-    Local,
-    Production,
-}
-
-impl Environment {
-    pub fn as_str(&self) -> &'static str {
-        match self {
-            Environment::Local => "local",
-            Environment::Production => "production",
-        }
-    }
-}
-
-impl TryFrom<String> for Environment {
-    type Error = String;
-
-    fn try_from(s: String) -> Result<Self, Self::Error> {
-        match s.to_lowercase().as_str() {
-            "local" => Ok(Self::Local),
-            "production" => Ok(Self::Production),
-            other => Err(format!(
-                "{} is not a supported environment. Use either `local` or `production`.",
-                other
-            )),
-        }
-    }
-}
-
 #[derive(Debug, Deserialize, Clone)]
 pub enum LogLevel {
     Debug,
@@ -131,30 +208,110 @@ impl TryFrom<String> for LogLevel {
     }
 }
 
-pub fn get_configuration() -> Result<Config, config::ConfigError> {
-    let base_path = std::env::current_dir().expect("Failed to determine the current directory");
-    let configuration_directory = base_path.join("configuration");
+/// Where the configuration is read from: `<config_dir>/base.yaml`, then
+/// `<config_dir>/<profile>.yaml`, then `WC_` environment variables and finally
+/// the `overrides`, each source taking precedence over the previous ones.
+#[derive(Debug, Clone)]
+pub struct ConfigOptions {
+    pub config_dir: PathBuf,
+    pub profile: String,
+    /// `key=value` pairs, keys are dotted paths such as `camera.stream_fps`
+    pub overrides: Vec<(String, String)>,
+}
+
+/// Parses a `key=value` override as given to `--set`.
+pub fn parse_override(s: &str) -> Result<(String, String), String> {
+    match s.split_once('=') {
+        Some((key, value)) if !key.trim().is_empty() => {
+            Ok((key.trim().to_string(), value.to_string()))
+        }
+        _ => Err(format!("Invalid override `{}`, expected `key=value`", s)),
+    }
+}
 
-    let environment: Environment = std::env::var("APP_ENVIRONMENT")
-        .unwrap_or_else(|_| "local".into())
-        .try_into()
-        .expect("Failed to parse APP_ENVIRONMENT");
+pub fn get_configuration(options: &ConfigOptions) -> Result<Config, config::ConfigError> {
+    let profile_file = options.config_dir.join(format!("{}.yaml", options.profile));
+    if !profile_file.exists() {
+        return Err(config::ConfigError::Message(format!(
+            "Profile `{}` not found, {:?} does not exist",
+            options.profile, profile_file
+        )));
+    }
 
-    let config = config::Config::builder()
-        .add_source(config::File::from(
-            configuration_directory.join("base.yaml"),
-        ))
-        .add_source(config::File::from(
-            configuration_directory.join(format!("{}.yaml", environment.as_str())),
-        ))
+    let mut builder = config::Config::builder()
+        .add_source(config::File::from(options.config_dir.join("base.yaml")))
+        .add_source(config::File::from(profile_file))
         .add_source(
             config::Environment::with_prefix("WC")
                 .prefix_separator("_")
                 .separator("__"),
-        )
-        .build()?;
+        );
+    for (key, value) in &options.overrides {
+        builder = builder.set_override(key.as_str(), value.as_str())?;
+    }
 
-    let config: Config = config.try_deserialize::<Config>()?;
+    builder.build()?.try_deserialize::<Config>()
+}
+
+/// Returns every problem found in the configuration rather than stopping at the first one.
+pub fn check_configuration(config: &Config) -> Vec<String> {
+    let mut errors = Vec::new();
+
+    if config.server.port == 0 {
+        errors.push("server.port: must not be 0".to_string());
+    }
+
+    let prediction_service = &config.prediction_service;
+    if prediction_service.host.trim().is_empty() {
+        errors.push("prediction_service.host: must not be empty".to_string());
+    }
+    if prediction_service.port == 0 {
+        errors.push("prediction_service.port: must not be 0".to_string());
+    }
+    if prediction_service.request_timeout_ms == 0 {
+        errors.push("prediction_service.request_timeout_ms: must be greater than 0".to_string());
+    }
+    if let Some(token) = &prediction_service.auth_token {
+        if tonic::metadata::AsciiMetadataValue::try_from(format!("Bearer {}", token)).is_err() {
+            errors.push(
+                "prediction_service.auth_token: must only contain visible ASCII characters"
+                    .to_string(),
+            );
+        }
+    }
+    if let Some(tls_config) = &prediction_service.tls {
+        for path in tls_config.get_files() {
+            if let Err(e) = std::fs::File::open(path) {
+                errors.push(format!(
+                    "prediction_service.tls: cannot read {:?}: {}",
+                    path, e
+                ));
+            }
+        }
+        if tls_config.cert_file.is_some() != tls_config.key_file.is_some() {
+            errors.push(
+                "prediction_service.tls: cert_file and key_file must be set together".to_string(),
+            );
+        }
+    }
+
+    let camera = &config.camera;
+    if camera.stream_fps == 0 {
+        errors.push("camera.stream_fps: must be greater than 0".to_string());
+    }
+    if camera.prediction_fps == 0 {
+        errors.push("camera.prediction_fps: must be greater than 0".to_string());
+    }
+    if camera.prediction_fps > camera.stream_fps {
+        errors.push(format!(
+            "camera.prediction_fps: {} is above camera.stream_fps ({}), frames can only be predicted as fast as they are captured",
+            camera.prediction_fps, camera.stream_fps
+        ));
+    }
+
+    if !(0.0..=1.0).contains(&config.tracing.sample_ratio) {
+        errors.push("tracing.sample_ratio: must be between 0 and 1".to_string());
+    }
 
-    Ok(config)
+    errors
 }
@@ -10,3 +10,4 @@ pub mod app;
 pub mod config;
 
 pub use app::start_app;
+pub use telemetry::init_tracer;
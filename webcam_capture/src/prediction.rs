@@ -1,19 +1,28 @@
 use crate::{
-    bounding_box::BoundingBoxWithLabels, config::PredictionServiceConfig, cv_utils::CvUtilsError,
+    bounding_box::BoundingBoxWithLabels,
+    config::{PredictionServiceConfig, PredictionServiceTlsConfig},
+    cv_utils::CvUtilsError,
+    telemetry::inject_current_context,
+};
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
 };
-use std::time::{SystemTime, UNIX_EPOCH};
 use thiserror::Error;
 use tokio::{
     sync::Mutex,
-    time::{sleep, timeout, Duration},
+    time::{interval, sleep, timeout, Duration},
 };
 use tonic::{
-    transport::{Channel, Error},
+    metadata::{AsciiMetadataValue, MetadataMap},
+    transport::{Certificate, Channel, ClientTlsConfig, Endpoint, Error, Identity},
     Request, Status,
 };
 use tracing::instrument;
 use yolo_proto::{
     yolo_service_client::YoloServiceClient, BoundingBox, ColorLabel, Empty, ImageFrame,
+    InferenceSettings, UpdateSettingsRequest,
 };
 
 #[derive(Error, Debug)]
@@ -26,56 +35,283 @@ pub enum PredictionServiceError {
     GrpcRequestFailed(#[from] Status),
     #[error("Cv utils error: {0}")]
     OpenCvUtilsError(#[from] CvUtilsError),
+    #[error("Failed to read TLS file {path:?}: {source}")]
+    TlsFileError {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    #[error("Invalid TLS configuration: {0}")]
+    InvalidTlsConfig(String),
+    #[error("Invalid auth token: it must only contain visible ASCII characters")]
+    InvalidAuthToken,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Transport {
+    Unix,
+    Tcp,
+}
+
+impl Transport {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Transport::Unix => "unix",
+            Transport::Tcp => "tcp",
+        }
+    }
+}
+
+/// Settings version the inference service returns with its responses
+const SETTINGS_VERSION_HEADER: &str = "settings-version";
+
+fn settings_version(metadata: &MetadataMap) -> Option<u64> {
+    metadata
+        .get(SETTINGS_VERSION_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse().ok())
+}
+
+/// Labels with the settings version they were fetched at
+#[derive(Default)]
+struct ClassLabels {
+    labels: Vec<ColorLabel>,
+    settings_version: Option<u64>,
 }
 
 pub struct PredictionService {
-    client: Mutex<YoloServiceClient<Channel>>,
-    class_labels: Mutex<Vec<ColorLabel>>,
+    client: Arc<Mutex<YoloServiceClient<Channel>>>,
+    transport: Transport,
+    class_labels: Mutex<ClassLabels>,
+    auth_header: Option<AsciiMetadataValue>,
+    request_timeout: Duration,
+    skip_result_cache: bool,
 }
 
 impl PredictionService {
     pub async fn new(
         prediction_config: &PredictionServiceConfig,
     ) -> Result<Self, PredictionServiceError> {
-        let client = Self::get_client(prediction_config.get_address()).await?;
+        let auth_header = prediction_config
+            .auth_token
+            .as_ref()
+            .map(|token| format!("Bearer {}", token).parse())
+            .transpose()
+            .map_err(|_| PredictionServiceError::InvalidAuthToken)?;
+
+        let (client, transport) = Self::connect(prediction_config).await?;
 
         let service = Self {
-            client: Mutex::new(client),
-            class_labels: Mutex::new(Vec::new()),
+            client: Arc::new(Mutex::new(client)),
+            transport,
+            class_labels: Mutex::new(ClassLabels::default()),
+            auth_header,
+            request_timeout: prediction_config.get_request_timeout(),
+            skip_result_cache: prediction_config.skip_result_cache,
         };
 
-        // We need the client to initialize the labels
+        if let Some(tls_config) = prediction_config
+            .tls
+            .as_ref()
+            .filter(|tls_config| transport == Transport::Tcp && tls_config.reload_interval_secs > 0)
         {
-            let mut client = service.client.lock().await;
-            let request = Request::new(Empty {});
-            let response = client.get_yolo_class_labels(request).await?;
-            let labels = response.into_inner();
+            Self::spawn_tls_reload_task(
+                prediction_config.clone(),
+                tls_config.clone(),
+                service.client.clone(),
+            );
+        }
+
+        // We need the client to initialize the labels
+        let mut client = service.client.lock().await;
+        service.refresh_labels(&mut client).await?;
+        drop(client);
+
+        Ok(service)
+    }
 
-            drop(client);
+    /// The admin API changes the labels' names, colors and thresholds at runtime,
+    /// they are fetched again whenever a response carries a newer settings version.
+    async fn refresh_labels(
+        &self,
+        client: &mut YoloServiceClient<Channel>,
+    ) -> Result<(), PredictionServiceError> {
+        let response = client.get_yolo_class_labels(self.request(Empty {})).await?;
+        let settings_version = settings_version(response.metadata());
+
+        *self.class_labels.lock().await = ClassLabels {
+            labels: response.into_inner().class_labels,
+            settings_version,
+        };
+        Ok(())
+    }
+
+    pub fn transport(&self) -> Transport {
+        self.transport
+    }
 
-            let mut class_labels = service.class_labels.lock().await;
-            *class_labels = labels.class_labels;
+    /// Prefers the Unix socket when one is configured, falling back to TCP when
+    /// the socket cannot be reached, e.g. when the services run on different hosts.
+    async fn connect(
+        prediction_config: &PredictionServiceConfig,
+    ) -> Result<(YoloServiceClient<Channel>, Transport), PredictionServiceError> {
+        if let Some(unix_address) = prediction_config.get_unix_address() {
+            // No TLS on the socket, it is protected by its file permissions
+            let endpoint = Endpoint::from_shared(unix_address.clone())?
+                .timeout(prediction_config.get_request_timeout());
+            match Self::get_client(endpoint, 5).await {
+                Ok(client) => {
+                    tracing::info!("Connected to the inference service on {}", unix_address);
+                    return Ok((client, Transport::Unix));
+                }
+                Err(e) => tracing::warn!(
+                    "Failed to connect to {}, falling back to TCP: {}",
+                    unix_address,
+                    e
+                ),
+            }
         }
 
-        Ok(service)
+        let endpoint = Self::get_endpoint(prediction_config)?;
+        let client = Self::get_client(endpoint, 20).await?;
+        Ok((client, Transport::Tcp))
+    }
+
+    /// Wraps a message in a request carrying the configured credentials, deadline
+    /// and the current trace context.
+    fn request<T>(&self, message: T) -> Request<T> {
+        let mut request = Request::new(message);
+        request.set_timeout(self.request_timeout);
+        inject_current_context(request.metadata_mut());
+        if let Some(auth_header) = &self.auth_header {
+            request
+                .metadata_mut()
+                .insert("authorization", auth_header.clone());
+        }
+        request
+    }
+
+    /// Admin calls carry the caller's credentials rather than the configured token,
+    /// so the `admin` scope is never granted to whoever can reach this service.
+    fn admin_request<T>(
+        &self,
+        message: T,
+        authorization: Option<AsciiMetadataValue>,
+    ) -> Request<T> {
+        let mut request = self.request(message);
+        match authorization {
+            Some(authorization) => {
+                request
+                    .metadata_mut()
+                    .insert("authorization", authorization);
+            }
+            None => {
+                request.metadata_mut().remove("authorization");
+            }
+        }
+        request
+    }
+
+    fn get_endpoint(
+        prediction_config: &PredictionServiceConfig,
+    ) -> Result<Endpoint, PredictionServiceError> {
+        // Enforced on our side too, `grpc-timeout` alone relies on the server honoring it
+        let endpoint = Endpoint::from_shared(prediction_config.get_address())?
+            .timeout(prediction_config.get_request_timeout());
+
+        let Some(tls_config) = &prediction_config.tls else {
+            return Ok(endpoint);
+        };
+
+        let read = |path: &Path| {
+            std::fs::read(path).map_err(|source| PredictionServiceError::TlsFileError {
+                path: path.to_path_buf(),
+                source,
+            })
+        };
+
+        let mut client_tls_config = ClientTlsConfig::new()
+            .ca_certificate(Certificate::from_pem(read(&tls_config.ca_file)?))
+            .domain_name(
+                tls_config
+                    .domain
+                    .clone()
+                    .unwrap_or_else(|| prediction_config.host.clone()),
+            );
+
+        client_tls_config = match (&tls_config.cert_file, &tls_config.key_file) {
+            (Some(cert_file), Some(key_file)) => {
+                client_tls_config.identity(Identity::from_pem(read(cert_file)?, read(key_file)?))
+            }
+            (None, None) => client_tls_config,
+            _ => {
+                return Err(PredictionServiceError::InvalidTlsConfig(
+                    "cert_file and key_file must be set together".to_string(),
+                ))
+            }
+        };
+
+        Ok(endpoint.tls_config(client_tls_config)?)
+    }
+
+    /// Polls the TLS files and reconnects with the new certificates when they
+    /// change. Requests keep using the current client until the new one is up.
+    fn spawn_tls_reload_task(
+        prediction_config: PredictionServiceConfig,
+        tls_config: PredictionServiceTlsConfig,
+        client: Arc<Mutex<YoloServiceClient<Channel>>>,
+    ) {
+        let reload_interval = Duration::from_secs(tls_config.reload_interval_secs.max(1));
+        let modified_times = move || {
+            tls_config
+                .get_files()
+                .into_iter()
+                .map(|path| std::fs::metadata(path).and_then(|m| m.modified()).ok())
+                .collect::<Vec<_>>()
+        };
+
+        tokio::spawn(async move {
+            let mut ticker = interval(reload_interval);
+            let mut last_modified = modified_times();
+
+            loop {
+                ticker.tick().await;
+
+                let modified = modified_times();
+                if modified == last_modified {
+                    continue;
+                }
+
+                let connected = match Self::get_endpoint(&prediction_config) {
+                    Ok(endpoint) => timeout(Duration::from_secs(5), endpoint.connect())
+                        .await
+                        .map_err(|_| "connection timeout".to_string())
+                        .and_then(|result| result.map_err(|e| e.to_string())),
+                    Err(e) => Err(e.to_string()),
+                };
+
+                match connected {
+                    Ok(channel) => {
+                        *client.lock().await = YoloServiceClient::new(channel);
+                        last_modified = modified;
+                        tracing::info!("Reconnected to the inference service with new TLS files");
+                    }
+                    Err(e) => tracing::warn!("Failed to reload TLS files: {}", e),
+                }
+            }
+        });
     }
 
     async fn get_client(
-        address: String,
+        endpoint: Endpoint,
+        max_retries: u32,
     ) -> Result<YoloServiceClient<Channel>, PredictionServiceError> {
         let mut retry_delay = Duration::from_millis(50);
         let max_retry_delay = Duration::from_secs(1);
-        let max_retries = 20;
         let mut retry_count = 0;
 
         while retry_count < max_retries {
-            match timeout(
-                Duration::from_secs(1),
-                YoloServiceClient::connect(address.clone()),
-            )
-            .await
-            {
-                Ok(Ok(client)) => return Ok(client),
+            match timeout(Duration::from_secs(1), endpoint.connect()).await {
+                Ok(Ok(channel)) => return Ok(YoloServiceClient::new(channel)),
                 Ok(Err(e)) => {
                     tracing::error!("Failed to connect to gRPC server: {:?}", e);
                 }
@@ -105,14 +341,32 @@ impl PredictionService {
             .unwrap_or_default()
             .as_millis() as i64;
 
-        let request = Request::new(ImageFrame {
+        let mut request = self.request(ImageFrame {
             image_data,
             timestamp,
+            ..Default::default()
         });
+        if self.skip_result_cache {
+            request
+                .metadata_mut()
+                .insert("cache-control", AsciiMetadataValue::from_static("no-store"));
+        }
 
         let response = client.predict(request).await?;
+        let labels_stale = match settings_version(response.metadata()) {
+            Some(version) => self.class_labels.lock().await.settings_version != Some(version),
+            None => false,
+        };
+        if labels_stale {
+            // The frame is still labeled with the previous labels when the fetch fails
+            if let Err(e) = self.refresh_labels(&mut client).await {
+                tracing::warn!("Failed to fetch the updated class labels: {}", e);
+            }
+        }
+        drop(client);
+
         let detections = response.into_inner().detections;
-        let class_labels = self.class_labels.lock().await;
+        let class_labels = &self.class_labels.lock().await.labels;
 
         let labeled_detections: Vec<BoundingBoxWithLabels> = detections
             .into_iter()
@@ -123,7 +377,11 @@ impl PredictionService {
                         y1: bbox.y1,
                         x2: bbox.x2,
                         y2: bbox.y2,
-                        class_label: color_label.label.clone(),
+                        class_label: if color_label.display_name.is_empty() {
+                            color_label.label.clone()
+                        } else {
+                            color_label.display_name.clone()
+                        },
                         red: color_label.red,
                         green: color_label.green,
                         blue: color_label.blue,
@@ -147,4 +405,25 @@ impl PredictionService {
 
         Ok(labeled_detections)
     }
+
+    pub async fn get_settings(
+        &self,
+        authorization: Option<AsciiMetadataValue>,
+    ) -> Result<InferenceSettings, PredictionServiceError> {
+        let mut client = self.client.lock().await;
+        let request = self.admin_request(Empty {}, authorization);
+        let response = client.get_settings(request).await?;
+        Ok(response.into_inner())
+    }
+
+    pub async fn update_settings(
+        &self,
+        update: UpdateSettingsRequest,
+        authorization: Option<AsciiMetadataValue>,
+    ) -> Result<InferenceSettings, PredictionServiceError> {
+        let mut client = self.client.lock().await;
+        let request = self.admin_request(update, authorization);
+        let response = client.update_settings(request).await?;
+        Ok(response.into_inner())
+    }
 }
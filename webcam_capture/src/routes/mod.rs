@@ -1,10 +1,12 @@
 use crate::server::SharedState;
 mod health;
 mod predict_image;
+mod settings;
 mod video_feed;
 
 use health::healthcheck;
 use predict_image::predict_image;
+use settings::{get_settings, update_settings};
 use video_feed::video_feed;
 
 use axum::{
@@ -17,4 +19,5 @@ pub fn api_routes() -> Router<SharedState> {
         .route("/ws/video_feed", get(video_feed))
         .route("/predict_image", post(predict_image))
         .route("/health", get(healthcheck))
+        .route("/admin/settings", get(get_settings).patch(update_settings))
 }
@@ -1,23 +1,26 @@
 use crate::{
     cv_utils::{CvImage, CvUtilsError},
+    prediction::PredictionServiceError,
     server::SharedState,
 };
 use axum::{
     body::{Body, Bytes},
     extract::State,
-    http::{header, StatusCode},
+    http::{header, HeaderValue, StatusCode},
     response::{IntoResponse, Response},
 };
 use thiserror::Error;
 use tokio::time::Instant;
+use tonic::{Code, Status};
 use tracing::instrument;
+use yolo_proto::ErrorDetails;
 
 #[derive(Error, Debug)]
 pub enum PredictImageError {
     #[error("OpenCV decode failed: {0}")]
     OpenCvDecode(CvUtilsError),
     #[error("Prediction service failed: {0}")]
-    PredictionService(String),
+    PredictionService(#[from] PredictionServiceError),
     #[error("Image conversion failed: {0}")]
     ImageConversion(CvUtilsError),
     #[error("HTTP builder failed: {0}")]
@@ -30,13 +33,67 @@ impl From<CvUtilsError> for PredictImageError {
     }
 }
 
+/// Uses the `ErrorInfo` reason sent by the inference service when there is one,
+/// the gRPC status code otherwise.
+fn grpc_status_code(status: &Status) -> StatusCode {
+    match ErrorDetails::from_status(status).reason() {
+        Some("IMAGE_TOO_LARGE" | "IMAGE_DIMENSIONS_TOO_LARGE" | "IMAGE_MEMORY_LIMIT_EXCEEDED") => {
+            StatusCode::PAYLOAD_TOO_LARGE
+        }
+        Some("MALFORMED_IMAGE" | "UNSUPPORTED_IMAGE_FORMAT") => StatusCode::UNPROCESSABLE_ENTITY,
+        _ => match status.code() {
+            Code::InvalidArgument => StatusCode::BAD_REQUEST,
+            // Messages above the service's size limit are rejected before decoding
+            Code::OutOfRange => StatusCode::PAYLOAD_TOO_LARGE,
+            Code::Unavailable
+            | Code::ResourceExhausted
+            | Code::DeadlineExceeded
+            | Code::Cancelled => StatusCode::SERVICE_UNAVAILABLE,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        },
+    }
+}
+
 impl IntoResponse for PredictImageError {
     fn into_response(self) -> Response {
-        (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            format!("Something went wrong: {}", self),
-        )
-            .into_response()
+        match self {
+            // The upload is not an image OpenCV can read
+            PredictImageError::OpenCvDecode(_) => {
+                (StatusCode::UNPROCESSABLE_ENTITY, self.to_string()).into_response()
+            }
+            PredictImageError::PredictionService(PredictionServiceError::GrpcRequestFailed(
+                status,
+            )) => {
+                let status_code = grpc_status_code(&status);
+                if status_code == StatusCode::INTERNAL_SERVER_ERROR {
+                    tracing::error!("Prediction failed: {}", status);
+                }
+                let mut response = (status_code, status.message().to_string()).into_response();
+
+                // Rate limit rejections carry a hint in milliseconds, HTTP wants seconds
+                if let Some(retry_after_ms) = status
+                    .metadata()
+                    .get("retry-after-ms")
+                    .and_then(|value| value.to_str().ok())
+                    .and_then(|value| value.parse::<u64>().ok())
+                {
+                    response.headers_mut().insert(
+                        header::RETRY_AFTER,
+                        HeaderValue::from(retry_after_ms.div_ceil(1000)),
+                    );
+                }
+                response
+            }
+            PredictImageError::PredictionService(
+                PredictionServiceError::ConnectionFailed(_)
+                | PredictionServiceError::MaxRetriesExceeded,
+            ) => (StatusCode::SERVICE_UNAVAILABLE, self.to_string()).into_response(),
+            _ => (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Something went wrong: {}", self),
+            )
+                .into_response(),
+        }
     }
 }
 
@@ -52,12 +109,13 @@ pub async fn predict_image(
     let predictions = state
         .prediction_service
         .predict(image_data.to_vec())
-        .await
-        .map_err(|e| PredictImageError::PredictionService(e.to_string()))?;
+        .await?;
     let elapsed = start.elapsed().as_millis();
-    state
-        .metrics
-        .record_prediction_duration(elapsed as u64, "predict_image");
+    state.metrics.record_prediction_duration(
+        elapsed as u64,
+        "predict_image",
+        state.prediction_service.transport().as_str(),
+    );
     state.metrics.record_request("predict_image");
 
     let response = Response::builder()
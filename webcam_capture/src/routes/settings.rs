@@ -0,0 +1,156 @@
+use crate::{prediction::PredictionServiceError, server::SharedState};
+use axum::{
+    extract::State,
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Json, Response},
+};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use thiserror::Error;
+use tonic::{metadata::AsciiMetadataValue, Code};
+use yolo_proto::{ClassFilterSettings, InferenceSettings, UpdateSettingsRequest};
+
+#[derive(Error, Debug)]
+pub enum SettingsError {
+    #[error("Invalid authorization header")]
+    InvalidAuthorization,
+    #[error("Prediction service failed: {0}")]
+    PredictionService(#[from] PredictionServiceError),
+}
+
+impl IntoResponse for SettingsError {
+    fn into_response(self) -> Response {
+        match self {
+            SettingsError::InvalidAuthorization => {
+                (StatusCode::BAD_REQUEST, self.to_string()).into_response()
+            }
+            SettingsError::PredictionService(PredictionServiceError::GrpcRequestFailed(status)) => {
+                let status_code = match status.code() {
+                    Code::InvalidArgument => StatusCode::BAD_REQUEST,
+                    Code::Unauthenticated => StatusCode::UNAUTHORIZED,
+                    Code::PermissionDenied => StatusCode::FORBIDDEN,
+                    Code::Aborted | Code::FailedPrecondition => StatusCode::CONFLICT,
+                    _ => StatusCode::BAD_GATEWAY,
+                };
+                (status_code, status.message().to_string()).into_response()
+            }
+            SettingsError::PredictionService(_) => {
+                (StatusCode::BAD_GATEWAY, self.to_string()).into_response()
+            }
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Default)]
+pub struct ClassFilter {
+    #[serde(default)]
+    thresholds: HashMap<String, f32>,
+    #[serde(default)]
+    allow: Vec<String>,
+    #[serde(default)]
+    deny: Vec<String>,
+}
+
+#[derive(Serialize)]
+pub struct Settings {
+    min_probability: f32,
+    nms_iou_threshold: f32,
+    class_filter: ClassFilter,
+    log_level: String,
+    recording: bool,
+    version: u64,
+}
+
+impl From<InferenceSettings> for Settings {
+    fn from(settings: InferenceSettings) -> Self {
+        let class_filter = settings.class_filter.unwrap_or_default();
+        Self {
+            min_probability: settings.min_probability,
+            nms_iou_threshold: settings.nms_iou_threshold,
+            class_filter: ClassFilter {
+                thresholds: class_filter.thresholds,
+                allow: class_filter.allow,
+                deny: class_filter.deny,
+            },
+            log_level: settings.log_level,
+            recording: settings.recording,
+            version: settings.version,
+        }
+    }
+}
+
+/// Fields left out keep their current value, `class_filter` is replaced as a whole.
+/// Unknown fields are rejected so a typo does not silently change nothing.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SettingsUpdate {
+    min_probability: Option<f32>,
+    nms_iou_threshold: Option<f32>,
+    class_filter: Option<ClassFilter>,
+    log_level: Option<String>,
+    /// Starts or stops the request recorder of the inference service
+    recording: Option<bool>,
+    /// Fails with 409 when the settings changed since this version was read
+    #[serde(default)]
+    expected_version: u64,
+}
+
+impl From<SettingsUpdate> for UpdateSettingsRequest {
+    fn from(update: SettingsUpdate) -> Self {
+        Self {
+            min_probability: update.min_probability,
+            nms_iou_threshold: update.nms_iou_threshold,
+            class_filter: update.class_filter.map(|class_filter| ClassFilterSettings {
+                thresholds: class_filter.thresholds,
+                allow: class_filter.allow,
+                deny: class_filter.deny,
+            }),
+            log_level: update.log_level,
+            recording: update.recording,
+            expected_version: update.expected_version,
+        }
+    }
+}
+
+/// The caller's `Authorization` header is forwarded to the inference service,
+/// which checks for the `admin` scope and refuses every call when it has no
+/// authentication configured.
+fn authorization(headers: &HeaderMap) -> Result<Option<AsciiMetadataValue>, SettingsError> {
+    headers
+        .get(header::AUTHORIZATION)
+        .map(|value| {
+            value
+                .to_str()
+                .ok()
+                .and_then(|value| value.parse().ok())
+                .ok_or(SettingsError::InvalidAuthorization)
+        })
+        .transpose()
+}
+
+pub async fn get_settings(
+    State(state): State<SharedState>,
+    headers: HeaderMap,
+) -> Result<Json<Settings>, SettingsError> {
+    let settings = state
+        .prediction_service
+        .get_settings(authorization(&headers)?)
+        .await?;
+    state.metrics.record_request("admin_settings");
+
+    Ok(Json(settings.into()))
+}
+
+pub async fn update_settings(
+    State(state): State<SharedState>,
+    headers: HeaderMap,
+    Json(update): Json<SettingsUpdate>,
+) -> Result<Json<Settings>, SettingsError> {
+    let settings = state
+        .prediction_service
+        .update_settings(update.into(), authorization(&headers)?)
+        .await?;
+    state.metrics.record_request("admin_settings");
+
+    Ok(Json(settings.into()))
+}
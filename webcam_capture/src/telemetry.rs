@@ -1,9 +1,70 @@
+use crate::config::TracingConfig;
 use opentelemetry::{
     global,
     metrics::{Counter, Gauge, Histogram, MeterProvider},
+    propagation::Injector,
     KeyValue,
 };
+use opentelemetry_sdk::{
+    propagation::TraceContextPropagator,
+    trace::{Sampler, SdkTracerProvider},
+    Resource,
+};
 use std::collections::HashSet;
+use tonic::metadata::{MetadataKey, MetadataMap, MetadataValue};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+/// Exports spans over OTLP, returns `None` when tracing export is disabled.
+/// The trace context is sent to the prediction service as W3C `traceparent` headers.
+pub fn init_tracer(tracing_config: &TracingConfig) -> Option<SdkTracerProvider> {
+    global::set_text_map_propagator(TraceContextPropagator::new());
+
+    if !tracing_config.otlp {
+        return None;
+    }
+
+    // Uses OTEL_EXPORTER_OTLP_ENDPOINT as well
+    let exporter = opentelemetry_otlp::SpanExporter::builder()
+        .with_tonic()
+        .build()
+        .expect("failed to create OTLP span exporter");
+
+    let provider = SdkTracerProvider::builder()
+        .with_batch_exporter(exporter)
+        .with_sampler(Sampler::ParentBased(Box::new(Sampler::TraceIdRatioBased(
+            tracing_config.sample_ratio,
+        ))))
+        .with_resource(
+            Resource::builder()
+                .with_service_name("webcam_capture")
+                .build(),
+        )
+        .build();
+
+    global::set_tracer_provider(provider.clone());
+    Some(provider)
+}
+
+struct MetadataInjector<'a>(&'a mut MetadataMap);
+
+impl Injector for MetadataInjector<'_> {
+    fn set(&mut self, key: &str, value: String) {
+        if let (Ok(key), Ok(value)) = (
+            MetadataKey::from_bytes(key.as_bytes()),
+            MetadataValue::try_from(value),
+        ) {
+            self.0.insert(key, value);
+        }
+    }
+}
+
+/// Adds the current span's trace context to outgoing gRPC metadata.
+pub fn inject_current_context(metadata: &mut MetadataMap) {
+    let context = tracing::Span::current().context();
+    global::get_text_map_propagator(|propagator| {
+        propagator.inject_context(&context, &mut MetadataInjector(metadata))
+    });
+}
 
 pub struct Metrics {
     request_counter: Counter<u64>,
@@ -69,8 +130,12 @@ impl Metrics {
         self.request_counter.add(1, &attributes);
     }
 
-    pub fn record_prediction_duration(&self, duration_ms: u64, route: &str) {
-        let attributes = vec![KeyValue::new("route", route.to_string())];
+    /// `transport` splits the histogram so Unix socket and TCP latencies can be compared
+    pub fn record_prediction_duration(&self, duration_ms: u64, route: &str, transport: &str) {
+        let attributes = vec![
+            KeyValue::new("route", route.to_string()),
+            KeyValue::new("transport", transport.to_string()),
+        ];
         self.prediction_duration.record(duration_ms, &attributes);
     }
 
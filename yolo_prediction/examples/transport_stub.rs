@@ -0,0 +1,81 @@
+//! Stub `YoloService` answering every `Predict` with an empty batch, served on TCP
+//! and a Unix socket at once, so `yolo-bench` measures the transport alone.
+//!
+//! ```sh
+//! cargo run --release --example transport_stub -- --port 50061 --unix-socket /tmp/yolo_stub.sock
+//! ```
+use clap::Parser;
+use std::path::PathBuf;
+use tokio::net::UnixListener;
+use tokio_stream::wrappers::UnixListenerStream;
+use tonic::{transport::Server, Request, Response, Status};
+use yolo_proto::{
+    yolo_service_server::{YoloService, YoloServiceServer},
+    Empty, ImageFrame, InferenceSettings, PredictionBatch, UpdateSettingsRequest, YoloClassLabels,
+};
+
+#[derive(Debug, Parser)]
+struct Cli {
+    /// TCP port, on 127.0.0.1
+    #[arg(long, default_value_t = 50061)]
+    port: u16,
+    /// Unix socket path, replaced if it exists
+    #[arg(long, default_value = "/tmp/yolo_stub.sock")]
+    unix_socket: PathBuf,
+}
+
+struct StubService;
+
+#[tonic::async_trait]
+impl YoloService for StubService {
+    async fn predict(
+        &self,
+        request: Request<ImageFrame>,
+    ) -> Result<Response<PredictionBatch>, Status> {
+        Ok(Response::new(PredictionBatch {
+            timestamp: request.into_inner().timestamp,
+            ..Default::default()
+        }))
+    }
+
+    async fn get_yolo_class_labels(
+        &self,
+        _request: Request<Empty>,
+    ) -> Result<Response<YoloClassLabels>, Status> {
+        Ok(Response::new(YoloClassLabels::default()))
+    }
+
+    async fn get_settings(
+        &self,
+        _request: Request<Empty>,
+    ) -> Result<Response<InferenceSettings>, Status> {
+        Ok(Response::new(InferenceSettings::default()))
+    }
+
+    async fn update_settings(
+        &self,
+        _request: Request<UpdateSettingsRequest>,
+    ) -> Result<Response<InferenceSettings>, Status> {
+        Ok(Response::new(InferenceSettings::default()))
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let cli = Cli::parse();
+    let service = || YoloServiceServer::new(StubService).max_decoding_message_size(64 << 20);
+
+    let _ = std::fs::remove_file(&cli.unix_socket);
+    let listener = UnixListener::bind(&cli.unix_socket)?;
+    let tcp = Server::builder()
+        .add_service(service())
+        .serve(([127, 0, 0, 1], cli.port).into());
+    let unix = Server::builder()
+        .add_service(service())
+        .serve_with_incoming(UnixListenerStream::new(listener));
+
+    let (tcp, unix) = tokio::join!(tcp, unix);
+    tcp?;
+    unix?;
+    Ok(())
+}
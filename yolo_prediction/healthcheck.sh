@@ -1,3 +1,11 @@
 #!/bin/bash
-grpc_health_probe -addr=localhost:50051 || exit 1
+args=(-addr=localhost:50051)
+if [ -n "$YP_SERVER__TLS__CERT_FILE" ]; then
+  args+=(-tls -tls-no-verify)
+  # With mutual TLS the probe needs its own client certificate
+  if [ -n "$HEALTHCHECK_TLS_CLIENT_CERT" ]; then
+    args+=(-tls-client-cert="$HEALTHCHECK_TLS_CLIENT_CERT" -tls-client-key="$HEALTHCHECK_TLS_CLIENT_KEY")
+  fi
+fi
+grpc_health_probe "${args[@]}" || exit 1
 exit 0
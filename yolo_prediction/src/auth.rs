@@ -0,0 +1,239 @@
+use crate::{config::AuthConfig, error::ServiceError};
+use std::{fs, sync::Arc};
+use tonic::{metadata::MetadataMap, service::Interceptor, Request, Status};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Scope {
+    Predict,
+    Labels,
+    /// Grants every other scope
+    Admin,
+}
+
+impl Scope {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Scope::Predict => "predict",
+            Scope::Labels => "labels",
+            Scope::Admin => "admin",
+        }
+    }
+}
+
+impl TryFrom<&str> for Scope {
+    type Error = String;
+
+    fn try_from(s: &str) -> Result<Self, Self::Error> {
+        match s.trim().to_lowercase().as_str() {
+            "predict" => Ok(Self::Predict),
+            "labels" => Ok(Self::Labels),
+            "admin" => Ok(Self::Admin),
+            other => Err(format!(
+                "{} is not a supported scope. Use either `predict`, `labels` or `admin`.",
+                other
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ApiKey {
+    name: String,
+    token: String,
+    scopes: Vec<Scope>,
+}
+
+/// Authenticated caller, attached to the request extensions by [`AuthInterceptor`].
+#[derive(Debug, Clone)]
+pub struct Principal {
+    pub name: String,
+    scopes: Vec<Scope>,
+}
+
+impl Principal {
+    pub fn new(name: String, scopes: Vec<Scope>) -> Self {
+        Self { name, scopes }
+    }
+
+    pub fn has_scope(&self, scope: Scope) -> bool {
+        self.scopes.contains(&Scope::Admin) || self.scopes.contains(&scope)
+    }
+}
+
+/// Parses `name:token:scope,scope` entries, one per line or separated by `;`.
+/// Blank lines and lines starting with `#` are ignored.
+pub fn parse_api_keys(content: &str) -> Result<Vec<ApiKey>, String> {
+    content
+        .split(['\n', ';'])
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty() && !entry.starts_with('#'))
+        .map(|entry| {
+            let mut parts = entry.splitn(3, ':').map(str::trim);
+            let (Some(name), Some(token), Some(scopes)) =
+                (parts.next(), parts.next(), parts.next())
+            else {
+                return Err("Invalid API key entry, expected `name:token:scopes`".to_string());
+            };
+            if name.is_empty() || token.is_empty() {
+                return Err("Invalid API key entry, empty name or token".to_string());
+            }
+
+            Ok(ApiKey {
+                name: name.to_string(),
+                token: token.to_string(),
+                scopes: scopes
+                    .split(',')
+                    .map(Scope::try_from)
+                    .collect::<Result<_, _>>()
+                    .map_err(|e| format!("API key {}: {}", name, e))?,
+            })
+        })
+        .collect()
+}
+
+pub fn load_api_keys(auth_cfg: &AuthConfig) -> Result<Vec<ApiKey>, String> {
+    let mut keys = Vec::new();
+
+    if let Some(keys_file) = &auth_cfg.keys_file {
+        let content = fs::read_to_string(keys_file)
+            .map_err(|e| format!("Failed to read API keys from {:?}: {}", keys_file, e))?;
+        keys.extend(parse_api_keys(&content)?);
+    }
+
+    if let Some(keys_env) = &auth_cfg.keys_env {
+        let content = std::env::var(keys_env)
+            .map_err(|e| format!("Failed to read API keys from ${}: {}", keys_env, e))?;
+        keys.extend(parse_api_keys(&content)?);
+    }
+
+    if keys.is_empty() {
+        return Err("Authentication is enabled but no API key is configured".to_string());
+    }
+
+    tracing::info!("Loaded {} API keys", keys.len());
+    Ok(keys)
+}
+
+/// Compares every byte so the time taken does not leak how much of a token matched.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+fn extract_token(metadata: &MetadataMap) -> Option<&str> {
+    if let Some(authorization) = metadata.get("authorization") {
+        return authorization.to_str().ok()?.strip_prefix("Bearer ");
+    }
+    metadata.get("x-api-key")?.to_str().ok()
+}
+
+/// Accepts `authorization: Bearer <token>` or `x-api-key: <token>`. Without
+/// configured keys every call is let through and no [`Principal`] is attached.
+#[derive(Debug, Clone, Default)]
+pub struct AuthInterceptor {
+    keys: Option<Arc<Vec<ApiKey>>>,
+}
+
+impl AuthInterceptor {
+    pub fn new(keys: Option<Vec<ApiKey>>) -> Self {
+        Self {
+            keys: keys.map(Arc::new),
+        }
+    }
+}
+
+impl Interceptor for AuthInterceptor {
+    fn call(&mut self, mut request: Request<()>) -> Result<Request<()>, Status> {
+        let Some(keys) = &self.keys else {
+            return Ok(request);
+        };
+
+        let token = extract_token(request.metadata()).ok_or(ServiceError::MissingApiKey)?;
+
+        // Check every key rather than stopping at the first match
+        let matched = keys.iter().fold(None, |matched, key| {
+            if constant_time_eq(key.token.as_bytes(), token.as_bytes()) {
+                Some(key)
+            } else {
+                matched
+            }
+        });
+        let key = matched.ok_or(ServiceError::InvalidApiKey)?;
+
+        request
+            .extensions_mut()
+            .insert(Principal::new(key.name.clone(), key.scopes.clone()));
+        Ok(request)
+    }
+}
+
+/// Checks the scope of the caller authenticated by [`AuthInterceptor`]. Without
+/// authentication every scope is open but `admin`, which fails closed.
+pub fn authorize<T>(request: &Request<T>, scope: Scope) -> Result<(), ServiceError> {
+    match request.extensions().get::<Principal>() {
+        Some(principal) if !principal.has_scope(scope) => Err(ServiceError::MissingScope {
+            key: principal.name.clone(),
+            scope: scope.as_str(),
+        }),
+        Some(_) => Ok(()),
+        None if scope == Scope::Admin => Err(ServiceError::AdminDisabled),
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tonic::Code;
+
+    fn request(header: &'static str, value: &'static str) -> Request<()> {
+        let mut request = Request::new(());
+        request
+            .metadata_mut()
+            .insert(header, value.parse().unwrap());
+        request
+    }
+
+    #[test]
+    fn test_parse_api_keys() {
+        let keys =
+            parse_api_keys("# keys\nwebcam:s3cr3t:predict,labels\n\nops:adm1n:admin").unwrap();
+
+        assert_eq!(keys.len(), 2);
+        assert_eq!(keys[0].scopes, vec![Scope::Predict, Scope::Labels]);
+        assert_eq!(parse_api_keys("a:b:predict;c:d:labels").unwrap().len(), 2);
+        assert!(parse_api_keys("webcam:s3cr3t").is_err());
+        assert!(parse_api_keys("webcam:s3cr3t:write").is_err());
+    }
+
+    #[test]
+    fn test_auth_interceptor() {
+        let mut interceptor =
+            AuthInterceptor::new(Some(parse_api_keys("webcam:s3cr3t:predict").unwrap()));
+
+        let missing = interceptor.call(Request::new(())).unwrap_err();
+        assert_eq!(missing.code(), Code::Unauthenticated);
+        let invalid = interceptor
+            .call(request("authorization", "Bearer wrong"))
+            .unwrap_err();
+        assert_eq!(invalid.code(), Code::Unauthenticated);
+
+        let request = interceptor
+            .call(request("authorization", "Bearer s3cr3t"))
+            .unwrap();
+        assert!(authorize(&request, Scope::Predict).is_ok());
+        assert_eq!(
+            authorize(&request, Scope::Labels).unwrap_err().code(),
+            Code::PermissionDenied
+        );
+
+        let mut open = AuthInterceptor::new(None);
+        let request = open.call(Request::new(())).unwrap();
+        assert!(authorize(&request, Scope::Predict).is_ok());
+        assert!(authorize(&request, Scope::Labels).is_ok());
+        // Settings cannot be changed by anyone who can reach the port
+        assert_eq!(
+            authorize(&request, Scope::Admin).unwrap_err().code(),
+            Code::PermissionDenied
+        );
+    }
+}
@@ -0,0 +1,594 @@
+#[cfg(feature = "ort")]
+use crate::ort_service::OrtModelService;
+#[cfg(feature = "tract")]
+use crate::tract_service::TractModelService;
+use crate::{
+    config::{Backend, Config, ImageConfig},
+    http_gateway::PredictionResponse,
+    image_decoder::decode_image,
+    model_service::{Deadline, ModelService},
+    regions::RegionFilter,
+    settings::LiveSettings,
+    state::{ServiceState, State},
+    telemetry::Metrics,
+};
+use image::{Rgb, RgbImage};
+use serde::Serialize;
+use std::{
+    fs::File,
+    io::{self, BufWriter, Write},
+    ops::RangeInclusive,
+    path::{Component, Path, PathBuf},
+    sync::Arc,
+    time::Instant,
+};
+use tokio::task::JoinSet;
+use yolo_proto::{BoundingBox, ColorLabel, ImageFrame, PredictionBatch};
+
+const IMAGE_EXTENSIONS: [&str; 8] = ["jpg", "jpeg", "png", "webp", "bmp", "tif", "tiff", "avif"];
+
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum OutputFormat {
+    /// One JSON object per image
+    Jsonl,
+    /// COCO results, a JSON array of `{image_id, category_id, bbox, score}`
+    Coco,
+    /// One `<class> <x_center> <y_center> <width> <height>` txt file per image
+    Yolo,
+}
+
+/// Options of the `yolo-predict` binary
+#[derive(Debug, Clone, clap::Args)]
+pub struct BatchOptions {
+    /// Image files, directories or glob patterns such as `data/**/*.jpg`
+    #[arg(required = true)]
+    pub inputs: Vec<String>,
+    /// Searches directories recursively
+    #[arg(long, short)]
+    pub recursive: bool,
+    #[arg(long, value_enum, default_value_t = OutputFormat::Jsonl)]
+    pub format: OutputFormat,
+    /// Output file for `jsonl` and `coco`, `-` for stdout. Output directory for `yolo`
+    #[arg(long, short, default_value = "-")]
+    pub output: PathBuf,
+    /// Writes a copy of each image with its detections drawn to this directory
+    #[arg(long, value_name = "DIR")]
+    pub annotate_dir: Option<PathBuf>,
+    /// Appends the confidence to each line of the YOLO txt files
+    #[arg(long)]
+    pub save_conf: bool,
+    /// Images processed at once, defaults to `model.num_instances`
+    #[arg(long)]
+    pub concurrency: Option<usize>,
+}
+
+#[derive(Debug)]
+pub(crate) struct InputImage {
+    pub path: PathBuf,
+    /// Path under the output directories, relative to the directory or glob base
+    /// the image was found under
+    pub relative: PathBuf,
+}
+
+#[derive(Debug, Default)]
+pub struct BatchSummary {
+    pub processed: usize,
+    pub failed: usize,
+}
+
+/// Writes the per-image outputs, called from the prediction tasks as each image completes
+pub(crate) type ResultHandler =
+    Arc<dyn Fn(&InputImage, &PredictionBatch, &[ColorLabel]) -> Result<(), String> + Send + Sync>;
+
+pub(crate) struct Predictions {
+    /// In input order
+    pub results: Vec<Result<PredictionBatch, String>>,
+    /// Labels indexed by the class ids of the detections
+    pub labels: Vec<ColorLabel>,
+}
+
+/// Runs the model over every input image, with the filters, regions and remapping
+/// the gRPC server would apply, and writes the results in the requested format.
+pub async fn run_batch(config: Config, options: BatchOptions) -> Result<BatchSummary, String> {
+    if options.format == OutputFormat::Yolo && options.output == Path::new("-") {
+        return Err("--output must be a directory for the yolo format".into());
+    }
+    let inputs = Arc::new(collect_inputs(&options.inputs, options.recursive)?);
+    tracing::info!("Found {} images", inputs.len());
+
+    let predictions = predict_images(
+        &config,
+        inputs.clone(),
+        RegionFilter::new(&config.regions)?,
+        options.concurrency,
+        output_handler(&options, &config.image),
+    )
+    .await?;
+
+    let results: Vec<_> = inputs.iter().zip(&predictions.results).collect();
+    match options.format {
+        OutputFormat::Jsonl => write_output(&options.output, |writer| {
+            write_jsonl(writer, &results, &predictions.labels)
+        }),
+        OutputFormat::Coco => write_output(&options.output, |writer| write_coco(writer, &results)),
+        // Written by each task
+        OutputFormat::Yolo => Ok(()),
+    }
+    .map_err(|e| format!("failed to write {}: {}", options.output.display(), e))?;
+
+    Ok(BatchSummary {
+        processed: results.len(),
+        failed: results.iter().filter(|(_, result)| result.is_err()).count(),
+    })
+}
+
+/// Runs the model over the images like the gRPC server does, minus authentication
+/// and rate limiting. `concurrency` defaults to `model.num_instances`.
+pub(crate) async fn predict_images(
+    config: &Config,
+    inputs: Arc<Vec<InputImage>>,
+    region_filter: RegionFilter,
+    concurrency: Option<usize>,
+    on_result: Option<ResultHandler>,
+) -> Result<Predictions, String> {
+    let metrics = Arc::new(Metrics::new(&config.model.get_name()));
+    let service_state =
+        ServiceState::new(config.labels.as_ref(), &config.model).map_err(|e| e.to_string())?;
+    let settings = service_state.get_settings().clone();
+    let concurrency = concurrency.unwrap_or(config.model.num_instances).max(1);
+
+    match &config.model.backend {
+        #[cfg(feature = "ort")]
+        Backend::Ort => {
+            let model_service =
+                OrtModelService::new(&config.model, &config.image, settings.clone(), metrics)
+                    .map_err(|e| format!("failed to instantiate ort model service: {}", e))?;
+            Predictor {
+                model_service,
+                settings,
+                region_filter,
+                on_result,
+            }
+            .predict_all(inputs, concurrency)
+            .await
+        }
+        #[cfg(feature = "tract")]
+        Backend::Tract => {
+            let model_service =
+                TractModelService::new(&config.model, &config.image, settings.clone(), metrics)
+                    .map_err(|e| format!("failed to instantiate tract model service: {}", e))?;
+            Predictor {
+                model_service,
+                settings,
+                region_filter,
+                on_result,
+            }
+            .predict_all(inputs, concurrency)
+            .await
+        }
+        #[allow(unreachable_patterns)]
+        backend => Err(format!(
+            "The {} backend is not compiled in, rebuild with `--features {}`",
+            backend.as_str(),
+            backend.as_str()
+        )),
+    }
+}
+
+struct Predictor<M: ModelService> {
+    model_service: M,
+    settings: Arc<LiveSettings>,
+    region_filter: RegionFilter,
+    on_result: Option<ResultHandler>,
+}
+
+impl<M: ModelService> Predictor<M> {
+    /// Keeps `concurrency` images in flight so every session of the pool stays busy.
+    async fn predict_all(
+        self,
+        inputs: Arc<Vec<InputImage>>,
+        concurrency: usize,
+    ) -> Result<Predictions, String> {
+        let started = Instant::now();
+        let predictor = Arc::new(self);
+        let mut results: Vec<Option<Result<PredictionBatch, String>>> =
+            (0..inputs.len()).map(|_| None).collect();
+        let mut tasks = JoinSet::new();
+        let mut next = 0;
+
+        loop {
+            while next < inputs.len() && tasks.len() < concurrency {
+                let predictor = predictor.clone();
+                let inputs = inputs.clone();
+                let index = next;
+                tasks.spawn(async move { (index, predictor.predict(&inputs[index]).await) });
+                next += 1;
+            }
+
+            let Some(joined) = tasks.join_next().await else {
+                break;
+            };
+            let (index, result) = joined.map_err(|e| format!("prediction task failed: {}", e))?;
+            if let Err(e) = &result {
+                tracing::warn!("Failed to process {}: {}", inputs[index].path.display(), e);
+            }
+            results[index] = Some(result);
+        }
+
+        let results: Vec<_> = results.into_iter().flatten().collect();
+        let elapsed = started.elapsed().as_secs_f64();
+        tracing::info!(
+            "Processed {} images ({} failed) in {:.1}s, {:.1} images/s",
+            results.len(),
+            results.iter().filter(|result| result.is_err()).count(),
+            elapsed,
+            results.len() as f64 / elapsed
+        );
+
+        Ok(Predictions {
+            results,
+            labels: predictor.settings.current().labels.clone(),
+        })
+    }
+
+    async fn predict(&self, input: &InputImage) -> Result<PredictionBatch, String> {
+        let image_data = tokio::fs::read(&input.path)
+            .await
+            .map_err(|e| e.to_string())?;
+
+        let image_frame = ImageFrame {
+            image_data,
+            ..Default::default()
+        };
+        let mut batch = self
+            .model_service
+            .predict(image_frame, Deadline::default())
+            .await
+            .map_err(|e| e.to_string())?;
+        batch.detections =
+            self.region_filter
+                .apply(batch.detections, batch.image_width, batch.image_height);
+        batch.detections = self.settings.class_remapper().apply(batch.detections);
+
+        if let Some(on_result) = &self.on_result {
+            on_result(input, &batch, &self.settings.current().labels)?;
+        }
+
+        Ok(batch)
+    }
+}
+
+/// YOLO txt files and annotated copies are written as each image completes
+fn output_handler(options: &BatchOptions, image_cfg: &ImageConfig) -> Option<ResultHandler> {
+    let yolo_dir = (options.format == OutputFormat::Yolo).then(|| options.output.clone());
+    let annotate_dir = options.annotate_dir.clone();
+    if yolo_dir.is_none() && annotate_dir.is_none() {
+        return None;
+    }
+    let save_conf = options.save_conf;
+    let image_cfg = image_cfg.clone();
+
+    Some(Arc::new(move |input, batch, labels| {
+        if let Some(yolo_dir) = &yolo_dir {
+            let path = yolo_dir.join(&input.relative).with_extension("txt");
+            write_yolo_labels(&path, batch, save_conf)
+                .map_err(|e| format!("failed to write {}: {}", path.display(), e))?;
+        }
+
+        if let Some(annotate_dir) = &annotate_dir {
+            let path = annotate_dir.join(&input.relative);
+            let image_data = std::fs::read(&input.path).map_err(|e| e.to_string())?;
+            let mut image = decode_image(&image_data, &image_cfg)
+                .map_err(|e| e.to_string())?
+                .to_rgb8();
+            draw_detections(&mut image, &batch.detections, labels);
+            create_parent(&path)
+                .and_then(|()| image.save(&path).map_err(io::Error::other))
+                .map_err(|e| format!("failed to write {}: {}", path.display(), e))?;
+        }
+
+        Ok(())
+    }))
+}
+
+/// `-` writes to stdout
+fn write_output(
+    output: &Path,
+    write: impl FnOnce(&mut dyn Write) -> io::Result<()>,
+) -> io::Result<()> {
+    if output == Path::new("-") {
+        let mut stdout = io::stdout().lock();
+        write(&mut stdout)?;
+        return stdout.flush();
+    }
+
+    create_parent(output)?;
+    let mut writer = BufWriter::new(File::create(output)?);
+    write(&mut writer)?;
+    writer.flush()
+}
+
+/// Expands directories and glob patterns into a sorted list of images.
+pub(crate) fn collect_inputs(
+    inputs: &[String],
+    recursive: bool,
+) -> Result<Vec<InputImage>, String> {
+    let mut images = Vec::new();
+
+    for input in inputs {
+        let path = Path::new(input);
+        if path.is_dir() {
+            walk_dir(path, path, recursive, &mut images)
+                .map_err(|e| format!("failed to read {}: {}", input, e))?;
+        } else if path.is_file() {
+            images.push(InputImage {
+                path: path.to_path_buf(),
+                relative: path.file_name().map(PathBuf::from).unwrap_or_default(),
+            });
+        } else {
+            let base = glob_base(input);
+            let paths =
+                glob::glob(input).map_err(|e| format!("invalid pattern {}: {}", input, e))?;
+            let count = images.len();
+            for path in paths {
+                let path = path.map_err(|e| format!("failed to read {}: {}", input, e))?;
+                if path.is_file() {
+                    images.push(InputImage {
+                        relative: path.strip_prefix(&base).unwrap_or(&path).to_path_buf(),
+                        path,
+                    });
+                }
+            }
+            if images.len() == count {
+                return Err(format!("No such file or directory: {}", input));
+            }
+        }
+    }
+
+    images.sort_by(|a, b| a.path.cmp(&b.path));
+    images.dedup_by(|a, b| a.path == b.path);
+    if images.is_empty() {
+        return Err("No images found".into());
+    }
+    Ok(images)
+}
+
+fn walk_dir(
+    root: &Path,
+    dir: &Path,
+    recursive: bool,
+    images: &mut Vec<InputImage>,
+) -> io::Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            if recursive {
+                walk_dir(root, &path, recursive, images)?;
+            }
+        } else if is_image(&path) {
+            images.push(InputImage {
+                relative: path.strip_prefix(root).unwrap_or(&path).to_path_buf(),
+                path,
+            });
+        }
+    }
+    Ok(())
+}
+
+fn is_image(path: &Path) -> bool {
+    path.extension()
+        .and_then(|extension| extension.to_str())
+        .is_some_and(|extension| IMAGE_EXTENSIONS.contains(&extension.to_lowercase().as_str()))
+}
+
+/// Leading components of a glob pattern that contain no wildcard
+fn glob_base(pattern: &str) -> PathBuf {
+    Path::new(pattern)
+        .components()
+        .take_while(|component| {
+            !matches!(component, Component::Normal(name)
+                if name.to_string_lossy().contains(['*', '?', '[']))
+        })
+        .collect()
+}
+
+fn create_parent(path: &Path) -> io::Result<()> {
+    match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => std::fs::create_dir_all(parent),
+        _ => Ok(()),
+    }
+}
+
+#[derive(Serialize)]
+#[serde(untagged)]
+enum JsonlRecord<'a> {
+    Predicted {
+        file: &'a Path,
+        #[serde(flatten)]
+        prediction: PredictionResponse,
+    },
+    Failed {
+        file: &'a Path,
+        error: &'a str,
+    },
+}
+
+fn write_jsonl(
+    writer: &mut dyn Write,
+    results: &[(&InputImage, &Result<PredictionBatch, String>)],
+    labels: &[ColorLabel],
+) -> io::Result<()> {
+    for (input, result) in results {
+        let record = match result {
+            Ok(batch) => JsonlRecord::Predicted {
+                file: &input.path,
+                prediction: PredictionResponse::new(batch.clone(), labels),
+            },
+            Err(error) => JsonlRecord::Failed {
+                file: &input.path,
+                error,
+            },
+        };
+        serde_json::to_writer(&mut *writer, &record)?;
+        writeln!(writer)?;
+    }
+    Ok(())
+}
+
+#[derive(Serialize)]
+struct CocoResult {
+    image_id: u64,
+    category_id: i32,
+    /// `[x, y, width, height]` in pixels
+    bbox: [f32; 4],
+    score: f32,
+}
+
+/// Image ids are taken from numeric file names, as in the COCO datasets, otherwise
+/// they follow the input order starting at 1. Category ids are the class ids.
+fn write_coco(
+    writer: &mut dyn Write,
+    results: &[(&InputImage, &Result<PredictionBatch, String>)],
+) -> io::Result<()> {
+    let mut coco_results = Vec::new();
+    for (index, (input, result)) in results.iter().enumerate() {
+        let Ok(batch) = result else {
+            continue;
+        };
+        let image_id = input
+            .path
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .and_then(|stem| stem.parse().ok())
+            .unwrap_or(index as u64 + 1);
+        coco_results.extend(batch.detections.iter().map(|detection| CocoResult {
+            image_id,
+            category_id: detection.class_id,
+            bbox: [
+                detection.x1,
+                detection.y1,
+                detection.x2 - detection.x1,
+                detection.y2 - detection.y1,
+            ],
+            score: detection.confidence,
+        }));
+    }
+    serde_json::to_writer(writer, &coco_results)?;
+    Ok(())
+}
+
+/// Coordinates are normalized by the image size, as in YOLO training labels
+fn write_yolo_labels(path: &Path, batch: &PredictionBatch, save_conf: bool) -> io::Result<()> {
+    create_parent(path)?;
+    let mut writer = BufWriter::new(File::create(path)?);
+    let (width, height) = (batch.image_width as f32, batch.image_height as f32);
+    for detection in &batch.detections {
+        write!(
+            writer,
+            "{} {:.6} {:.6} {:.6} {:.6}",
+            detection.class_id,
+            (detection.x1 + detection.x2) / 2. / width,
+            (detection.y1 + detection.y2) / 2. / height,
+            (detection.x2 - detection.x1) / width,
+            (detection.y2 - detection.y1) / height,
+        )?;
+        if save_conf {
+            write!(writer, " {:.6}", detection.confidence)?;
+        }
+        writeln!(writer)?;
+    }
+    writer.flush()
+}
+
+/// Draws each box outline in its class color
+fn draw_detections(image: &mut RgbImage, detections: &[BoundingBox], labels: &[ColorLabel]) {
+    let (width, height) = image.dimensions();
+    if width == 0 || height == 0 {
+        return;
+    }
+    let thickness = (width.min(height) / 300).max(2);
+
+    for detection in detections {
+        let color = usize::try_from(detection.class_id)
+            .ok()
+            .and_then(|class_id| labels.get(class_id))
+            .map_or(Rgb([255, 0, 0]), |label| {
+                Rgb([label.red as u8, label.green as u8, label.blue as u8])
+            });
+        let clamp = |value: f32, max: u32| (value.max(0.) as u32).min(max - 1);
+        let (x1, y1) = (clamp(detection.x1, width), clamp(detection.y1, height));
+        let (x2, y2) = (clamp(detection.x2, width), clamp(detection.y2, height));
+
+        let mut fill = |xs: RangeInclusive<u32>, ys: RangeInclusive<u32>| {
+            for y in ys {
+                for x in xs.clone() {
+                    image.put_pixel(x, y, color);
+                }
+            }
+        };
+        fill(x1..=x2, y1..=(y1 + thickness - 1).min(y2));
+        fill(x1..=x2, (y2.saturating_sub(thickness - 1)).max(y1)..=y2);
+        fill(x1..=(x1 + thickness - 1).min(x2), y1..=y2);
+        fill((x2.saturating_sub(thickness - 1)).max(x1)..=x2, y1..=y2);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_collect_inputs() {
+        let dir = std::env::temp_dir().join("yolo_prediction_test_batch");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("night")).unwrap();
+        for file in ["a.jpg", "b.PNG", "notes.txt", "night/c.jpg"] {
+            std::fs::write(dir.join(file), b"").unwrap();
+        }
+
+        let relative = |images: Vec<InputImage>| {
+            images
+                .into_iter()
+                .map(|image| image.relative.to_string_lossy().into_owned())
+                .collect::<Vec<_>>()
+        };
+        let dir_input = dir.to_string_lossy().into_owned();
+
+        let images = collect_inputs(std::slice::from_ref(&dir_input), false).unwrap();
+        assert_eq!(relative(images), vec!["a.jpg", "b.PNG"]);
+
+        let images = collect_inputs(std::slice::from_ref(&dir_input), true).unwrap();
+        assert_eq!(relative(images), vec!["a.jpg", "b.PNG", "night/c.jpg"]);
+
+        let pattern = format!("{}/**/*.jpg", dir_input);
+        let images = collect_inputs(&[pattern, dir_input], false).unwrap();
+        assert_eq!(relative(images), vec!["a.jpg", "b.PNG", "night/c.jpg"]);
+
+        assert!(collect_inputs(&[format!("{}/*.gif", dir.display())], false).is_err());
+    }
+
+    #[test]
+    fn test_write_yolo_labels() {
+        let path = std::env::temp_dir().join("yolo_prediction_test_labels.txt");
+        let batch = PredictionBatch {
+            detections: vec![BoundingBox {
+                x1: 10.,
+                y1: 20.,
+                x2: 50.,
+                y2: 100.,
+                class_id: 3,
+                confidence: 0.9,
+                ..Default::default()
+            }],
+            image_width: 200,
+            image_height: 400,
+            ..Default::default()
+        };
+
+        write_yolo_labels(&path, &batch, true).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            "3 0.150000 0.150000 0.200000 0.200000 0.900000\n"
+        );
+    }
+}
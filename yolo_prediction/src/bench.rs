@@ -0,0 +1,415 @@
+use crate::batch::collect_inputs;
+use serde::Serialize;
+use std::{
+    collections::BTreeMap,
+    fmt,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+use tokio::{sync::Semaphore, task::JoinSet, time::Instant};
+use tonic::{
+    metadata::AsciiMetadataValue,
+    transport::{Certificate, Channel, ClientTlsConfig, Endpoint, Identity},
+    Request, Status,
+};
+use yolo_proto::{yolo_service_client::YoloServiceClient, ImageFrame};
+
+/// Options of the `yolo-bench` binary
+#[derive(Debug, Clone, clap::Args)]
+pub struct BenchOptions {
+    /// Image files, directories or glob patterns, sent in turn
+    #[arg(required = true)]
+    pub inputs: Vec<String>,
+    /// Searches directories recursively
+    #[arg(long, short)]
+    pub recursive: bool,
+    /// Address of the inference service, `unix:///path` for a Unix socket
+    #[arg(
+        long,
+        env = "YOLO_BENCH_ADDRESS",
+        default_value = "http://127.0.0.1:50051"
+    )]
+    pub address: String,
+    /// Sent as a bearer token when the service requires authentication
+    #[arg(long, env = "YOLO_BENCH_API_KEY", hide_env_values = true)]
+    pub api_key: Option<String>,
+    /// CA certificate of the service, enables TLS
+    #[arg(long, value_name = "FILE")]
+    pub ca_cert: Option<PathBuf>,
+    /// Client certificate for mutual TLS
+    #[arg(long, value_name = "FILE", requires_all = ["ca_cert", "client_key"])]
+    pub client_cert: Option<PathBuf>,
+    #[arg(long, value_name = "FILE", requires = "client_cert")]
+    pub client_key: Option<PathBuf>,
+    /// Name checked against the server certificate, defaults to the address host
+    #[arg(long, requires = "ca_cert")]
+    pub tls_domain: Option<String>,
+    /// Requests in flight at once. With `--rate`, the most requests left in flight
+    /// before sending falls behind schedule
+    #[arg(long, short, default_value_t = 1)]
+    pub concurrency: usize,
+    /// Sends this many requests per second instead of as many as the service answers
+    #[arg(long)]
+    pub rate: Option<f64>,
+    /// Length of the measurement, in seconds
+    #[arg(long, value_parser = parse_seconds, default_value = "30")]
+    pub duration: Duration,
+    /// Stops the measurement after this many requests
+    #[arg(long)]
+    pub requests: Option<usize>,
+    /// Load sent before measuring so the sessions and allocators are warm, in seconds
+    #[arg(long, value_parser = parse_seconds, default_value = "5")]
+    pub warmup: Duration,
+    /// HTTP/2 connections the requests are spread over
+    #[arg(long, default_value_t = 1)]
+    pub connections: usize,
+    /// Deadline of each request, in milliseconds
+    #[arg(long, default_value_t = 10_000)]
+    pub timeout_ms: u64,
+    /// Also writes the report as JSON to this file, `-` for stdout
+    #[arg(long, value_name = "FILE")]
+    pub json: Option<PathBuf>,
+}
+
+#[derive(Debug, Default, PartialEq, Serialize)]
+pub struct LatencySummary {
+    pub min: f64,
+    pub mean: f64,
+    pub p50: f64,
+    pub p95: f64,
+    pub p99: f64,
+    pub max: f64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct BenchReport {
+    pub address: String,
+    pub concurrency: usize,
+    /// Requests per second asked for with `--rate`
+    pub target_rate: Option<f64>,
+    pub images: usize,
+    pub requests: usize,
+    pub succeeded: usize,
+    /// Failed requests by gRPC status code
+    pub errors: BTreeMap<String, usize>,
+    pub duration_secs: f64,
+    /// Successful requests per second
+    pub throughput: f64,
+    /// Of the successful requests, in milliseconds
+    pub latency_ms: LatencySummary,
+}
+
+#[derive(Debug, Default)]
+struct Samples {
+    latencies: Vec<Duration>,
+    errors: BTreeMap<String, usize>,
+}
+
+impl Samples {
+    fn record(&mut self, latency: Duration, result: Result<(), Status>) {
+        match result {
+            Ok(()) => self.latencies.push(latency),
+            Err(status) => {
+                let count = self
+                    .errors
+                    .entry(format!("{:?}", status.code()))
+                    .or_default();
+                *count += 1;
+                // Once per code, the same failure usually repeats for every request
+                if *count == 1 {
+                    tracing::warn!("Request failed: {}", status);
+                }
+            }
+        }
+    }
+}
+
+struct Bench {
+    clients: Vec<YoloServiceClient<Channel>>,
+    corpus: Vec<Vec<u8>>,
+    authorization: Option<AsciiMetadataValue>,
+    timeout: Duration,
+    concurrency: usize,
+    rate: Option<f64>,
+}
+
+/// Sends the images to `YoloService/Predict` and measures the latency and throughput
+/// seen by the client.
+///
+/// Without `--rate`, `--concurrency` workers each send their next request as soon as
+/// the previous one is answered. With `--rate`, requests are sent on a fixed schedule
+/// and their latency is counted from the time they were due, so a saturated service
+/// shows up in the percentiles rather than as a lower request rate.
+pub async fn run_bench(options: BenchOptions) -> Result<BenchReport, String> {
+    if options.concurrency == 0 || options.connections == 0 {
+        return Err("--concurrency and --connections must be at least 1".into());
+    }
+    if let Some(rate) = options.rate {
+        if !rate.is_finite() || rate <= 0.0 {
+            return Err(format!("Invalid rate {}: must be positive", rate));
+        }
+    }
+
+    let corpus = collect_inputs(&options.inputs, options.recursive)?
+        .iter()
+        .map(|input| {
+            std::fs::read(&input.path)
+                .map_err(|e| format!("failed to read {}: {}", input.path.display(), e))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    tracing::info!("Loaded {} images", corpus.len());
+
+    let authorization = options
+        .api_key
+        .as_ref()
+        .map(|api_key| {
+            format!("Bearer {}", api_key)
+                .parse()
+                .map_err(|_| "Invalid API key: must be printable ASCII".to_string())
+        })
+        .transpose()?;
+
+    let bench = Arc::new(Bench {
+        clients: connect(&options).await?,
+        corpus,
+        authorization,
+        timeout: Duration::from_millis(options.timeout_ms),
+        concurrency: options.concurrency,
+        rate: options.rate,
+    });
+
+    if !options.warmup.is_zero() {
+        tracing::info!("Warming up for {:?}", options.warmup);
+        bench.run(options.warmup, None).await;
+    }
+    tracing::info!("Measuring for {:?}", options.duration);
+    let (samples, elapsed) = bench.run(options.duration, options.requests).await;
+
+    let succeeded = samples.latencies.len();
+    Ok(BenchReport {
+        address: options.address,
+        concurrency: options.concurrency,
+        target_rate: options.rate,
+        images: bench.corpus.len(),
+        requests: succeeded + samples.errors.values().sum::<usize>(),
+        succeeded,
+        errors: samples.errors,
+        duration_secs: elapsed.as_secs_f64(),
+        throughput: succeeded as f64 / elapsed.as_secs_f64(),
+        latency_ms: LatencySummary::new(samples.latencies),
+    })
+}
+
+async fn connect(options: &BenchOptions) -> Result<Vec<YoloServiceClient<Channel>>, String> {
+    let mut endpoint = Endpoint::from_shared(options.address.clone())
+        .map_err(|e| format!("Invalid address {}: {}", options.address, e))?;
+
+    if let Some(ca_cert) = &options.ca_cert {
+        let read = |path: &PathBuf| {
+            std::fs::read(path).map_err(|e| format!("failed to read {}: {}", path.display(), e))
+        };
+        let mut tls_config =
+            ClientTlsConfig::new().ca_certificate(Certificate::from_pem(read(ca_cert)?));
+        if let (Some(cert), Some(key)) = (&options.client_cert, &options.client_key) {
+            tls_config = tls_config.identity(Identity::from_pem(read(cert)?, read(key)?));
+        }
+        if let Some(domain) = &options.tls_domain {
+            tls_config = tls_config.domain_name(domain);
+        }
+        endpoint = endpoint
+            .tls_config(tls_config)
+            .map_err(|e| format!("Invalid TLS configuration: {}", e))?;
+    }
+
+    let mut clients = Vec::with_capacity(options.connections);
+    for _ in 0..options.connections {
+        let channel = endpoint
+            .connect()
+            .await
+            .map_err(|e| format!("failed to connect to {}: {}", options.address, e))?;
+        clients.push(YoloServiceClient::new(channel));
+    }
+    Ok(clients)
+}
+
+impl Bench {
+    /// Sends requests until `duration` has passed or `max_requests` were sent, then
+    /// waits for the requests in flight.
+    async fn run(
+        self: &Arc<Self>,
+        duration: Duration,
+        max_requests: Option<usize>,
+    ) -> (Samples, Duration) {
+        let start = Instant::now();
+        let deadline = start + duration;
+        let samples = Arc::new(Mutex::new(Samples::default()));
+        let mut tasks = JoinSet::new();
+
+        match self.rate {
+            None => {
+                let sent = Arc::new(AtomicUsize::new(0));
+                for _ in 0..self.concurrency {
+                    let bench = self.clone();
+                    let sent = sent.clone();
+                    let samples = samples.clone();
+                    tasks.spawn(async move {
+                        while Instant::now() < deadline {
+                            let index = sent.fetch_add(1, Ordering::Relaxed);
+                            if max_requests.is_some_and(|max| index >= max) {
+                                break;
+                            }
+                            let sent_at = Instant::now();
+                            let result = bench.send(index).await;
+                            samples.lock().unwrap().record(sent_at.elapsed(), result);
+                        }
+                    });
+                }
+            }
+            Some(rate) => {
+                let interval = Duration::from_secs_f64(1.0 / rate);
+                let in_flight = Arc::new(Semaphore::new(self.concurrency));
+                for index in 0..max_requests.unwrap_or(usize::MAX) {
+                    let scheduled = start + interval.mul_f64(index as f64);
+                    if scheduled >= deadline {
+                        break;
+                    }
+                    tokio::time::sleep_until(scheduled).await;
+                    let permit = in_flight.clone().acquire_owned().await.unwrap();
+
+                    let bench = self.clone();
+                    let samples = samples.clone();
+                    tasks.spawn(async move {
+                        let result = bench.send(index).await;
+                        samples.lock().unwrap().record(scheduled.elapsed(), result);
+                        drop(permit);
+                    });
+                    while tasks.try_join_next().is_some() {}
+                }
+            }
+        }
+
+        while tasks.join_next().await.is_some() {}
+        let elapsed = start.elapsed();
+        let samples = std::mem::take(&mut *samples.lock().unwrap());
+        (samples, elapsed)
+    }
+
+    async fn send(&self, index: usize) -> Result<(), Status> {
+        let mut client = self.clients[index % self.clients.len()].clone();
+        let mut request = Request::new(ImageFrame {
+            image_data: self.corpus[index % self.corpus.len()].clone(),
+            ..Default::default()
+        });
+        request.set_timeout(self.timeout);
+        if let Some(authorization) = &self.authorization {
+            request
+                .metadata_mut()
+                .insert("authorization", authorization.clone());
+        }
+        client.predict(request).await.map(|_| ())
+    }
+}
+
+impl LatencySummary {
+    /// Nearest-rank percentiles, in milliseconds
+    fn new(mut latencies: Vec<Duration>) -> Self {
+        if latencies.is_empty() {
+            return Self::default();
+        }
+        latencies.sort();
+
+        let millis = |latency: Duration| latency.as_secs_f64() * 1000.0;
+        let percentile = |p: f64| {
+            let rank = (p / 100.0 * latencies.len() as f64).ceil() as usize;
+            millis(latencies[rank.clamp(1, latencies.len()) - 1])
+        };
+        Self {
+            min: millis(latencies[0]),
+            mean: latencies.iter().copied().map(millis).sum::<f64>() / latencies.len() as f64,
+            p50: percentile(50.0),
+            p95: percentile(95.0),
+            p99: percentile(99.0),
+            max: millis(latencies[latencies.len() - 1]),
+        }
+    }
+}
+
+impl fmt::Display for BenchReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Target: {}, concurrency {}",
+            self.address, self.concurrency
+        )?;
+        match self.target_rate {
+            Some(rate) => writeln!(f, ", {} requests/s", rate)?,
+            None => writeln!(f)?,
+        }
+        writeln!(f, "Images: {}", self.images)?;
+        writeln!(
+            f,
+            "Requests: {} ({} failed)",
+            self.requests,
+            self.requests - self.succeeded
+        )?;
+        for (code, count) in &self.errors {
+            writeln!(f, "  {}: {}", code, count)?;
+        }
+        writeln!(f, "Duration: {:.2} s", self.duration_secs)?;
+        writeln!(f, "Throughput: {:.2} requests/s", self.throughput)?;
+        writeln!(f)?;
+
+        let latency = &self.latency_ms;
+        writeln!(
+            f,
+            "{:<12} {:>9} {:>9} {:>9} {:>9} {:>9} {:>9}",
+            "latency", "min", "mean", "p50", "p95", "p99", "max"
+        )?;
+        writeln!(
+            f,
+            "{:<12} {:>9.2} {:>9.2} {:>9.2} {:>9.2} {:>9.2} {:>9.2}",
+            "ms", latency.min, latency.mean, latency.p50, latency.p95, latency.p99, latency.max
+        )
+    }
+}
+
+fn parse_seconds(value: &str) -> Result<Duration, String> {
+    value
+        .parse::<f64>()
+        .ok()
+        .and_then(|seconds| Duration::try_from_secs_f64(seconds).ok())
+        .ok_or_else(|| format!("invalid number of seconds: {}", value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_latency_summary() {
+        let latencies = (1..=200).rev().map(Duration::from_millis).collect();
+        let summary = LatencySummary::new(latencies);
+
+        assert_eq!(summary.min, 1.0);
+        assert_eq!(summary.mean, 100.5);
+        assert_eq!(summary.p50, 100.0);
+        assert_eq!(summary.p95, 190.0);
+        assert_eq!(summary.p99, 198.0);
+        assert_eq!(summary.max, 200.0);
+
+        assert_eq!(LatencySummary::new(Vec::new()), LatencySummary::default());
+        assert_eq!(LatencySummary::new(vec![Duration::from_millis(7)]).p99, 7.0);
+    }
+
+    #[test]
+    fn test_parse_seconds() {
+        assert_eq!(parse_seconds("1.5"), Ok(Duration::from_millis(1500)));
+        assert_eq!(parse_seconds("0"), Ok(Duration::ZERO));
+        assert!(parse_seconds("-1").is_err());
+        assert!(parse_seconds("soon").is_err());
+    }
+}
@@ -0,0 +1,64 @@
+use clap::Parser;
+use std::{
+    fs::File,
+    io::{self, Write},
+    path::Path,
+    process::ExitCode,
+};
+use tracing_subscriber::EnvFilter;
+use yolo_prediction::{run_bench, BenchOptions, BenchReport};
+
+#[derive(Debug, Parser)]
+#[command(
+    version,
+    about = "Measures the latency and throughput of a running inference service"
+)]
+struct Cli {
+    #[command(flatten)]
+    bench: BenchOptions,
+}
+
+fn write_json(path: &Path, report: &BenchReport) -> io::Result<()> {
+    let json = serde_json::to_string_pretty(report)?;
+    if path == Path::new("-") {
+        return writeln!(io::stdout(), "{}", json);
+    }
+    writeln!(File::create(path)?, "{}", json)
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    let cli = Cli::parse();
+
+    tracing_subscriber::fmt()
+        .with_writer(std::io::stderr)
+        .with_env_filter(EnvFilter::try_from_default_env().unwrap_or_else(|_| "info".into()))
+        .init();
+
+    let json = cli.bench.json.clone();
+    let report = match run_bench(cli.bench).await {
+        Ok(report) => report,
+        Err(e) => {
+            eprintln!("{}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    // The table moves to stderr when stdout carries the JSON report
+    match &json {
+        Some(path) if path == Path::new("-") => eprint!("{}", report),
+        _ => print!("{}", report),
+    }
+    if let Some(path) = &json {
+        if let Err(e) = write_json(path, &report) {
+            eprintln!("failed to write {}: {}", path.display(), e);
+            return ExitCode::FAILURE;
+        }
+    }
+
+    if report.succeeded == 0 {
+        eprintln!("No request succeeded");
+        return ExitCode::FAILURE;
+    }
+    ExitCode::SUCCESS
+}
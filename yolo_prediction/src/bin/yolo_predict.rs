@@ -0,0 +1,125 @@
+use clap::{Parser, Subcommand};
+use std::{path::PathBuf, process::ExitCode};
+use tracing_subscriber::EnvFilter;
+use yolo_prediction::{
+    config::{self, ConfigOptions},
+    run_batch, run_evaluation, run_replay, BatchOptions, EvaluateOptions, ReplayOptions,
+};
+
+#[derive(Debug, Parser)]
+#[command(
+    version,
+    about = "Runs YOLO object detection over image files, without the gRPC server",
+    args_conflicts_with_subcommands = true,
+    subcommand_negates_reqs = true
+)]
+struct Cli {
+    /// Directory holding `base.yaml` and the profile files
+    #[arg(long, global = true, default_value = "configuration")]
+    config_dir: PathBuf,
+    /// Profile merged over `base.yaml`, `<profile>.yaml` in the config directory
+    #[arg(long, global = true, env = "APP_ENVIRONMENT", default_value = "local")]
+    profile: String,
+    /// Overrides a setting, e.g. `--set model.min_probability=0.3`. Can be repeated
+    #[arg(long = "set", global = true, value_name = "KEY=VALUE", value_parser = config::parse_override)]
+    overrides: Vec<(String, String)>,
+    #[command(subcommand)]
+    command: Option<Command>,
+    #[command(flatten)]
+    batch: BatchOptions,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Scores the model against a labeled dataset: mAP, per-class precision and
+    /// recall, and a confusion matrix
+    Evaluate(EvaluateOptions),
+    /// Runs the requests recorded by the server again against the configured model
+    /// and reports the ones whose detections changed
+    Replay(ReplayOptions),
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    let cli = Cli::parse();
+
+    // Results may go to stdout, so logs are written to stderr
+    tracing_subscriber::fmt()
+        .with_writer(std::io::stderr)
+        .with_env_filter(
+            EnvFilter::try_from_default_env().unwrap_or_else(|_| "info,ort=warn".into()),
+        )
+        .init();
+
+    let options = ConfigOptions {
+        config_dir: cli.config_dir,
+        profile: cli.profile,
+        overrides: cli.overrides,
+    };
+    let config = match config::get_configuration(&options) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("Invalid configuration: {}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    if let Some(Command::Replay(replay_options)) = cli.command {
+        let json = replay_options.json;
+        return match run_replay(config, replay_options).await {
+            Ok(report) => {
+                if json {
+                    println!("{}", serde_json::to_string_pretty(&report).unwrap());
+                } else {
+                    print!("{}", report);
+                }
+                if report.changed == 0 && report.failed == 0 {
+                    ExitCode::SUCCESS
+                } else {
+                    ExitCode::FAILURE
+                }
+            }
+            Err(e) => {
+                eprintln!("{}", e);
+                ExitCode::FAILURE
+            }
+        };
+    }
+
+    if let Some(Command::Evaluate(evaluate_options)) = cli.command {
+        let json = evaluate_options.json;
+        return match run_evaluation(config, evaluate_options).await {
+            Ok(report) => {
+                if json {
+                    println!("{}", serde_json::to_string_pretty(&report).unwrap());
+                } else {
+                    print!("{}", report);
+                }
+                if report.failed == 0 {
+                    ExitCode::SUCCESS
+                } else {
+                    ExitCode::FAILURE
+                }
+            }
+            Err(e) => {
+                eprintln!("{}", e);
+                ExitCode::FAILURE
+            }
+        };
+    }
+
+    match run_batch(config, cli.batch).await {
+        Ok(summary) if summary.failed == 0 => ExitCode::SUCCESS,
+        Ok(summary) => {
+            eprintln!(
+                "{} of {} images could not be processed",
+                summary.failed, summary.processed
+            );
+            ExitCode::FAILURE
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
@@ -0,0 +1,134 @@
+use crate::config::ClassFilterConfig;
+use yolo_proto::ColorLabel;
+
+#[derive(Debug, Clone)]
+pub struct ClassFilter {
+    thresholds: Vec<f32>,
+    enabled: Vec<bool>,
+    default_threshold: f32,
+    allow_unlisted: bool,
+}
+
+impl ClassFilter {
+    pub fn new(
+        filter_cfg: &ClassFilterConfig,
+        min_probability: f32,
+        labels: &[ColorLabel],
+    ) -> Result<Self, String> {
+        let class_id = |name: &str| {
+            labels
+                .iter()
+                .position(|color_label| color_label.label == name)
+                .ok_or_else(|| format!("Unknown class label in class filter: {}", name))
+        };
+
+        let allow_unlisted = filter_cfg.allow.is_empty();
+        let mut thresholds = vec![min_probability; labels.len()];
+        let mut enabled = vec![allow_unlisted; labels.len()];
+
+        for (name, threshold) in &filter_cfg.thresholds {
+            if !(0.0..=1.0).contains(threshold) {
+                return Err(format!(
+                    "Invalid threshold {} for class {}: must be between 0 and 1",
+                    threshold, name
+                ));
+            }
+            thresholds[class_id(name)?] = *threshold;
+        }
+
+        for name in &filter_cfg.allow {
+            enabled[class_id(name)?] = true;
+        }
+
+        for name in &filter_cfg.deny {
+            enabled[class_id(name)?] = false;
+        }
+
+        // Classes disabled in the labels file stay disabled whatever the config says
+        for (class_enabled, color_label) in enabled.iter_mut().zip(labels) {
+            *class_enabled &= color_label.enabled;
+        }
+
+        Ok(Self {
+            thresholds,
+            enabled,
+            default_threshold: min_probability,
+            allow_unlisted,
+        })
+    }
+
+    pub fn threshold(&self, class_id: usize) -> f32 {
+        self.thresholds
+            .get(class_id)
+            .copied()
+            .unwrap_or(self.default_threshold)
+    }
+
+    pub fn is_enabled(&self, class_id: usize) -> bool {
+        self.enabled
+            .get(class_id)
+            .copied()
+            .unwrap_or(self.allow_unlisted)
+    }
+
+    pub fn accepts(&self, class_id: usize, prob: f32) -> bool {
+        self.is_enabled(class_id) && prob >= self.threshold(class_id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn labels(names: &[&str]) -> Vec<ColorLabel> {
+        names
+            .iter()
+            .map(|name| ColorLabel {
+                label: name.to_string(),
+                enabled: true,
+                ..Default::default()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_class_filter() {
+        let filter_cfg = ClassFilterConfig {
+            thresholds: HashMap::from([("person".to_string(), 0.3), ("tie".to_string(), 0.8)]),
+            allow: vec![],
+            deny: vec!["car".to_string()],
+        };
+        let filter =
+            ClassFilter::new(&filter_cfg, 0.5, &labels(&["person", "car", "tie"])).unwrap();
+
+        assert!(filter.accepts(0, 0.35));
+        assert!(!filter.accepts(1, 0.99));
+        assert!(!filter.accepts(2, 0.6));
+        assert!(filter.accepts(2, 0.85));
+        assert!(filter.accepts(7, 0.5));
+    }
+
+    #[test]
+    fn test_class_filter_allow_list() {
+        let filter_cfg = ClassFilterConfig {
+            allow: vec!["car".to_string()],
+            ..Default::default()
+        };
+        let filter = ClassFilter::new(&filter_cfg, 0.5, &labels(&["person", "car"])).unwrap();
+
+        assert!(!filter.accepts(0, 0.9));
+        assert!(filter.accepts(1, 0.9));
+        assert!(!filter.accepts(7, 0.9));
+    }
+
+    #[test]
+    fn test_class_filter_unknown_label() {
+        let filter_cfg = ClassFilterConfig {
+            deny: vec!["unicorn".to_string()],
+            ..Default::default()
+        };
+
+        assert!(ClassFilter::new(&filter_cfg, 0.5, &labels(&["person"])).is_err());
+    }
+}
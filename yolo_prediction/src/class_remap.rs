@@ -0,0 +1,298 @@
+use crate::{
+    class_filter::ClassFilter, config::ClassRemapConfig, labels::parse_hex_color,
+    postprocessing::non_max_suppression,
+};
+use std::collections::{HashMap, HashSet};
+use yolo_proto::{BoundingBox, ColorLabel};
+
+/// Maps model classes onto the taxonomy exposed to clients: source classes can be
+/// merged into a target, renamed (a target with a single source) or dropped.
+#[derive(Debug, Clone)]
+pub struct ClassRemapper {
+    mapping: Vec<Option<i32>>,
+    merged: Vec<bool>,
+    labels: Vec<ColorLabel>,
+    nms_iou_threshold: f32,
+    is_identity: bool,
+}
+
+impl ClassRemapper {
+    pub fn new(remap_cfg: &ClassRemapConfig, source_labels: &[ColorLabel]) -> Result<Self, String> {
+        if remap_cfg.targets.is_empty() && remap_cfg.drop.is_empty() {
+            return Ok(Self {
+                mapping: (0..source_labels.len() as i32).map(Some).collect(),
+                merged: vec![false; source_labels.len()],
+                labels: source_labels.to_vec(),
+                nms_iou_threshold: remap_cfg.nms_iou_threshold,
+                is_identity: true,
+            });
+        }
+
+        let source_ids: HashMap<&str, usize> = source_labels
+            .iter()
+            .enumerate()
+            .map(|(class_id, color_label)| (color_label.label.as_str(), class_id))
+            .collect();
+        let class_id = |name: &str| {
+            source_ids
+                .get(name)
+                .copied()
+                .ok_or_else(|| format!("Unknown class label in class remap: {}", name))
+        };
+
+        let mut mapping: Vec<Option<i32>> = vec![None; source_labels.len()];
+        let mut assigned = vec![false; source_labels.len()];
+        let mut assign = |source_id: usize, name: &str| {
+            if std::mem::replace(&mut assigned[source_id], true) {
+                return Err(format!("Class {} is remapped more than once", name));
+            }
+            Ok(())
+        };
+
+        for name in &remap_cfg.drop {
+            assign(class_id(name)?, name)?;
+        }
+
+        let mut labels = Vec::new();
+        let mut merged = Vec::new();
+        for target in &remap_cfg.targets {
+            if target.sources.is_empty() {
+                return Err(format!(
+                    "Remap target {} has no source classes",
+                    target.name
+                ));
+            }
+
+            let target_id = labels.len() as i32;
+            let mut sources = Vec::new();
+            for name in &target.sources {
+                let source_id = class_id(name)?;
+                assign(source_id, name)?;
+                mapping[source_id] = Some(target_id);
+                sources.push(&source_labels[source_id]);
+            }
+
+            let (red, green, blue) = match &target.color {
+                Some(color) => parse_hex_color(color)?,
+                None => (sources[0].red, sources[0].green, sources[0].blue),
+            };
+            labels.push(ColorLabel {
+                label: target.name.clone(),
+                red,
+                green,
+                blue,
+                min_probability: sources
+                    .iter()
+                    .map(|source| source.min_probability)
+                    .fold(f32::INFINITY, f32::min),
+                enabled: sources.iter().any(|source| source.enabled),
+                ..Default::default()
+            });
+            merged.push(sources.len() > 1);
+        }
+
+        if remap_cfg.keep_unmapped {
+            for (source_id, color_label) in source_labels.iter().enumerate() {
+                if !assigned[source_id] {
+                    mapping[source_id] = Some(labels.len() as i32);
+                    labels.push(color_label.clone());
+                    merged.push(false);
+                }
+            }
+        }
+
+        let mut names = HashSet::new();
+        if let Some(duplicate) = labels.iter().find(|l| !names.insert(l.label.as_str())) {
+            return Err(format!(
+                "Class {} appears twice in the remapped labels",
+                duplicate.label
+            ));
+        }
+
+        tracing::info!(
+            "Remapped {} model classes onto {} classes",
+            source_labels.len(),
+            labels.len()
+        );
+
+        Ok(Self {
+            mapping,
+            merged,
+            labels,
+            nms_iou_threshold: remap_cfg.nms_iou_threshold,
+            is_identity: false,
+        })
+    }
+
+    /// Labels carrying the thresholds and enabled flags resolved by the class filter.
+    /// A merged class gets the lowest threshold of its sources and is enabled when
+    /// any of them is.
+    pub fn annotate_labels(&self, class_filter: &ClassFilter) -> Vec<ColorLabel> {
+        let mut labels = self.labels.clone();
+        for color_label in &mut labels {
+            color_label.min_probability = f32::INFINITY;
+            color_label.enabled = false;
+        }
+
+        for (source_id, target_id) in self.mapping.iter().enumerate() {
+            if let Some(color_label) = target_id.and_then(|id| labels.get_mut(id as usize)) {
+                color_label.min_probability = color_label
+                    .min_probability
+                    .min(class_filter.threshold(source_id));
+                color_label.enabled |= class_filter.is_enabled(source_id);
+            }
+        }
+
+        labels
+    }
+
+    /// Scores in the remapped taxonomy: a merged class gets the best score of its
+    /// sources, dropped classes are left out.
+    fn remap_scores(&self, scores: &[f32]) -> Vec<f32> {
+        let mut remapped = vec![0f32; self.labels.len()];
+        for (score, target_id) in scores.iter().zip(&self.mapping) {
+            if let Some(target_id) = target_id {
+                let remapped_score = &mut remapped[*target_id as usize];
+                *remapped_score = remapped_score.max(*score);
+            }
+        }
+        remapped
+    }
+
+    /// Rewrites class ids and scores into the remapped taxonomy, drops hidden classes
+    /// and suppresses overlapping boxes that now share a merged class.
+    pub fn apply(&self, detections: Vec<BoundingBox>) -> Vec<BoundingBox> {
+        if self.is_identity {
+            return detections;
+        }
+
+        let mut result = Vec::new();
+        let mut merged_groups: HashMap<i32, Vec<BoundingBox>> = HashMap::new();
+
+        for mut detection in detections {
+            let Some(target_id) = usize::try_from(detection.class_id)
+                .ok()
+                .and_then(|source_id| self.mapping.get(source_id).copied().flatten())
+            else {
+                continue;
+            };
+
+            detection.class_id = target_id;
+            if !detection.class_scores.is_empty() {
+                detection.class_scores = self.remap_scores(&detection.class_scores);
+            }
+            if self.merged[target_id as usize] {
+                merged_groups.entry(target_id).or_default().push(detection);
+            } else {
+                result.push(detection);
+            }
+        }
+
+        for (_, group) in merged_groups {
+            result.extend(non_max_suppression(group, self.nms_iou_threshold));
+        }
+
+        result.sort_by(|box1, box2| box2.confidence.total_cmp(&box1.confidence));
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{ClassFilterConfig, RemapTargetConfig};
+
+    fn labels(names: &[&str]) -> Vec<ColorLabel> {
+        names
+            .iter()
+            .map(|name| ColorLabel {
+                label: name.to_string(),
+                min_probability: 0.5,
+                enabled: true,
+                ..Default::default()
+            })
+            .collect()
+    }
+
+    fn bbox(x1: f32, class_id: i32, confidence: f32) -> BoundingBox {
+        BoundingBox {
+            x1,
+            y1: 0.,
+            x2: x1 + 10.,
+            y2: 10.,
+            class_id,
+            confidence,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_class_remapper() {
+        let remap_cfg = ClassRemapConfig {
+            targets: vec![
+                RemapTargetConfig {
+                    name: "vehicle".to_string(),
+                    sources: vec!["car".to_string(), "truck".to_string()],
+                    color: Some("#FF8800".to_string()),
+                },
+                RemapTargetConfig {
+                    name: "human".to_string(),
+                    sources: vec!["person".to_string()],
+                    color: None,
+                },
+            ],
+            drop: vec!["tie".to_string()],
+            ..Default::default()
+        };
+        let source_labels = labels(&["person", "car", "truck", "tie", "dog"]);
+        let remapper = ClassRemapper::new(&remap_cfg, &source_labels).unwrap();
+
+        let names: Vec<_> = remapper.labels.iter().map(|l| l.label.as_str()).collect();
+        assert_eq!(names, vec!["vehicle", "human", "dog"]);
+        assert_eq!(remapper.labels[0].red, 255);
+
+        let filter_cfg = ClassFilterConfig {
+            thresholds: HashMap::from([("truck".to_string(), 0.3)]),
+            deny: vec!["car".to_string(), "dog".to_string()],
+            ..Default::default()
+        };
+        let class_filter = ClassFilter::new(&filter_cfg, 0.5, &source_labels).unwrap();
+        let annotated = remapper.annotate_labels(&class_filter);
+        assert_eq!(annotated[0].min_probability, 0.3);
+        assert!(annotated[0].enabled);
+        assert!(!annotated[2].enabled);
+
+        let detections = remapper.apply(vec![
+            bbox(0., 1, 0.9),
+            bbox(1., 2, 0.8),
+            bbox(50., 0, 0.7),
+            bbox(80., 3, 0.95),
+            bbox(100., 4, 0.6),
+        ]);
+
+        let class_ids: Vec<_> = detections.iter().map(|d| d.class_id).collect();
+        assert_eq!(class_ids, vec![0, 1, 2]);
+
+        // Merged classes get the best score of their sources
+        let detections = remapper.apply(vec![BoundingBox {
+            class_scores: vec![0.1, 0.6, 0.3, 0.2, 0.05],
+            ..bbox(0., 1, 0.6)
+        }]);
+        assert_eq!(detections[0].class_scores, vec![0.6, 0.1, 0.05]);
+    }
+
+    #[test]
+    fn test_class_remapper_rejects_duplicates() {
+        let remap_cfg = ClassRemapConfig {
+            targets: vec![RemapTargetConfig {
+                name: "vehicle".to_string(),
+                sources: vec!["car".to_string()],
+                color: None,
+            }],
+            drop: vec!["car".to_string()],
+            ..Default::default()
+        };
+
+        assert!(ClassRemapper::new(&remap_cfg, &labels(&["car"])).is_err());
+    }
+}
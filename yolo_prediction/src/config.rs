@@ -1,36 +1,322 @@
-use serde::Deserialize;
-use std::path::PathBuf;
+use crate::{
+    auth::load_api_keys,
+    http_gateway::cors_layer,
+    regions::RegionFilter,
+    shadow,
+    state::{ServiceState, State},
+    tls::load_server_config,
+};
+use prost::Message;
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, net::SocketAddr, path::PathBuf};
+use yolo_proto::{DetectionExtras, ImageFrame, Point, Polygon, RegionFilter as ProtoRegionFilter};
 
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct Config {
     pub server: ServerConfig,
     pub model: ModelConfig,
-    pub labels: LabelsConfig,
-    #[serde(deserialize_with = "deserialize_log_level")]
+    pub labels: Option<LabelsConfig>,
+    #[serde(default)]
+    pub image: ImageConfig,
+    #[serde(default)]
+    pub regions: RegionsConfig,
+    pub auth: Option<AuthConfig>,
+    #[serde(default)]
+    pub rate_limit: RateLimitConfig,
+    #[serde(default)]
+    pub cache: CacheConfig,
+    pub shadow: Option<ShadowConfig>,
+    pub recorder: Option<RecorderConfig>,
+    #[serde(default)]
+    pub metrics: MetricsConfig,
+    #[serde(default)]
+    pub tracing: TracingConfig,
+    #[serde(deserialize_with = "deserialize_from_string")]
     pub log_level: LogLevel,
 }
 
-fn deserialize_log_level<'de, D>(deserializer: D) -> Result<LogLevel, D::Error>
+/// Enums read with [`deserialize_from_string`] are written back with the same names
+macro_rules! serialize_as_str {
+    ($($name:ty),*) => {
+        $(impl Serialize for $name {
+            fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                serializer.serialize_str(self.as_str())
+            }
+        })*
+    };
+}
+
+serialize_as_str!(Backend, LabelPalette, AnchorPoint, LogLevel);
+
+fn deserialize_from_string<'de, D, T>(deserializer: D) -> Result<T, D::Error>
 where
     D: serde::Deserializer<'de>,
+    T: TryFrom<String, Error = String>,
 {
     let s = String::deserialize(deserializer)?;
     s.try_into().map_err(serde::de::Error::custom)
 }
 
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct ServerConfig {
     pub host: String,
     pub port: u16,
+    pub tls: Option<TlsConfig>,
+    /// REST/JSON gateway and gRPC-Web, disabled when unset
+    #[serde(default)]
+    pub http: Option<HttpConfig>,
+    /// Also serve gRPC on this Unix socket, for clients sharing the filesystem.
+    /// Without TLS, access is controlled by the socket's file permissions.
+    #[serde(default)]
+    pub unix_socket: Option<PathBuf>,
 }
 
 impl ServerConfig {
-    pub fn get_address(self) -> String {
+    pub fn get_address(&self) -> String {
         format!("{}:{}", self.host, self.port)
     }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.tls.is_some() && self.http.is_some() {
+            return Err(
+                "server.http: the HTTP gateway is served in plain text and cannot be \
+                 enabled with server.tls, terminate TLS in front of it instead"
+                    .to_string(),
+            );
+        }
+        Ok(())
+    }
+
+    pub fn get_http_address(&self) -> Option<String> {
+        self.http
+            .as_ref()
+            .map(|http_cfg| format!("{}:{}", self.host, http_cfg.port))
+    }
 }
 
-#[derive(Debug, Deserialize, Clone)]
+/// HTTP listener for clients that cannot speak gRPC. It is served in plain text,
+/// TLS has to be terminated by a reverse proxy. It cannot be combined with
+/// `server.tls`, which would leave the gateway as an unencrypted way around it.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct HttpConfig {
+    pub port: u16,
+    /// Origins allowed to call the API from a browser, `*` allows any origin
+    #[serde(default)]
+    pub cors_allowed_origins: Vec<String>,
+}
+
+/// PEM files for the gRPC listener. Setting `client_ca_file` enables mutual TLS.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct TlsConfig {
+    pub cert_file: PathBuf,
+    pub key_file: PathBuf,
+    #[serde(default)]
+    pub client_ca_file: Option<PathBuf>,
+    /// How often the files are checked for changes, 0 disables reloading
+    #[serde(default = "default_tls_reload_interval_secs")]
+    pub reload_interval_secs: u64,
+}
+
+fn default_tls_reload_interval_secs() -> u64 {
+    30
+}
+
+/// Enables API key authentication. Keys are `name:token:scope,scope` entries read
+/// from `keys_file` (one per line) and/or the `keys_env` environment variable
+/// (separated by `;`). Without it, the admin RPCs are refused.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct AuthConfig {
+    #[serde(default)]
+    pub keys_file: Option<PathBuf>,
+    #[serde(default)]
+    pub keys_env: Option<String>,
+}
+
+/// Per-client token buckets plus a global cap on concurrent predictions. Clients
+/// are identified by API key name, or by peer IP when authentication is off.
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct RateLimitConfig {
+    /// Sustained rate allowed per client, 0 disables per-client limits
+    #[serde(default)]
+    pub requests_per_second: f64,
+    /// Bucket size, defaults to one second worth of requests
+    #[serde(default)]
+    pub burst: Option<u32>,
+    /// Overrides keyed by API key name
+    #[serde(default)]
+    pub clients: HashMap<String, ClientRateLimitConfig>,
+    /// Predictions running at once across all clients, 0 means unlimited
+    #[serde(default)]
+    pub max_concurrent_requests: usize,
+}
+
+/// Results of byte-identical frames, served without running the model again.
+/// Clients skip the cache by sending `cache-control: no-store`.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct CacheConfig {
+    /// Results kept, the least recently used are evicted first. 0 disables the cache
+    #[serde(default)]
+    pub max_entries: usize,
+    /// Seconds a result is served for, 0 keeps it until it is evicted
+    #[serde(default = "default_cache_ttl_secs")]
+    pub ttl_secs: u64,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        Self {
+            max_entries: 0,
+            ttl_secs: default_cache_ttl_secs(),
+        }
+    }
+}
+
+fn default_cache_ttl_secs() -> u64 {
+    60
+}
+
+/// Candidate model run on a sample of the `Predict` calls, next to the primary one,
+/// to compare their results before promoting it. Clients always get the primary
+/// result. The shadow model runs on the primary's backend, with the same settings,
+/// regions and class remapping, so it must predict the same classes.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct ShadowConfig {
+    pub onnx_file: String,
+    /// Defaults to `model.model_dir`
+    #[serde(default)]
+    pub model_dir: Option<PathBuf>,
+    #[serde(default = "default_shadow_instances")]
+    pub num_instances: usize,
+    /// Share of the predictions mirrored to the shadow model
+    #[serde(default = "default_shadow_sample_ratio")]
+    pub sample_ratio: f64,
+    /// Shadow predictions running at once, frames sampled while they are all busy are skipped
+    #[serde(default = "default_shadow_max_concurrent")]
+    pub max_concurrent: usize,
+    /// Boxes of the two models overlapping at least this much are matched
+    #[serde(default = "default_shadow_iou_threshold")]
+    pub iou_threshold: f32,
+    /// Writes the frames the models disagree on to this directory, with both results
+    #[serde(default)]
+    pub dump_dir: Option<PathBuf>,
+    /// Frames written to `dump_dir` at most, over the life of the process
+    #[serde(default = "default_shadow_max_dumps")]
+    pub max_dumps: usize,
+}
+
+impl ShadowConfig {
+    /// The primary model config pointing at the shadow model
+    pub fn model_config(&self, model_cfg: &ModelConfig) -> ModelConfig {
+        ModelConfig {
+            onnx_file: self.onnx_file.clone(),
+            model_dir: self
+                .model_dir
+                .clone()
+                .unwrap_or_else(|| model_cfg.model_dir.clone()),
+            num_instances: self.num_instances,
+            ..model_cfg.clone()
+        }
+    }
+}
+
+fn default_shadow_instances() -> usize {
+    1
+}
+
+fn default_shadow_sample_ratio() -> f64 {
+    0.1
+}
+
+fn default_shadow_max_concurrent() -> usize {
+    1
+}
+
+fn default_shadow_iou_threshold() -> f32 {
+    0.5
+}
+
+fn default_shadow_max_dumps() -> usize {
+    1000
+}
+
+/// Archive of the `Predict` calls, replayed with `yolo-predict replay`. Each record
+/// holds the frame as received, the settings and zones in effect and the result.
+/// Files are rotated by size, the oldest are deleted once the archive is full.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct RecorderConfig {
+    pub dir: PathBuf,
+    /// Records from startup, otherwise recording is started through `UpdateSettings`
+    #[serde(default)]
+    pub enabled: bool,
+    #[serde(default = "default_recorder_max_file_bytes")]
+    pub max_file_bytes: u64,
+    #[serde(default = "default_recorder_max_total_bytes")]
+    pub max_total_bytes: u64,
+}
+
+fn default_recorder_max_file_bytes() -> u64 {
+    64 * 1024 * 1024
+}
+
+fn default_recorder_max_total_bytes() -> u64 {
+    1024 * 1024 * 1024
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct ClientRateLimitConfig {
+    pub requests_per_second: f64,
+    #[serde(default)]
+    pub burst: Option<u32>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct MetricsConfig {
+    /// Push metrics to the collector set by `OTEL_EXPORTER_OTLP_ENDPOINT`
+    #[serde(default = "default_otlp_enabled")]
+    pub otlp: bool,
+    /// Serve `/metrics` for Prometheus on this address, e.g. `0.0.0.0:9464`.
+    /// Needs the `prometheus` feature.
+    #[serde(default)]
+    pub prometheus_address: Option<String>,
+}
+
+impl Default for MetricsConfig {
+    fn default() -> Self {
+        Self {
+            otlp: default_otlp_enabled(),
+            prometheus_address: None,
+        }
+    }
+}
+
+fn default_otlp_enabled() -> bool {
+    true
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct TracingConfig {
+    /// Export spans to the collector set by `OTEL_EXPORTER_OTLP_ENDPOINT`
+    #[serde(default = "default_otlp_enabled")]
+    pub otlp: bool,
+    /// Share of new traces that are kept, requests joining a sampled trace always are
+    #[serde(default = "default_trace_sample_ratio")]
+    pub sample_ratio: f64,
+}
+
+impl Default for TracingConfig {
+    fn default() -> Self {
+        Self {
+            otlp: default_otlp_enabled(),
+            sample_ratio: default_trace_sample_ratio(),
+        }
+    }
+}
+
+fn default_trace_sample_ratio() -> f64 {
+    1.0
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct ModelConfig {
     pub onnx_file: String,
     #[serde(default = "default_model_instances")]
@@ -38,6 +324,49 @@ pub struct ModelConfig {
     pub model_dir: PathBuf,
     #[serde(default = "default_min_probability")]
     pub min_probability: f32,
+    /// Boxes of the same class overlapping more than this are suppressed
+    #[serde(default = "default_nms_iou_threshold")]
+    pub nms_iou_threshold: f32,
+    #[serde(default)]
+    pub class_filter: ClassFilterConfig,
+    #[serde(default)]
+    pub class_remap: ClassRemapConfig,
+    #[serde(default, deserialize_with = "deserialize_from_string")]
+    pub backend: Backend,
+    /// Palette for labels without explicit colors (model metadata, data.yaml, JSON)
+    #[serde(default, deserialize_with = "deserialize_from_string")]
+    pub label_palette: LabelPalette,
+}
+
+#[derive(Debug, Deserialize, Clone, Default, PartialEq)]
+pub enum Backend {
+    #[default]
+    Ort,
+    Tract,
+}
+
+impl Backend {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Backend::Ort => "ort",
+            Backend::Tract => "tract",
+        }
+    }
+}
+
+impl TryFrom<String> for Backend {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        match s.to_lowercase().as_str() {
+            "ort" => Ok(Self::Ort),
+            "tract" => Ok(Self::Tract),
+            other => Err(format!(
+                "{} is not a supported inference backend. Use either `ort` or `tract`.",
+                other
+            )),
+        }
+    }
 }
 
 fn default_model_instances() -> usize {
@@ -50,42 +379,267 @@ fn default_min_probability() -> f32 {
     0.50
 }
 
-#[derive(Debug, Deserialize, Clone)]
-pub struct LabelsConfig {
-    pub labels_file: String,
-    pub labels_dir: PathBuf,
+fn default_nms_iou_threshold() -> f32 {
+    0.7
 }
 
-#[derive(Debug, Deserialize, Clone)]
-pub enum Environment {
-    Local,
-    Production,
+/// Per-class overrides applied on top of `min_probability`, keyed by label name.
+///
+/// An empty `allow` list means every class is allowed; `deny` always wins.
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct ClassFilterConfig {
+    #[serde(default)]
+    pub thresholds: HashMap<String, f32>,
+    #[serde(default)]
+    pub allow: Vec<String>,
+    #[serde(default)]
+    pub deny: Vec<String>,
+}
+
+/// Taxonomy exposed to clients. Classes not listed in `targets` or `drop` are
+/// passed through unchanged unless `keep_unmapped` is false.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct ClassRemapConfig {
+    #[serde(default)]
+    pub targets: Vec<RemapTargetConfig>,
+    #[serde(default)]
+    pub drop: Vec<String>,
+    #[serde(default = "default_keep_unmapped")]
+    pub keep_unmapped: bool,
+    /// IoU threshold for the extra NMS pass between boxes merged into the same target
+    #[serde(default = "default_remap_nms_iou_threshold")]
+    pub nms_iou_threshold: f32,
+}
+
+impl Default for ClassRemapConfig {
+    fn default() -> Self {
+        Self {
+            targets: Vec::new(),
+            drop: Vec::new(),
+            keep_unmapped: default_keep_unmapped(),
+            nms_iou_threshold: default_remap_nms_iou_threshold(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct RemapTargetConfig {
+    pub name: String,
+    pub sources: Vec<String>,
+    #[serde(default)]
+    pub color: Option<String>,
+}
+
+fn default_keep_unmapped() -> bool {
+    true
+}
+
+fn default_remap_nms_iou_threshold() -> f32 {
+    0.5
 }
 
-impl Environment {
-    fn as_str(&self) -> &'static str {
+#[derive(Debug, Deserialize, Clone, Default, PartialEq)]
+pub enum LabelPalette {
+    #[default]
+    Distinct,
+    ColorblindSafe,
+}
+
+impl LabelPalette {
+    pub fn as_str(&self) -> &'static str {
         match self {
-            Environment::Local => "local",
-            Environment::Production => "production",
+            LabelPalette::Distinct => "distinct",
+            LabelPalette::ColorblindSafe => "colorblind_safe",
         }
     }
 }
 
-impl TryFrom<String> for Environment {
+impl TryFrom<String> for LabelPalette {
     type Error = String;
 
     fn try_from(s: String) -> Result<Self, Self::Error> {
         match s.to_lowercase().as_str() {
-            "local" => Ok(Self::Local),
-            "production" => Ok(Self::Production),
+            "distinct" => Ok(Self::Distinct),
+            "colorblind_safe" => Ok(Self::ColorblindSafe),
             other => Err(format!(
-                "{} is not a supported environment. Use either `local` or `production`.",
+                "{} is not a supported label palette. Use either `distinct` or `colorblind_safe`.",
                 other
             )),
         }
     }
 }
 
+/// Zones are polygons of `[x, y]` points in normalized image coordinates. A
+/// detection is kept when its anchor is inside any include zone (or no include
+/// zone is configured) and outside every exclude zone.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct RegionsConfig {
+    #[serde(default)]
+    pub include: Vec<Vec<[f32; 2]>>,
+    #[serde(default)]
+    pub exclude: Vec<Vec<[f32; 2]>>,
+    #[serde(default, deserialize_with = "deserialize_from_string")]
+    pub anchor: AnchorPoint,
+    /// Include and exclude zones a request can add, together
+    #[serde(default = "default_max_request_zones")]
+    pub max_request_zones: usize,
+    /// Points of each zone a request adds
+    #[serde(default = "default_max_zone_points")]
+    pub max_zone_points: usize,
+}
+
+impl Default for RegionsConfig {
+    fn default() -> Self {
+        Self {
+            include: Vec::new(),
+            exclude: Vec::new(),
+            anchor: AnchorPoint::default(),
+            max_request_zones: default_max_request_zones(),
+            max_zone_points: default_max_zone_points(),
+        }
+    }
+}
+
+fn default_max_request_zones() -> usize {
+    16
+}
+
+fn default_max_zone_points() -> usize {
+    64
+}
+
+#[derive(Debug, Deserialize, Clone, Default, PartialEq)]
+pub enum AnchorPoint {
+    #[default]
+    BottomCenter,
+    Centroid,
+}
+
+impl AnchorPoint {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AnchorPoint::BottomCenter => "bottom_center",
+            AnchorPoint::Centroid => "centroid",
+        }
+    }
+}
+
+impl TryFrom<String> for AnchorPoint {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        match s.to_lowercase().as_str() {
+            "bottom_center" => Ok(Self::BottomCenter),
+            "centroid" => Ok(Self::Centroid),
+            other => Err(format!(
+                "{} is not a supported anchor point. Use either `bottom_center` or `centroid`.",
+                other
+            )),
+        }
+    }
+}
+
+/// JPEG, PNG, WebP, BMP and TIFF images are decoded. AVIF needs the `avif` feature,
+/// which links against the system dav1d library, without it AVIF images are
+/// rejected as `UNSUPPORTED_IMAGE_FORMAT`.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct ImageConfig {
+    #[serde(default = "default_max_image_bytes")]
+    pub max_bytes: usize,
+    #[serde(default = "default_max_image_pixels")]
+    pub max_pixels: u64,
+    #[serde(default)]
+    pub crops: CropConfig,
+}
+
+impl Default for ImageConfig {
+    fn default() -> Self {
+        Self {
+            max_bytes: default_max_image_bytes(),
+            max_pixels: default_max_image_pixels(),
+            crops: CropConfig::default(),
+        }
+    }
+}
+
+/// Detection crops returned to clients that ask for them
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct CropConfig {
+    /// Margin added on each side, as a fraction of the box size, when the request sets none
+    #[serde(default = "default_crop_padding")]
+    pub padding: f32,
+    #[serde(default = "default_crop_jpeg_quality")]
+    pub jpeg_quality: u8,
+}
+
+impl Default for CropConfig {
+    fn default() -> Self {
+        Self {
+            padding: default_crop_padding(),
+            jpeg_quality: default_crop_jpeg_quality(),
+        }
+    }
+}
+
+fn default_crop_padding() -> f32 {
+    0.1
+}
+
+fn default_crop_jpeg_quality() -> u8 {
+    90
+}
+
+impl ImageConfig {
+    /// Upper bound for a single decoder allocation: every pixel at 16 bytes (RGBA f32)
+    pub fn max_alloc_bytes(&self) -> u64 {
+        self.max_pixels.saturating_mul(16)
+    }
+
+    /// gRPC message limit: the image itself plus the other `ImageFrame` fields at
+    /// their largest, so an image just under `max_bytes` is still rejected as too large
+    /// rather than failing to decode.
+    pub fn max_message_bytes(&self, regions_cfg: &RegionsConfig) -> usize {
+        let zone = Polygon {
+            points: vec![Point { x: 1., y: 1. }; regions_cfg.max_zone_points],
+        };
+        let envelope = ImageFrame {
+            image_data: Vec::new(),
+            timestamp: i64::MIN,
+            region_filter: Some(ProtoRegionFilter {
+                include: vec![zone; regions_cfg.max_request_zones],
+                exclude: Vec::new(),
+                anchor: yolo_proto::AnchorPoint::Centroid.into(),
+            }),
+            extras: Some(DetectionExtras {
+                crops: true,
+                crop_padding: Some(1.),
+                top_k: u32::MAX,
+                all_scores: true,
+            }),
+        };
+
+        // The image field adds its tag and length prefix
+        envelope
+            .encoded_len()
+            .saturating_add(1 + prost::length_delimiter_len(self.max_bytes))
+            .saturating_add(self.max_bytes)
+    }
+}
+
+fn default_max_image_bytes() -> usize {
+    16 * 1024 * 1024
+}
+
+fn default_max_image_pixels() -> u64 {
+    40_000_000
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct LabelsConfig {
+    pub labels_file: String,
+    pub labels_dir: PathBuf,
+}
+
 #[derive(Debug, Deserialize, Clone)]
 pub enum LogLevel {
     Debug,
@@ -127,6 +681,18 @@ pub trait Validatable {
     }
 }
 
+impl ModelConfig {
+    /// Model name used to label metrics, the ONNX file name without extension
+    pub fn get_name(&self) -> String {
+        std::path::Path::new(&self.onnx_file)
+            .file_stem()
+            .map_or_else(
+                || self.onnx_file.clone(),
+                |stem| stem.to_string_lossy().into_owned(),
+            )
+    }
+}
+
 impl Validatable for ModelConfig {
     fn get_path(&self) -> PathBuf {
         self.model_dir.join(&self.onnx_file)
@@ -139,40 +705,249 @@ impl Validatable for LabelsConfig {
     }
 }
 
-pub fn get_configuration() -> Result<Config, config::ConfigError> {
-    let base_path = std::env::current_dir().expect("Failed to determine the current directory");
-    let configuration_directory = base_path.join("configuration");
+/// Where the configuration is read from: `<config_dir>/base.yaml`, then
+/// `<config_dir>/<profile>.yaml`, then `YP_` environment variables and finally
+/// the `overrides`, each source taking precedence over the previous ones.
+#[derive(Debug, Clone)]
+pub struct ConfigOptions {
+    pub config_dir: PathBuf,
+    pub profile: String,
+    /// `key=value` pairs, keys are dotted paths such as `server.port`
+    pub overrides: Vec<(String, String)>,
+}
+
+/// Parses a `key=value` override as given to `--set`.
+pub fn parse_override(s: &str) -> Result<(String, String), String> {
+    match s.split_once('=') {
+        Some((key, value)) if !key.trim().is_empty() => {
+            Ok((key.trim().to_string(), value.to_string()))
+        }
+        _ => Err(format!("Invalid override `{}`, expected `key=value`", s)),
+    }
+}
 
-    let environment: Environment = std::env::var("APP_ENVIRONMENT")
-        .unwrap_or_else(|_| "local".into())
-        .try_into()
-        .expect("Failed to parse APP_ENVIRONMENT");
+/// Merges every source without validating the result.
+pub fn read_configuration(options: &ConfigOptions) -> Result<Config, config::ConfigError> {
+    let profile_file = options.config_dir.join(format!("{}.yaml", options.profile));
+    if !profile_file.exists() {
+        return Err(config::ConfigError::Message(format!(
+            "Profile `{}` not found, {:?} does not exist",
+            options.profile, profile_file
+        )));
+    }
 
-    let config = config::Config::builder()
-        .add_source(config::File::from(
-            configuration_directory.join("base.yaml"),
-        ))
-        .add_source(config::File::from(
-            configuration_directory.join(format!("{}.yaml", environment.as_str())),
-        ))
+    let mut builder = config::Config::builder()
+        .add_source(config::File::from(options.config_dir.join("base.yaml")))
+        .add_source(config::File::from(profile_file))
         .add_source(
             config::Environment::with_prefix("YP")
                 .prefix_separator("_")
                 .separator("__"),
-        )
-        .build()?;
+        );
+    for (key, value) in &options.overrides {
+        builder = builder.set_override(key.as_str(), value.as_str())?;
+    }
+
+    builder.build()?.try_deserialize::<Config>()
+}
 
-    let config: Config = config.try_deserialize::<Config>()?;
+pub fn get_configuration(options: &ConfigOptions) -> Result<Config, config::ConfigError> {
+    let config = read_configuration(options)?;
 
     if let Err(e) = config.model.validate() {
         tracing::error!("Configuration validation failed: {}", e);
         return Err(config::ConfigError::Message(e));
     }
 
-    if let Err(e) = config.labels.validate() {
+    if let Some(Err(e)) = config.labels.as_ref().map(Validatable::validate) {
+        tracing::error!("Configuration validation failed: {}", e);
+        return Err(config::ConfigError::Message(e));
+    }
+
+    if let Err(e) = config.server.validate() {
         tracing::error!("Configuration validation failed: {}", e);
         return Err(config::ConfigError::Message(e));
     }
 
     Ok(config)
 }
+
+fn check_address(name: &str, address: &str, errors: &mut Vec<String>) -> Option<SocketAddr> {
+    match address.parse::<SocketAddr>() {
+        Ok(addr) if addr.port() == 0 => {
+            errors.push(format!("{}: port must not be 0", name));
+            None
+        }
+        Ok(addr) => Some(addr),
+        Err(e) => {
+            errors.push(format!("{}: invalid address {}: {}", name, address, e));
+            None
+        }
+    }
+}
+
+/// Loads everything the server reads at startup and returns every problem found,
+/// rather than stopping at the first one. Runs with `--check-config` and before
+/// the server starts.
+pub fn check_configuration(config: &Config) -> Vec<String> {
+    let mut errors = Vec::new();
+
+    let model_found = config
+        .model
+        .validate()
+        .map_err(|e| errors.push(format!("model: {}", e)));
+    if config.model.num_instances == 0 {
+        errors.push("model.num_instances: must be at least 1".to_string());
+    }
+    if !(0.0..=1.0).contains(&config.model.min_probability) {
+        errors.push("model.min_probability: must be between 0 and 1".to_string());
+    }
+    if !(0.0..=1.0).contains(&config.model.nms_iou_threshold) {
+        errors.push("model.nms_iou_threshold: must be between 0 and 1".to_string());
+    }
+    let backend_compiled = match config.model.backend {
+        Backend::Ort => cfg!(feature = "ort"),
+        Backend::Tract => cfg!(feature = "tract"),
+    };
+    if !backend_compiled {
+        errors.push(format!(
+            "model.backend: the {} backend is not compiled in, rebuild with `--features {}`",
+            config.model.backend.as_str(),
+            config.model.backend.as_str()
+        ));
+    }
+
+    let labels_found = match config.labels.as_ref().map(Validatable::validate) {
+        Some(Err(e)) => {
+            errors.push(format!("labels: {}", e));
+            false
+        }
+        _ => true,
+    };
+    // Reads the model metadata and labels, then builds the class filter and remapper
+    let service_state = if model_found.is_ok() && labels_found {
+        ServiceState::new(config.labels.as_ref(), &config.model)
+            .map_err(|e| errors.push(format!("labels: {}", e)))
+            .ok()
+    } else {
+        None
+    };
+
+    let grpc_addr = check_address("server", &config.server.get_address(), &mut errors);
+    let http_addr = config
+        .server
+        .get_http_address()
+        .and_then(|address| check_address("server.http", &address, &mut errors));
+    let prometheus_addr = config
+        .metrics
+        .prometheus_address
+        .as_ref()
+        .and_then(|address| check_address("metrics.prometheus_address", address, &mut errors));
+    let ports: Vec<u16> = [grpc_addr, http_addr, prometheus_addr]
+        .into_iter()
+        .flatten()
+        .map(|addr| addr.port())
+        .collect();
+    for (i, port) in ports.iter().enumerate() {
+        if ports[..i].contains(port) {
+            errors.push(format!("server: port {} is used by two listeners", port));
+        }
+    }
+
+    if let Some(http_cfg) = &config.server.http {
+        if let Err(e) = cors_layer(http_cfg) {
+            errors.push(format!("server.http.cors_allowed_origins: {}", e));
+        }
+    }
+    if let Some(tls_cfg) = &config.server.tls {
+        if let Err(e) = load_server_config(tls_cfg) {
+            errors.push(format!("server.tls: {}", e));
+        }
+    }
+    if let Err(e) = config.server.validate() {
+        errors.push(e);
+    }
+    if let Some(parent) = config
+        .server
+        .unix_socket
+        .as_ref()
+        .and_then(|path| path.parent())
+        .filter(|parent| !parent.as_os_str().is_empty() && !parent.exists())
+    {
+        errors.push(format!(
+            "server.unix_socket: directory {:?} does not exist",
+            parent
+        ));
+    }
+
+    if let Some(auth_cfg) = &config.auth {
+        if let Err(e) = load_api_keys(auth_cfg) {
+            errors.push(format!("auth: {}", e));
+        }
+    }
+    if config.rate_limit.requests_per_second < 0.
+        || config
+            .rate_limit
+            .clients
+            .values()
+            .any(|client| client.requests_per_second < 0.)
+    {
+        errors.push("rate_limit: requests_per_second must not be negative".to_string());
+    }
+    if let Err(e) = RegionFilter::new(&config.regions) {
+        errors.push(format!("regions: {}", e));
+    }
+    if config.image.max_bytes == 0 || config.image.max_pixels == 0 {
+        errors.push("image: max_bytes and max_pixels must be greater than 0".to_string());
+    }
+    if !(0.0..=1.0).contains(&config.image.crops.padding) {
+        errors.push("image.crops.padding: must be between 0 and 1".to_string());
+    }
+    if !(1..=100).contains(&config.image.crops.jpeg_quality) {
+        errors.push("image.crops.jpeg_quality: must be between 1 and 100".to_string());
+    }
+    if let Some(shadow_cfg) = &config.shadow {
+        let model_cfg = shadow_cfg.model_config(&config.model);
+        match model_cfg.validate() {
+            Err(e) => errors.push(format!("shadow: {}", e)),
+            Ok(()) => {
+                if let Some(service_state) = &service_state {
+                    let labels = service_state.get_settings().source_labels();
+                    if let Err(e) = shadow::check_model(&model_cfg, labels) {
+                        errors.push(format!("shadow: {}", e));
+                    }
+                }
+            }
+        }
+        if shadow_cfg.num_instances == 0 || shadow_cfg.max_concurrent == 0 {
+            errors.push("shadow: num_instances and max_concurrent must be at least 1".to_string());
+        }
+        if !(0.0..=1.0).contains(&shadow_cfg.sample_ratio) {
+            errors.push("shadow.sample_ratio: must be between 0 and 1".to_string());
+        }
+        if !(0.0..=1.0).contains(&shadow_cfg.iou_threshold) {
+            errors.push("shadow.iou_threshold: must be between 0 and 1".to_string());
+        }
+        if let Some(dump_dir) = shadow_cfg.dump_dir.as_ref().filter(|dir| !dir.is_dir()) {
+            errors.push(format!(
+                "shadow.dump_dir: directory {:?} does not exist",
+                dump_dir
+            ));
+        }
+    }
+    if let Some(recorder_cfg) = &config.recorder {
+        if recorder_cfg.max_file_bytes == 0
+            || recorder_cfg.max_total_bytes < recorder_cfg.max_file_bytes
+        {
+            errors.push(
+                "recorder: max_file_bytes must be greater than 0 and at most max_total_bytes"
+                    .to_string(),
+            );
+        }
+    }
+    if !(0.0..=1.0).contains(&config.tracing.sample_ratio) {
+        errors.push("tracing.sample_ratio: must be between 0 and 1".to_string());
+    }
+
+    errors
+}
@@ -0,0 +1,226 @@
+use crate::image_decoder::ImageDecodeError;
+use std::{collections::HashMap, time::Duration};
+use thiserror::Error;
+use tonic::{metadata::MetadataMap, Code, Status};
+use yolo_proto::{
+    google::rpc::{bad_request::FieldViolation, BadRequest, ErrorInfo},
+    ErrorDetails,
+};
+
+/// `ErrorInfo.domain` of every error sent by the service
+const ERROR_DOMAIN: &str = "yolo_prediction";
+
+/// Errors of the inference service. Each variant maps to a gRPC status code and an
+/// `ErrorInfo` reason, sent in the status details so clients do not have to parse
+/// messages. Errors caused by a request field also carry a `BadRequest`.
+#[derive(Debug, Error)]
+pub enum ServiceError {
+    #[error("{reason}: {0}", reason = .0.reason())]
+    InvalidImage(#[from] ImageDecodeError),
+    #[error("INVALID_REGION: {0}")]
+    InvalidRegion(String),
+    #[error("INVALID_JSON: {0}")]
+    InvalidJson(String),
+    #[error("INVALID_BASE64: {0}")]
+    InvalidBase64(String),
+    #[error("INVALID_SETTINGS: {0}")]
+    InvalidSettings(String),
+    #[error("INVALID_EXTRAS: {0}")]
+    InvalidExtras(String),
+    #[error("VERSION_MISMATCH: settings are at version {current}, the update expected {expected}")]
+    VersionMismatch { current: u64, expected: u64 },
+    #[error("the log level cannot be changed at runtime")]
+    LogLevelReadOnly,
+    #[error("no recorder is configured")]
+    RecorderNotConfigured,
+    #[error("missing API key")]
+    MissingApiKey,
+    #[error("invalid API key")]
+    InvalidApiKey,
+    #[error("the admin API is disabled when authentication is not configured")]
+    AdminDisabled,
+    #[error("API key {key} lacks the `{scope}` scope")]
+    MissingScope { key: String, scope: &'static str },
+    #[error("server is at capacity")]
+    AtCapacity { retry_after: Duration },
+    #[error("rate limit exceeded")]
+    RateLimited { retry_after: Duration },
+    #[error("deadline expired before {stage}")]
+    DeadlineExceeded { stage: &'static str },
+    #[error("{0}")]
+    ModelUnavailable(String),
+    #[error("{0}")]
+    Inference(String),
+    #[error("{0}")]
+    Internal(String),
+}
+
+impl ServiceError {
+    pub fn code(&self) -> Code {
+        match self {
+            ServiceError::InvalidImage(_)
+            | ServiceError::InvalidRegion(_)
+            | ServiceError::InvalidJson(_)
+            | ServiceError::InvalidBase64(_)
+            | ServiceError::InvalidSettings(_)
+            | ServiceError::InvalidExtras(_) => Code::InvalidArgument,
+            ServiceError::VersionMismatch { .. } => Code::Aborted,
+            ServiceError::LogLevelReadOnly | ServiceError::RecorderNotConfigured => {
+                Code::FailedPrecondition
+            }
+            ServiceError::MissingApiKey | ServiceError::InvalidApiKey => Code::Unauthenticated,
+            ServiceError::AdminDisabled | ServiceError::MissingScope { .. } => {
+                Code::PermissionDenied
+            }
+            ServiceError::AtCapacity { .. } | ServiceError::RateLimited { .. } => {
+                Code::ResourceExhausted
+            }
+            ServiceError::DeadlineExceeded { .. } => Code::DeadlineExceeded,
+            ServiceError::ModelUnavailable(_) => Code::Unavailable,
+            ServiceError::Inference(_) | ServiceError::Internal(_) => Code::Internal,
+        }
+    }
+
+    /// `ErrorInfo.reason`, stable across releases
+    pub fn reason(&self) -> &'static str {
+        match self {
+            ServiceError::InvalidImage(e) => e.reason(),
+            ServiceError::InvalidRegion(_) => "INVALID_REGION",
+            ServiceError::InvalidJson(_) => "INVALID_JSON",
+            ServiceError::InvalidBase64(_) => "INVALID_BASE64",
+            ServiceError::InvalidSettings(_) => "INVALID_SETTINGS",
+            ServiceError::InvalidExtras(_) => "INVALID_EXTRAS",
+            ServiceError::VersionMismatch { .. } => "VERSION_MISMATCH",
+            ServiceError::LogLevelReadOnly => "LOG_LEVEL_READ_ONLY",
+            ServiceError::RecorderNotConfigured => "RECORDER_NOT_CONFIGURED",
+            ServiceError::MissingApiKey => "MISSING_API_KEY",
+            ServiceError::InvalidApiKey => "INVALID_API_KEY",
+            ServiceError::AdminDisabled => "ADMIN_DISABLED",
+            ServiceError::MissingScope { .. } => "MISSING_SCOPE",
+            ServiceError::AtCapacity { .. } => "SERVER_AT_CAPACITY",
+            ServiceError::RateLimited { .. } => "RATE_LIMITED",
+            ServiceError::DeadlineExceeded { .. } => "DEADLINE_EXCEEDED",
+            ServiceError::ModelUnavailable(_) => "MODEL_UNAVAILABLE",
+            ServiceError::Inference(_) => "INFERENCE_FAILED",
+            ServiceError::Internal(_) => "INTERNAL",
+        }
+    }
+
+    /// Request field at fault, named as in `ImageFrame` or the HTTP JSON body
+    fn field(&self) -> Option<&'static str> {
+        match self {
+            ServiceError::InvalidImage(_) => Some("image_data"),
+            ServiceError::InvalidRegion(_) => Some("region_filter"),
+            ServiceError::InvalidJson(_) => Some("body"),
+            ServiceError::InvalidBase64(_) => Some("image"),
+            ServiceError::InvalidExtras(_) => Some("extras"),
+            _ => None,
+        }
+    }
+
+    fn metadata(&self) -> HashMap<String, String> {
+        let entries: Vec<(&str, String)> = match self {
+            ServiceError::InvalidImage(ImageDecodeError::TooManyBytes { size, max }) => {
+                vec![("size", size.to_string()), ("max_bytes", max.to_string())]
+            }
+            ServiceError::InvalidImage(ImageDecodeError::TooManyPixels { width, height, max }) => {
+                vec![
+                    ("width", width.to_string()),
+                    ("height", height.to_string()),
+                    ("max_pixels", max.to_string()),
+                ]
+            }
+            ServiceError::VersionMismatch { current, expected } => vec![
+                ("current_version", current.to_string()),
+                ("expected_version", expected.to_string()),
+            ],
+            ServiceError::MissingScope { scope, .. } => vec![("scope", scope.to_string())],
+            ServiceError::AtCapacity { retry_after }
+            | ServiceError::RateLimited { retry_after } => {
+                vec![("retry_after_ms", retry_after_ms(*retry_after))]
+            }
+            ServiceError::DeadlineExceeded { stage } => vec![("stage", stage.to_string())],
+            _ => Vec::new(),
+        };
+        entries
+            .into_iter()
+            .map(|(key, value)| (key.to_string(), value))
+            .collect()
+    }
+}
+
+fn retry_after_ms(retry_after: Duration) -> String {
+    retry_after.as_millis().max(1).to_string()
+}
+
+impl From<ServiceError> for Status {
+    fn from(err: ServiceError) -> Self {
+        let mut metadata = MetadataMap::new();
+        // Kept next to `ErrorInfo` for clients that only read the trailers
+        if let ServiceError::AtCapacity { retry_after }
+        | ServiceError::RateLimited { retry_after } = &err
+        {
+            metadata.insert(
+                "retry-after-ms",
+                retry_after_ms(*retry_after).parse().unwrap(),
+            );
+        }
+
+        let details = ErrorDetails {
+            error_info: Some(ErrorInfo {
+                reason: err.reason().to_string(),
+                domain: ERROR_DOMAIN.to_string(),
+                metadata: err.metadata(),
+            }),
+            bad_request: err.field().map(|field| BadRequest {
+                field_violations: vec![FieldViolation {
+                    field: field.to_string(),
+                    description: err.to_string(),
+                    reason: err.reason().to_string(),
+                }],
+            }),
+        };
+        details.into_status(err.code(), err.to_string(), metadata)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_status_details() {
+        let status = Status::from(ServiceError::InvalidImage(ImageDecodeError::TooManyBytes {
+            size: 20,
+            max: 10,
+        }));
+        assert_eq!(status.code(), Code::InvalidArgument);
+        assert_eq!(
+            status.message(),
+            "IMAGE_TOO_LARGE: image is 20 bytes, maximum is 10 bytes"
+        );
+
+        let details = ErrorDetails::from_status(&status);
+        assert_eq!(details.reason(), Some("IMAGE_TOO_LARGE"));
+        let error_info = details.error_info.unwrap();
+        assert_eq!(error_info.domain, "yolo_prediction");
+        assert_eq!(error_info.metadata["max_bytes"], "10");
+        let violations = details.bad_request.unwrap().field_violations;
+        assert_eq!(violations[0].field, "image_data");
+
+        let status = Status::from(ServiceError::RateLimited {
+            retry_after: Duration::from_millis(1500),
+        });
+        assert_eq!(status.code(), Code::ResourceExhausted);
+        assert_eq!(status.metadata().get("retry-after-ms").unwrap(), "1500");
+        let details = ErrorDetails::from_status(&status);
+        assert_eq!(details.reason(), Some("RATE_LIMITED"));
+        assert_eq!(details.bad_request, None);
+
+        // A plain status has no details
+        assert_eq!(
+            ErrorDetails::from_status(&Status::internal("oops")),
+            ErrorDetails::default()
+        );
+    }
+}
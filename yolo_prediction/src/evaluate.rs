@@ -0,0 +1,678 @@
+use crate::{
+    batch::{collect_inputs, predict_images, InputImage},
+    config::Config,
+    regions::RegionFilter,
+    state::{ServiceState, State},
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    fmt,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+use yolo_proto::{BoundingBox, ColorLabel};
+
+/// 0.5, 0.55, ..., 0.95 as in the COCO evaluation
+const IOU_THRESHOLDS: [f32; 10] = [0.5, 0.55, 0.6, 0.65, 0.7, 0.75, 0.8, 0.85, 0.9, 0.95];
+const RECALL_POINTS: usize = 101;
+
+/// Options of the `yolo-predict evaluate` command
+#[derive(Debug, Clone, clap::Args)]
+pub struct EvaluateOptions {
+    /// Directory of the dataset images
+    #[arg(long, value_name = "DIR")]
+    pub images: PathBuf,
+    /// COCO annotations, `file_name` entries are relative to `--images`
+    #[arg(long, value_name = "FILE", required_unless_present = "yolo_labels")]
+    pub coco: Option<PathBuf>,
+    /// Directory of YOLO txt labels laid out like `--images`. Class ids index the
+    /// labels served by the model
+    #[arg(long, value_name = "DIR", conflicts_with = "coco")]
+    pub yolo_labels: Option<PathBuf>,
+    /// Prints the report as JSON
+    #[arg(long)]
+    pub json: bool,
+    /// Images processed at once, defaults to `model.num_instances`
+    #[arg(long)]
+    pub concurrency: Option<usize>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct GroundTruthBox {
+    class_id: usize,
+    /// `[x1, y1, x2, y2]`, normalized when read from YOLO labels
+    bbox: [f32; 4],
+}
+
+struct Dataset {
+    inputs: Vec<InputImage>,
+    /// Indexed like `inputs`
+    boxes: Vec<Vec<GroundTruthBox>>,
+    normalized: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ClassReport {
+    pub class_id: usize,
+    pub label: String,
+    pub ground_truth: usize,
+    pub detections: usize,
+    /// At IoU 0.5 and the configured confidence thresholds
+    pub precision: f64,
+    pub recall: f64,
+    pub ap50: f64,
+    pub ap50_95: f64,
+}
+
+/// Rows are predicted classes and columns true classes, the last row and column
+/// stand for the background: missed objects and false detections.
+#[derive(Debug, Serialize)]
+pub struct ConfusionMatrix {
+    pub labels: Vec<String>,
+    pub counts: Vec<Vec<usize>>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct EvaluationReport {
+    pub images: usize,
+    pub failed: usize,
+    pub map50: f64,
+    pub map50_95: f64,
+    /// Classes with ground truth or detections
+    pub classes: Vec<ClassReport>,
+    pub confusion_matrix: ConfusionMatrix,
+}
+
+/// Runs the model over a labeled dataset through the same preprocessing, decoding,
+/// NMS and filters as the server, region filters aside, and scores the detections.
+///
+/// Only the detections above the configured thresholds are scored, lower
+/// `model.min_probability` to get the full precision-recall curve.
+pub async fn run_evaluation(
+    config: Config,
+    options: EvaluateOptions,
+) -> Result<EvaluationReport, String> {
+    let labels = ServiceState::new(config.labels.as_ref(), &config.model)
+        .map_err(|e| e.to_string())?
+        .get_settings()
+        .current()
+        .labels
+        .clone();
+
+    let dataset = match (&options.coco, &options.yolo_labels) {
+        (Some(coco), _) => load_coco(&options.images, coco, &labels)?,
+        (None, Some(yolo_labels)) => load_yolo(&options.images, yolo_labels, labels.len())?,
+        (None, None) => return Err("either --coco or --yolo-labels is required".into()),
+    };
+    let num_objects: usize = dataset.boxes.iter().map(Vec::len).sum();
+    tracing::info!(
+        "Evaluating {} images with {} objects",
+        dataset.inputs.len(),
+        num_objects
+    );
+
+    let inputs = Arc::new(dataset.inputs);
+    let predictions = predict_images(
+        &config,
+        inputs.clone(),
+        RegionFilter::default(),
+        options.concurrency,
+        None,
+    )
+    .await?;
+
+    let mut ground_truth = Vec::new();
+    let mut detections = Vec::new();
+    for (boxes, result) in dataset.boxes.into_iter().zip(predictions.results) {
+        let Ok(batch) = result else {
+            continue;
+        };
+        let scale = if dataset.normalized {
+            [batch.image_width as f32, batch.image_height as f32]
+        } else {
+            [1., 1.]
+        };
+        ground_truth.push(
+            boxes
+                .into_iter()
+                .map(|gt| GroundTruthBox {
+                    class_id: gt.class_id,
+                    bbox: [
+                        gt.bbox[0] * scale[0],
+                        gt.bbox[1] * scale[1],
+                        gt.bbox[2] * scale[0],
+                        gt.bbox[3] * scale[1],
+                    ],
+                })
+                .collect(),
+        );
+        detections.push(batch.detections);
+    }
+
+    let mut report = evaluate(&ground_truth, &detections, &predictions.labels);
+    report.failed = inputs.len() - report.images;
+    Ok(report)
+}
+
+#[derive(Deserialize)]
+struct CocoDataset {
+    images: Vec<CocoImage>,
+    annotations: Vec<CocoAnnotation>,
+    categories: Vec<CocoCategory>,
+}
+
+#[derive(Deserialize)]
+struct CocoImage {
+    id: u64,
+    file_name: String,
+}
+
+#[derive(Deserialize)]
+struct CocoAnnotation {
+    image_id: u64,
+    category_id: u64,
+    /// `[x, y, width, height]` in pixels
+    bbox: [f32; 4],
+    #[serde(default)]
+    iscrowd: u8,
+}
+
+#[derive(Deserialize)]
+struct CocoCategory {
+    id: u64,
+    name: String,
+}
+
+/// Categories are matched to the served labels by name. Crowd annotations are
+/// skipped, so detections on crowds count as false positives.
+fn load_coco(images_dir: &Path, path: &Path, labels: &[ColorLabel]) -> Result<Dataset, String> {
+    let file = std::fs::File::open(path)
+        .map_err(|e| format!("failed to read {}: {}", path.display(), e))?;
+    let coco: CocoDataset = serde_json::from_reader(std::io::BufReader::new(file))
+        .map_err(|e| format!("invalid COCO annotations {}: {}", path.display(), e))?;
+
+    let mut class_ids = HashMap::new();
+    for category in &coco.categories {
+        match labels.iter().position(|label| label.label == category.name) {
+            Some(class_id) => {
+                class_ids.insert(category.id, class_id);
+            }
+            None => tracing::warn!(
+                "COCO category {} is not a model class, its annotations are skipped",
+                category.name
+            ),
+        }
+    }
+
+    let image_index: HashMap<u64, usize> = coco
+        .images
+        .iter()
+        .enumerate()
+        .map(|(index, image)| (image.id, index))
+        .collect();
+    let mut boxes = vec![Vec::new(); coco.images.len()];
+    for annotation in &coco.annotations {
+        let (Some(&index), Some(&class_id)) = (
+            image_index.get(&annotation.image_id),
+            class_ids.get(&annotation.category_id),
+        ) else {
+            continue;
+        };
+        if annotation.iscrowd != 0 {
+            continue;
+        }
+        let [x, y, width, height] = annotation.bbox;
+        boxes[index].push(GroundTruthBox {
+            class_id,
+            bbox: [x, y, x + width, y + height],
+        });
+    }
+
+    let inputs = coco
+        .images
+        .into_iter()
+        .map(|image| InputImage {
+            path: images_dir.join(&image.file_name),
+            relative: PathBuf::from(image.file_name),
+        })
+        .collect();
+
+    Ok(Dataset {
+        inputs,
+        boxes,
+        normalized: false,
+    })
+}
+
+/// An image without a label file has no objects.
+fn load_yolo(images_dir: &Path, labels_dir: &Path, num_classes: usize) -> Result<Dataset, String> {
+    let inputs = collect_inputs(&[images_dir.to_string_lossy().into_owned()], true)?;
+    let boxes = inputs
+        .iter()
+        .map(|input| {
+            let path = labels_dir.join(&input.relative).with_extension("txt");
+            match std::fs::read_to_string(&path) {
+                Ok(content) => parse_yolo_labels(&content, num_classes)
+                    .map_err(|e| format!("invalid labels {}: {}", path.display(), e)),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Vec::new()),
+                Err(e) => Err(format!("failed to read {}: {}", path.display(), e)),
+            }
+        })
+        .collect::<Result<_, _>>()?;
+
+    Ok(Dataset {
+        inputs,
+        boxes,
+        normalized: true,
+    })
+}
+
+/// Reads `<class> <x_center> <y_center> <width> <height>` lines, extra columns
+/// such as a confidence are ignored.
+fn parse_yolo_labels(content: &str, num_classes: usize) -> Result<Vec<GroundTruthBox>, String> {
+    content
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(line_number, line)| {
+            let invalid = || {
+                format!(
+                    "line {}: expected a class and 4 coordinates",
+                    line_number + 1
+                )
+            };
+            let mut fields = line.split_whitespace();
+            let class_id: usize = fields
+                .next()
+                .and_then(|field| field.parse().ok())
+                .ok_or_else(invalid)?;
+            if class_id >= num_classes {
+                return Err(format!(
+                    "line {}: class {} is out of range, the model has {} classes",
+                    line_number + 1,
+                    class_id,
+                    num_classes
+                ));
+            }
+            let mut coordinates = [0.; 4];
+            for coordinate in &mut coordinates {
+                *coordinate = fields
+                    .next()
+                    .and_then(|field| field.parse().ok())
+                    .ok_or_else(invalid)?;
+            }
+            let [x_center, y_center, width, height] = coordinates;
+            Ok(GroundTruthBox {
+                class_id,
+                bbox: [
+                    x_center - width / 2.,
+                    y_center - height / 2.,
+                    x_center + width / 2.,
+                    y_center + height / 2.,
+                ],
+            })
+        })
+        .collect()
+}
+
+fn iou(a: &[f32; 4], detection: &BoundingBox) -> f32 {
+    let width = a[2].min(detection.x2) - a[0].max(detection.x1);
+    let height = a[3].min(detection.y2) - a[1].max(detection.y1);
+    if width <= 0. || height <= 0. {
+        return 0.;
+    }
+    let intersection = width * height;
+    let union = (a[2] - a[0]) * (a[3] - a[1])
+        + (detection.x2 - detection.x1) * (detection.y2 - detection.y1)
+        - intersection;
+    intersection / union
+}
+
+#[derive(Default)]
+struct ClassMatches {
+    ground_truth: usize,
+    /// Confidence of each detection and whether it matched at each IoU threshold
+    detections: Vec<(f32, [bool; IOU_THRESHOLDS.len()])>,
+}
+
+fn evaluate(
+    ground_truth: &[Vec<GroundTruthBox>],
+    detections: &[Vec<BoundingBox>],
+    labels: &[ColorLabel],
+) -> EvaluationReport {
+    let num_classes = labels.len();
+    let mut classes: Vec<ClassMatches> = (0..num_classes).map(|_| Default::default()).collect();
+    let mut confusion = vec![vec![0; num_classes + 1]; num_classes + 1];
+
+    for (image_truth, image_detections) in ground_truth.iter().zip(detections) {
+        let image_detections: Vec<_> = image_detections
+            .iter()
+            .filter(|detection| (0..num_classes as i32).contains(&detection.class_id))
+            .collect();
+
+        for gt in image_truth {
+            classes[gt.class_id].ground_truth += 1;
+        }
+
+        // Greedy matching by confidence, as in the COCO evaluation
+        for (class_id, class_matches) in classes.iter_mut().enumerate() {
+            let truth: Vec<_> = image_truth
+                .iter()
+                .filter(|gt| gt.class_id == class_id)
+                .collect();
+            let mut candidates: Vec<_> = image_detections
+                .iter()
+                .filter(|detection| detection.class_id as usize == class_id)
+                .collect();
+            candidates.sort_by(|a, b| b.confidence.total_cmp(&a.confidence));
+
+            let mut matched = vec![[false; IOU_THRESHOLDS.len()]; truth.len()];
+            for detection in candidates {
+                let mut is_match = [false; IOU_THRESHOLDS.len()];
+                for (t, threshold) in IOU_THRESHOLDS.iter().enumerate() {
+                    let best = truth
+                        .iter()
+                        .enumerate()
+                        .filter(|(g, _)| !matched[*g][t])
+                        .map(|(g, gt)| (g, iou(&gt.bbox, detection)))
+                        .filter(|(_, overlap)| overlap >= threshold)
+                        .max_by(|a, b| a.1.total_cmp(&b.1));
+                    if let Some((g, _)) = best {
+                        matched[g][t] = true;
+                        is_match[t] = true;
+                    }
+                }
+                class_matches
+                    .detections
+                    .push((detection.confidence, is_match));
+            }
+        }
+
+        // Class agnostic one-to-one matching at IoU 0.5, best overlaps first
+        let mut pairs = Vec::new();
+        for (g, gt) in image_truth.iter().enumerate() {
+            for (d, detection) in image_detections.iter().enumerate() {
+                let overlap = iou(&gt.bbox, detection);
+                if overlap >= IOU_THRESHOLDS[0] {
+                    pairs.push((overlap, g, d));
+                }
+            }
+        }
+        pairs.sort_by(|a, b| b.0.total_cmp(&a.0));
+        let mut truth_matched = vec![false; image_truth.len()];
+        let mut detection_matched = vec![false; image_detections.len()];
+        for (_, g, d) in pairs {
+            if !truth_matched[g] && !detection_matched[d] {
+                truth_matched[g] = true;
+                detection_matched[d] = true;
+                confusion[image_detections[d].class_id as usize][image_truth[g].class_id] += 1;
+            }
+        }
+        for (g, gt) in image_truth.iter().enumerate() {
+            if !truth_matched[g] {
+                confusion[num_classes][gt.class_id] += 1;
+            }
+        }
+        for (d, detection) in image_detections.iter().enumerate() {
+            if !detection_matched[d] {
+                confusion[detection.class_id as usize][num_classes] += 1;
+            }
+        }
+    }
+
+    let mut class_reports = Vec::new();
+    for (class_id, class_matches) in classes.iter_mut().enumerate() {
+        if class_matches.ground_truth == 0 && class_matches.detections.is_empty() {
+            continue;
+        }
+        class_matches.detections.sort_by(|a, b| b.0.total_cmp(&a.0));
+        let aps: Vec<f64> = (0..IOU_THRESHOLDS.len())
+            .map(|t| average_precision(class_matches, t))
+            .collect();
+        let true_positives = class_matches
+            .detections
+            .iter()
+            .filter(|(_, is_match)| is_match[0])
+            .count();
+
+        class_reports.push(ClassReport {
+            class_id,
+            label: labels[class_id].label.clone(),
+            ground_truth: class_matches.ground_truth,
+            detections: class_matches.detections.len(),
+            precision: ratio(true_positives, class_matches.detections.len()),
+            recall: ratio(true_positives, class_matches.ground_truth),
+            ap50: aps[0],
+            ap50_95: aps.iter().sum::<f64>() / aps.len() as f64,
+        });
+    }
+
+    // Classes without ground truth have no defined AP and are left out of the mean
+    let scored: Vec<_> = class_reports
+        .iter()
+        .filter(|class| class.ground_truth > 0)
+        .collect();
+    let mean = |ap: fn(&ClassReport) -> f64| {
+        if scored.is_empty() {
+            0.
+        } else {
+            scored.iter().map(|class| ap(class)).sum::<f64>() / scored.len() as f64
+        }
+    };
+
+    let shown: Vec<_> = class_reports.iter().map(|class| class.class_id).collect();
+    let confusion_matrix = ConfusionMatrix {
+        labels: shown
+            .iter()
+            .map(|&class_id| labels[class_id].label.clone())
+            .chain(std::iter::once("background".to_string()))
+            .collect(),
+        counts: shown
+            .iter()
+            .copied()
+            .chain(std::iter::once(num_classes))
+            .map(|row| {
+                shown
+                    .iter()
+                    .copied()
+                    .chain(std::iter::once(num_classes))
+                    .map(|column| confusion[row][column])
+                    .collect()
+            })
+            .collect(),
+    };
+
+    EvaluationReport {
+        images: ground_truth.len(),
+        failed: 0,
+        map50: mean(|class| class.ap50),
+        map50_95: mean(|class| class.ap50_95),
+        classes: class_reports,
+        confusion_matrix,
+    }
+}
+
+/// Area under the interpolated precision-recall curve sampled at 101 recall
+/// points. Detections must be sorted by decreasing confidence.
+fn average_precision(class_matches: &ClassMatches, threshold: usize) -> f64 {
+    if class_matches.ground_truth == 0 {
+        return 0.;
+    }
+
+    let mut true_positives = 0;
+    let mut recall = Vec::with_capacity(class_matches.detections.len());
+    let mut precision = Vec::with_capacity(class_matches.detections.len());
+    for (rank, (_, is_match)) in class_matches.detections.iter().enumerate() {
+        if is_match[threshold] {
+            true_positives += 1;
+        }
+        recall.push(ratio(true_positives, class_matches.ground_truth));
+        precision.push(ratio(true_positives, rank + 1));
+    }
+    // Precision envelope, the best precision at this recall or higher
+    for i in (1..precision.len()).rev() {
+        precision[i - 1] = precision[i - 1].max(precision[i]);
+    }
+
+    (0..RECALL_POINTS)
+        .map(|point| {
+            let target = point as f64 / (RECALL_POINTS - 1) as f64;
+            let index = recall.partition_point(|&r| r < target);
+            precision.get(index).copied().unwrap_or(0.)
+        })
+        .sum::<f64>()
+        / RECALL_POINTS as f64
+}
+
+fn ratio(numerator: usize, denominator: usize) -> f64 {
+    if denominator == 0 {
+        0.
+    } else {
+        numerator as f64 / denominator as f64
+    }
+}
+
+impl fmt::Display for EvaluationReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Images: {} ({} failed)", self.images, self.failed)?;
+        writeln!(f, "mAP@0.5: {:.4}", self.map50)?;
+        writeln!(f, "mAP@0.5:0.95: {:.4}", self.map50_95)?;
+        writeln!(f)?;
+
+        let width = self
+            .confusion_matrix
+            .labels
+            .iter()
+            .map(String::len)
+            .max()
+            .unwrap_or(0)
+            .max(5);
+        writeln!(
+            f,
+            "{:<width$} {:>8} {:>8} {:>9} {:>7} {:>7} {:>8}",
+            "class", "objects", "detected", "precision", "recall", "AP50", "AP50-95"
+        )?;
+        for class in &self.classes {
+            writeln!(
+                f,
+                "{:<width$} {:>8} {:>8} {:>9.3} {:>7.3} {:>7.3} {:>8.3}",
+                class.label,
+                class.ground_truth,
+                class.detections,
+                class.precision,
+                class.recall,
+                class.ap50,
+                class.ap50_95
+            )?;
+        }
+
+        writeln!(f)?;
+        writeln!(
+            f,
+            "Confusion matrix at IoU 0.5, rows are predicted classes and columns true classes"
+        )?;
+        write!(f, "{:<width$}", "")?;
+        for label in &self.confusion_matrix.labels {
+            write!(f, " {:>width$}", label)?;
+        }
+        writeln!(f)?;
+        for (label, row) in self
+            .confusion_matrix
+            .labels
+            .iter()
+            .zip(&self.confusion_matrix.counts)
+        {
+            write!(f, "{:<width$}", label)?;
+            for count in row {
+                write!(f, " {:>width$}", count)?;
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn detection(x1: f32, class_id: i32, confidence: f32) -> BoundingBox {
+        BoundingBox {
+            x1,
+            y1: 0.,
+            x2: x1 + 10.,
+            y2: 10.,
+            class_id,
+            confidence,
+            ..Default::default()
+        }
+    }
+
+    fn truth(x1: f32, class_id: usize) -> GroundTruthBox {
+        GroundTruthBox {
+            class_id,
+            bbox: [x1, 0., x1 + 10., 10.],
+        }
+    }
+
+    #[test]
+    fn test_evaluate() {
+        let labels: Vec<_> = ["person", "car", "dog"]
+            .iter()
+            .map(|name| ColorLabel {
+                label: name.to_string(),
+                ..Default::default()
+            })
+            .collect();
+        let ground_truth = vec![
+            vec![truth(0., 0), truth(50., 0), truth(100., 1)],
+            vec![truth(0., 1)],
+        ];
+        let detections = vec![
+            vec![
+                detection(0., 0, 0.9),
+                // Shifted by 1px, matched up to IoU 0.8
+                detection(51., 0, 0.6),
+                // Right place, wrong class
+                detection(100., 0, 0.8),
+            ],
+            vec![detection(0., 1, 0.7), detection(200., 2, 0.5)],
+        ];
+
+        let report = evaluate(&ground_truth, &detections, &labels);
+        assert_eq!(report.images, 2);
+
+        let person = &report.classes[0];
+        assert_eq!((person.ground_truth, person.detections), (2, 3));
+        assert!((person.precision - 2. / 3.).abs() < 1e-9);
+        assert_eq!(person.recall, 1.);
+        // The false positive ranks between the two matches
+        assert!((person.ap50 - (51. * 1. + 50. * 2. / 3.) / 101.).abs() < 1e-9);
+        assert!(person.ap50_95 < person.ap50);
+
+        let car = &report.classes[1];
+        assert_eq!((car.precision, car.recall), (1., 0.5));
+        // Dog has no ground truth and is left out of the mean
+        assert_eq!(report.classes[2].ground_truth, 0);
+        assert!((report.map50 - (person.ap50 + car.ap50) / 2.).abs() < 1e-9);
+
+        let counts = &report.confusion_matrix.counts;
+        assert_eq!(counts[0], vec![2, 1, 0, 0]);
+        assert_eq!(counts[1], vec![0, 1, 0, 0]);
+        assert_eq!(counts[2], vec![0, 0, 0, 1]);
+        assert_eq!(counts[3], vec![0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn test_parse_yolo_labels() {
+        let boxes = parse_yolo_labels("1 0.5 0.5 0.2 0.4\n\n0 0.1 0.1 0.2 0.2 0.93\n", 2).unwrap();
+        assert_eq!(boxes.len(), 2);
+        assert_eq!(boxes[0].class_id, 1);
+        assert!((boxes[0].bbox[1] - 0.3).abs() < 1e-6);
+        assert!((boxes[0].bbox[2] - 0.6).abs() < 1e-6);
+
+        assert!(parse_yolo_labels("2 0.5 0.5 0.2 0.2", 2).is_err());
+        assert!(parse_yolo_labels("0 0.5 0.5", 2).is_err());
+    }
+}
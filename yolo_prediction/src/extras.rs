@@ -0,0 +1,182 @@
+use crate::{config::CropConfig, error::ServiceError};
+use image::{codecs::jpeg::JpegEncoder, DynamicImage, GenericImageView};
+use yolo_proto::{BoundingBox, ClassScore, DetectionExtras};
+
+/// Crops asked for by a request, with the server defaults filled in.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CropOptions {
+    padding: f32,
+    jpeg_quality: u8,
+}
+
+impl CropOptions {
+    /// `None` when the request did not ask for crops
+    pub fn from_request(
+        extras: Option<&DetectionExtras>,
+        crop_cfg: &CropConfig,
+    ) -> Result<Option<Self>, ServiceError> {
+        let Some(extras) = extras.filter(|extras| extras.crops) else {
+            return Ok(None);
+        };
+
+        let padding = extras.crop_padding.unwrap_or(crop_cfg.padding);
+        if !(0.0..=1.0).contains(&padding) {
+            return Err(ServiceError::InvalidExtras(format!(
+                "crop_padding must be between 0 and 1, got {}",
+                padding
+            )));
+        }
+
+        Ok(Some(Self {
+            padding,
+            jpeg_quality: crop_cfg.jpeg_quality,
+        }))
+    }
+
+    /// Sets `crop_jpeg` on every detection. Crops are clipped to the image, a box
+    /// entirely outside of it gets an empty crop.
+    pub fn apply(
+        &self,
+        image: &DynamicImage,
+        detections: &mut [BoundingBox],
+    ) -> Result<(), ServiceError> {
+        let (img_width, img_height) = image.dimensions();
+
+        for detection in detections {
+            let pad_x = (detection.x2 - detection.x1) * self.padding;
+            let pad_y = (detection.y2 - detection.y1) * self.padding;
+            let x1 = (detection.x1 - pad_x).floor().clamp(0., img_width as f32) as u32;
+            let y1 = (detection.y1 - pad_y).floor().clamp(0., img_height as f32) as u32;
+            let x2 = (detection.x2 + pad_x).ceil().clamp(0., img_width as f32) as u32;
+            let y2 = (detection.y2 + pad_y).ceil().clamp(0., img_height as f32) as u32;
+            if x2 <= x1 || y2 <= y1 {
+                continue;
+            }
+
+            let crop = image.crop_imm(x1, y1, x2 - x1, y2 - y1).to_rgb8();
+            let mut crop_jpeg = Vec::new();
+            JpegEncoder::new_with_quality(&mut crop_jpeg, self.jpeg_quality)
+                .encode_image(&crop)
+                .map_err(|e| ServiceError::Internal(format!("failed to encode crop: {}", e)))?;
+            detection.crop_jpeg = crop_jpeg;
+        }
+
+        Ok(())
+    }
+}
+
+/// Whether the model service has to keep the score vector of each detection
+pub fn wants_scores(extras: Option<&DetectionExtras>) -> bool {
+    extras.is_some_and(|extras| extras.top_k > 0 || extras.all_scores)
+}
+
+/// Turns the score vectors kept for the request into the top classes and/or the
+/// full vector it asked for.
+pub fn select_scores(detections: &mut [BoundingBox], extras: Option<&DetectionExtras>) {
+    let (top_k, all_scores) = extras.map_or((0, false), |extras| {
+        (extras.top_k as usize, extras.all_scores)
+    });
+
+    for detection in detections {
+        if top_k > 0 {
+            let mut top_classes: Vec<ClassScore> = detection
+                .class_scores
+                .iter()
+                .enumerate()
+                .map(|(class_id, score)| ClassScore {
+                    class_id: class_id as i32,
+                    score: *score,
+                })
+                .collect();
+            top_classes.sort_by(|class1, class2| class2.score.total_cmp(&class1.score));
+            top_classes.truncate(top_k);
+            detection.top_classes = top_classes;
+        }
+        if !all_scores {
+            detection.class_scores = Vec::new();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{ImageBuffer, Rgb};
+
+    fn bbox(x1: f32, y1: f32, x2: f32, y2: f32) -> BoundingBox {
+        BoundingBox {
+            x1,
+            y1,
+            x2,
+            y2,
+            class_scores: vec![0.1, 0.7, 0.2],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_crops() {
+        let extras = DetectionExtras {
+            crops: true,
+            ..Default::default()
+        };
+        let crop_cfg = CropConfig {
+            padding: 0.5,
+            jpeg_quality: 80,
+        };
+        assert_eq!(CropOptions::from_request(None, &crop_cfg).unwrap(), None);
+        let invalid = DetectionExtras {
+            crop_padding: Some(-1.),
+            ..extras
+        };
+        assert!(CropOptions::from_request(Some(&invalid), &crop_cfg).is_err());
+
+        let image = DynamicImage::ImageRgb8(ImageBuffer::from_pixel(100, 50, Rgb([255, 0, 0])));
+        let mut detections = vec![
+            bbox(10., 10., 30., 20.),
+            // Clipped to the image
+            bbox(90., 40., 120., 60.),
+            bbox(200., 200., 220., 220.),
+        ];
+        CropOptions::from_request(Some(&extras), &crop_cfg)
+            .unwrap()
+            .unwrap()
+            .apply(&image, &mut detections)
+            .unwrap();
+
+        let dimensions =
+            |crop_jpeg: &[u8]| image::load_from_memory(crop_jpeg).unwrap().dimensions();
+        assert_eq!(dimensions(&detections[0].crop_jpeg), (40, 20));
+        assert_eq!(dimensions(&detections[1].crop_jpeg), (25, 20));
+        assert!(detections[2].crop_jpeg.is_empty());
+    }
+
+    #[test]
+    fn test_select_scores() {
+        let mut detections = vec![bbox(0., 0., 1., 1.)];
+        let extras = DetectionExtras {
+            top_k: 2,
+            ..Default::default()
+        };
+        assert!(wants_scores(Some(&extras)));
+        assert!(!wants_scores(None));
+
+        select_scores(&mut detections, Some(&extras));
+        let top_classes: Vec<_> = detections[0]
+            .top_classes
+            .iter()
+            .map(|class_score| class_score.class_id)
+            .collect();
+        assert_eq!(top_classes, [1, 2]);
+        assert!(detections[0].class_scores.is_empty());
+
+        let mut detections = vec![bbox(0., 0., 1., 1.)];
+        let extras = DetectionExtras {
+            all_scores: true,
+            ..Default::default()
+        };
+        select_scores(&mut detections, Some(&extras));
+        assert!(detections[0].top_classes.is_empty());
+        assert_eq!(detections[0].class_scores, [0.1, 0.7, 0.2]);
+    }
+}
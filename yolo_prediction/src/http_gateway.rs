@@ -0,0 +1,536 @@
+use crate::{
+    auth::{authorize, AuthInterceptor, Scope},
+    config::{Config, HttpConfig, ImageConfig},
+    error::ServiceError,
+    inference_service::InferenceService,
+    model_service::ModelService,
+    state::State,
+};
+use axum::{
+    body::Bytes,
+    extract::{ConnectInfo, DefaultBodyLimit, Request as HttpRequest},
+    http::{header, request::Parts, HeaderMap, HeaderName, HeaderValue, Method, StatusCode},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Json, Router,
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use serde::{Deserialize, Serialize};
+use std::{
+    future::Future,
+    net::SocketAddr,
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tokio::net::TcpListener;
+use tonic::{
+    metadata::MetadataMap,
+    service::{interceptor::InterceptedService, Interceptor, Routes},
+    transport::server::TcpConnectInfo,
+    Code, Request, Status,
+};
+use tonic_web::GrpcWebLayer;
+use tower_http::cors::{AllowOrigin, Any, CorsLayer};
+use yolo_proto::{
+    yolo_service_server::{YoloService, YoloServiceServer},
+    ColorLabel, DetectionExtras, Empty, ErrorDetails, ImageFrame, PredictionBatch,
+};
+
+/// The Yolo gRPC service with authentication, shared by the gRPC and gRPC-Web listeners.
+pub type GrpcService<M, S> =
+    InterceptedService<YoloServiceServer<InferenceService<M, S>>, AuthInterceptor>;
+
+#[derive(Debug, Deserialize)]
+struct PredictBody {
+    /// Base64 encoded image
+    image: String,
+    #[serde(default)]
+    timestamp: Option<i64>,
+    /// Returns a base64 JPEG crop of each detection
+    #[serde(default)]
+    crops: bool,
+    #[serde(default)]
+    crop_padding: Option<f32>,
+    /// Number of best classes returned per detection
+    #[serde(default)]
+    top_k: u32,
+    /// Returns the score of every class per detection
+    #[serde(default)]
+    all_scores: bool,
+}
+
+#[derive(Debug, Serialize)]
+struct ClassScoreResponse {
+    class_id: i32,
+    label: String,
+    score: f32,
+}
+
+#[derive(Debug, Serialize)]
+struct DetectionResponse {
+    x1: f32,
+    y1: f32,
+    x2: f32,
+    y2: f32,
+    class_id: i32,
+    label: String,
+    confidence: f32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    crop: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    top_classes: Vec<ClassScoreResponse>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    class_scores: Vec<f32>,
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct PredictionResponse {
+    timestamp: i64,
+    image_width: u32,
+    image_height: u32,
+    detections: Vec<DetectionResponse>,
+}
+
+impl PredictionResponse {
+    pub(crate) fn new(batch: PredictionBatch, labels: &[ColorLabel]) -> Self {
+        let label = |class_id: i32| {
+            usize::try_from(class_id)
+                .ok()
+                .and_then(|class_id| labels.get(class_id))
+                .map_or_else(String::new, |label| label.label.clone())
+        };
+        let detections = batch
+            .detections
+            .into_iter()
+            .map(|detection| DetectionResponse {
+                x1: detection.x1,
+                y1: detection.y1,
+                x2: detection.x2,
+                y2: detection.y2,
+                class_id: detection.class_id,
+                label: label(detection.class_id),
+                confidence: detection.confidence,
+                crop: (!detection.crop_jpeg.is_empty())
+                    .then(|| STANDARD.encode(&detection.crop_jpeg)),
+                top_classes: detection
+                    .top_classes
+                    .into_iter()
+                    .map(|class_score| ClassScoreResponse {
+                        class_id: class_score.class_id,
+                        label: label(class_score.class_id),
+                        score: class_score.score,
+                    })
+                    .collect(),
+                class_scores: detection.class_scores,
+            })
+            .collect();
+
+        Self {
+            timestamp: batch.timestamp,
+            image_width: batch.image_width,
+            image_height: batch.image_height,
+            detections,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct LabelResponse {
+    class_id: usize,
+    label: String,
+    display_name: String,
+    color: [u32; 3],
+    min_probability: f32,
+    enabled: bool,
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct ModelResponse {
+    name: String,
+    onnx_file: String,
+    backend: &'static str,
+    num_instances: usize,
+    num_classes: usize,
+    min_probability: f32,
+}
+
+#[derive(Debug, Serialize)]
+struct ErrorBody {
+    code: String,
+    /// `ErrorInfo.reason` from the status details, such as `IMAGE_TOO_LARGE`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reason: Option<String>,
+    message: String,
+}
+
+/// gRPC status rendered as a JSON error with the matching HTTP status code.
+#[derive(Debug)]
+struct ApiError(Status);
+
+impl From<Status> for ApiError {
+    fn from(status: Status) -> Self {
+        Self(status)
+    }
+}
+
+impl From<ServiceError> for ApiError {
+    fn from(err: ServiceError) -> Self {
+        Self(err.into())
+    }
+}
+
+fn http_status(code: Code) -> StatusCode {
+    match code {
+        Code::Ok => StatusCode::OK,
+        Code::InvalidArgument | Code::FailedPrecondition | Code::OutOfRange => {
+            StatusCode::BAD_REQUEST
+        }
+        Code::Unauthenticated => StatusCode::UNAUTHORIZED,
+        Code::PermissionDenied => StatusCode::FORBIDDEN,
+        Code::NotFound => StatusCode::NOT_FOUND,
+        Code::AlreadyExists | Code::Aborted => StatusCode::CONFLICT,
+        Code::ResourceExhausted => StatusCode::TOO_MANY_REQUESTS,
+        Code::Cancelled => StatusCode::REQUEST_TIMEOUT,
+        Code::DeadlineExceeded => StatusCode::GATEWAY_TIMEOUT,
+        Code::Unimplemented => StatusCode::NOT_IMPLEMENTED,
+        Code::Unavailable => StatusCode::SERVICE_UNAVAILABLE,
+        Code::Unknown | Code::Internal | Code::DataLoss => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let status = self.0;
+        let body = ErrorBody {
+            code: format!("{:?}", status.code()),
+            reason: ErrorDetails::from_status(&status)
+                .reason()
+                .map(str::to_string),
+            message: status.message().to_string(),
+        };
+        let mut response = (http_status(status.code()), Json(body)).into_response();
+
+        // Rate limit rejections carry a hint in milliseconds, HTTP wants seconds
+        if let Some(retry_after_ms) = status
+            .metadata()
+            .get("retry-after-ms")
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse::<u64>().ok())
+        {
+            response
+                .headers_mut()
+                .insert(header::RETRY_AFTER, retry_after_ms.div_ceil(1000).into());
+        }
+        response
+    }
+}
+
+fn now_millis() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as i64
+}
+
+/// Reads a JSON body with a base64 image, or the raw image bytes for any other content type.
+/// Largest REST body: the image in base64 plus the other `PredictBody` fields at
+/// their longest, pretty-printed
+fn max_body_bytes(image_cfg: &ImageConfig) -> usize {
+    let fields = serde_json::json!({
+        "image": "",
+        "timestamp": i64::MIN,
+        "crops": false,
+        "crop_padding": f32::MIN,
+        "top_k": u32::MAX,
+        "all_scores": false,
+    });
+    let fields = serde_json::to_string_pretty(&fields).map_or(0, |json| json.len());
+
+    image_cfg
+        .max_bytes
+        .div_ceil(3)
+        .saturating_mul(4)
+        .saturating_add(fields)
+}
+
+fn image_frame(headers: &HeaderMap, body: Bytes) -> Result<ImageFrame, ServiceError> {
+    let is_json = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with("application/json"));
+
+    let (image_data, timestamp, extras) = if is_json {
+        let body: PredictBody =
+            serde_json::from_slice(&body).map_err(|e| ServiceError::InvalidJson(e.to_string()))?;
+        let image_data = STANDARD
+            .decode(body.image)
+            .map_err(|e| ServiceError::InvalidBase64(e.to_string()))?;
+        let extras = DetectionExtras {
+            crops: body.crops,
+            crop_padding: body.crop_padding,
+            top_k: body.top_k,
+            all_scores: body.all_scores,
+        };
+        (image_data, body.timestamp, Some(extras))
+    } else {
+        (body.to_vec(), None, None)
+    };
+
+    Ok(ImageFrame {
+        image_data,
+        timestamp: timestamp.unwrap_or_else(now_millis),
+        region_filter: None,
+        extras,
+    })
+}
+
+struct Gateway<M: ModelService, S: State> {
+    inference_service: Arc<InferenceService<M, S>>,
+    auth_interceptor: AuthInterceptor,
+    model: ModelResponse,
+}
+
+impl<M: ModelService, S: State> Gateway<M, S> {
+    /// Builds a gRPC request from the HTTP headers and authenticates it like the gRPC API
+    fn request<T>(&self, parts: Parts, message: T) -> Result<Request<T>, Status> {
+        let request = Request::from_parts(
+            MetadataMap::from_headers(parts.headers),
+            parts.extensions,
+            (),
+        );
+        let (metadata, extensions, ()) = self.auth_interceptor.clone().call(request)?.into_parts();
+        Ok(Request::from_parts(metadata, extensions, message))
+    }
+}
+
+async fn predict_handler<M: ModelService, S: State>(
+    gateway: axum::extract::State<Arc<Gateway<M, S>>>,
+    parts: Parts,
+    body: Bytes,
+) -> Result<Json<PredictionResponse>, ApiError> {
+    let image_frame = image_frame(&parts.headers, body)?;
+    let request = gateway.request(parts, image_frame)?;
+    let batch = gateway
+        .inference_service
+        .predict(request)
+        .await?
+        .into_inner();
+
+    Ok(Json(PredictionResponse::new(
+        batch,
+        &gateway.inference_service.labels(),
+    )))
+}
+
+async fn labels_handler<M: ModelService, S: State>(
+    gateway: axum::extract::State<Arc<Gateway<M, S>>>,
+    parts: Parts,
+) -> Result<Json<Vec<LabelResponse>>, ApiError> {
+    let request = gateway.request(parts, Empty {})?;
+    let labels = gateway
+        .inference_service
+        .get_yolo_class_labels(request)
+        .await?
+        .into_inner()
+        .class_labels
+        .into_iter()
+        .enumerate()
+        .map(|(class_id, label)| LabelResponse {
+            class_id,
+            label: label.label,
+            display_name: label.display_name,
+            color: [label.red, label.green, label.blue],
+            min_probability: label.min_probability,
+            enabled: label.enabled,
+        })
+        .collect();
+
+    Ok(Json(labels))
+}
+
+async fn model_handler<M: ModelService, S: State>(
+    gateway: axum::extract::State<Arc<Gateway<M, S>>>,
+    parts: Parts,
+) -> Result<Json<ModelResponse>, ApiError> {
+    let request = gateway.request(parts, ())?;
+    authorize(&request, Scope::Labels)?;
+
+    Ok(Json(gateway.model.clone()))
+}
+
+/// Exposes the peer address the way tonic does, so HTTP clients get their own
+/// rate limit buckets when authentication is off.
+async fn attach_connect_info(
+    ConnectInfo(remote_addr): ConnectInfo<SocketAddr>,
+    mut request: HttpRequest,
+    next: Next,
+) -> Response {
+    request.extensions_mut().insert(TcpConnectInfo {
+        local_addr: None,
+        remote_addr: Some(remote_addr),
+    });
+    next.run(request).await
+}
+
+pub(crate) fn cors_layer(http_cfg: &HttpConfig) -> Result<Option<CorsLayer>, String> {
+    if http_cfg.cors_allowed_origins.is_empty() {
+        return Ok(None);
+    }
+
+    let allow_origin = if http_cfg.cors_allowed_origins.iter().any(|o| o == "*") {
+        AllowOrigin::any()
+    } else {
+        let origins = http_cfg
+            .cors_allowed_origins
+            .iter()
+            .map(|origin| {
+                HeaderValue::from_str(origin)
+                    .map_err(|e| format!("Invalid CORS origin {}: {}", origin, e))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        AllowOrigin::list(origins)
+    };
+
+    Ok(Some(
+        CorsLayer::new()
+            .allow_origin(allow_origin)
+            .allow_methods([Method::GET, Method::POST])
+            .allow_headers(Any)
+            .expose_headers([
+                HeaderName::from_static("grpc-status"),
+                HeaderName::from_static("grpc-message"),
+                HeaderName::from_static("grpc-status-details-bin"),
+                HeaderName::from_static("retry-after-ms"),
+                header::RETRY_AFTER,
+            ]),
+    ))
+}
+
+pub struct HttpGateway {
+    router: Router,
+    addr: String,
+}
+
+impl HttpGateway {
+    /// Serves the REST endpoints and gRPC-Web with the same service instances as
+    /// the gRPC listener, so limits, metrics and traces are shared.
+    pub fn new<M: ModelService, S: State>(
+        inference_service: Arc<InferenceService<M, S>>,
+        grpc_service: GrpcService<M, S>,
+        auth_interceptor: AuthInterceptor,
+        http_cfg: &HttpConfig,
+        config: &Config,
+    ) -> Result<Self, String> {
+        let model = ModelResponse {
+            name: config.model.get_name(),
+            onnx_file: config.model.onnx_file.clone(),
+            backend: config.model.backend.as_str(),
+            num_instances: config.model.num_instances,
+            num_classes: inference_service.labels().len(),
+            min_probability: config.model.min_probability,
+        };
+        let gateway = Arc::new(Gateway {
+            inference_service,
+            auth_interceptor,
+            model,
+        });
+
+        let max_body_bytes = max_body_bytes(&config.image);
+        let rest = Router::new()
+            .route("/v1/predict", post(predict_handler::<M, S>))
+            .route("/v1/labels", get(labels_handler::<M, S>))
+            .route("/v1/model", get(model_handler::<M, S>))
+            .layer(DefaultBodyLimit::max(max_body_bytes))
+            .with_state(gateway);
+        let grpc_web = Routes::new(grpc_service)
+            .into_axum_router()
+            .layer(GrpcWebLayer::new());
+
+        let mut router = rest.merge(grpc_web);
+        if let Some(cors) = cors_layer(http_cfg)? {
+            router = router.layer(cors);
+        }
+        let router = router.layer(middleware::from_fn(attach_connect_info));
+
+        Ok(Self {
+            router,
+            addr: format!("{}:{}", config.server.host, http_cfg.port),
+        })
+    }
+
+    pub async fn run(
+        self,
+        shutdown: impl Future<Output = ()> + Send + 'static,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let listener = TcpListener::bind(&self.addr).await?;
+        tracing::info!("HTTP gateway listening on {}", self.addr);
+
+        axum::serve(
+            listener,
+            self.router
+                .into_make_service_with_connect_info::<SocketAddr>(),
+        )
+        .with_graceful_shutdown(shutdown)
+        .await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_image_frame() {
+        let mut headers = HeaderMap::new();
+        let frame = image_frame(&headers, Bytes::from_static(b"\xff\xd8raw")).unwrap();
+        assert_eq!(frame.image_data, b"\xff\xd8raw");
+
+        headers.insert(header::CONTENT_TYPE, "application/json".parse().unwrap());
+        let body = Bytes::from_static(br#"{"image": "/9hyYXc=", "timestamp": 42}"#);
+        let frame = image_frame(&headers, body).unwrap();
+        assert_eq!(frame.image_data, b"\xff\xd8raw");
+        assert_eq!(frame.timestamp, 42);
+
+        let err = image_frame(&headers, Bytes::from_static(br#"{"image": "%%"}"#)).unwrap_err();
+        assert!(err.to_string().starts_with("INVALID_BASE64"));
+
+        // An image at `max_bytes` with every option set fits the body limit
+        let image_cfg = ImageConfig {
+            max_bytes: 1000,
+            ..Default::default()
+        };
+        let body = serde_json::json!({
+            "image": STANDARD.encode(vec![0; image_cfg.max_bytes]),
+            "timestamp": -1_000_000_000_000i64,
+            "crops": true,
+            "crop_padding": 0.125,
+            "top_k": 80,
+            "all_scores": true,
+        });
+        let body = serde_json::to_string_pretty(&body).unwrap();
+        assert!(body.len() <= max_body_bytes(&image_cfg));
+    }
+
+    #[test]
+    fn test_api_error() {
+        let mut metadata = MetadataMap::new();
+        metadata.insert("retry-after-ms", "1500".parse().unwrap());
+        let response = ApiError(Status::with_metadata(
+            Code::ResourceExhausted,
+            "rate limit exceeded",
+            metadata,
+        ))
+        .into_response();
+
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.headers()[header::RETRY_AFTER], "2");
+        assert_eq!(
+            ApiError(Status::invalid_argument("EMPTY_IMAGE: image data is empty"))
+                .into_response()
+                .status(),
+            StatusCode::BAD_REQUEST
+        );
+    }
+}
@@ -0,0 +1,192 @@
+use crate::config::ImageConfig;
+use image::{
+    error::ImageError, metadata::Orientation, DynamicImage, ImageDecoder, ImageReader, Limits,
+};
+use std::{fmt, io::Cursor};
+
+#[derive(Debug)]
+pub enum ImageDecodeError {
+    Empty,
+    TooManyBytes { size: usize, max: usize },
+    TooManyPixels { width: u32, height: u32, max: u64 },
+    UnsupportedFormat(String),
+    MemoryLimitExceeded(String),
+    Malformed(String),
+}
+
+impl ImageDecodeError {
+    pub fn reason(&self) -> &'static str {
+        match self {
+            ImageDecodeError::Empty => "EMPTY_IMAGE",
+            ImageDecodeError::TooManyBytes { .. } => "IMAGE_TOO_LARGE",
+            ImageDecodeError::TooManyPixels { .. } => "IMAGE_DIMENSIONS_TOO_LARGE",
+            ImageDecodeError::UnsupportedFormat(_) => "UNSUPPORTED_IMAGE_FORMAT",
+            ImageDecodeError::MemoryLimitExceeded(_) => "IMAGE_MEMORY_LIMIT_EXCEEDED",
+            ImageDecodeError::Malformed(_) => "MALFORMED_IMAGE",
+        }
+    }
+}
+
+impl fmt::Display for ImageDecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ImageDecodeError::Empty => write!(f, "image data is empty"),
+            ImageDecodeError::TooManyBytes { size, max } => {
+                write!(f, "image is {} bytes, maximum is {} bytes", size, max)
+            }
+            ImageDecodeError::TooManyPixels { width, height, max } => write!(
+                f,
+                "image is {}x{} pixels, maximum is {} pixels",
+                width, height, max
+            ),
+            ImageDecodeError::UnsupportedFormat(e) => write!(f, "unsupported image format: {}", e),
+            ImageDecodeError::MemoryLimitExceeded(e) => {
+                write!(f, "image exceeds decoding memory limit: {}", e)
+            }
+            ImageDecodeError::Malformed(e) => write!(f, "error decoding image: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for ImageDecodeError {}
+
+impl From<ImageError> for ImageDecodeError {
+    fn from(err: ImageError) -> Self {
+        match err {
+            ImageError::Unsupported(e) => ImageDecodeError::UnsupportedFormat(e.to_string()),
+            ImageError::Limits(e) => ImageDecodeError::MemoryLimitExceeded(e.to_string()),
+            other => ImageDecodeError::Malformed(other.to_string()),
+        }
+    }
+}
+
+/// Decodes an encoded image, enforcing the configured size limits and applying
+/// its EXIF orientation so that box coordinates match the upright image.
+pub fn decode_image(
+    image_data: &[u8],
+    image_cfg: &ImageConfig,
+) -> Result<DynamicImage, ImageDecodeError> {
+    if image_data.is_empty() {
+        return Err(ImageDecodeError::Empty);
+    }
+
+    if image_data.len() > image_cfg.max_bytes {
+        return Err(ImageDecodeError::TooManyBytes {
+            size: image_data.len(),
+            max: image_cfg.max_bytes,
+        });
+    }
+
+    let mut image_reader = ImageReader::new(Cursor::new(image_data))
+        .with_guessed_format()
+        .map_err(|e| ImageDecodeError::Malformed(e.to_string()))?;
+
+    if image_reader.format().is_none() {
+        return Err(ImageDecodeError::UnsupportedFormat(
+            "could not determine image format".to_string(),
+        ));
+    }
+
+    let mut limits = Limits::default();
+    limits.max_alloc = Some(image_cfg.max_alloc_bytes());
+    image_reader.limits(limits);
+
+    let mut decoder = image_reader.into_decoder()?;
+
+    let (width, height) = decoder.dimensions();
+    if u64::from(width) * u64::from(height) > image_cfg.max_pixels {
+        return Err(ImageDecodeError::TooManyPixels {
+            width,
+            height,
+            max: image_cfg.max_pixels,
+        });
+    }
+
+    let orientation = decoder.orientation().unwrap_or(Orientation::NoTransforms);
+    let mut img = DynamicImage::from_decoder(decoder)?;
+    img.apply_orientation(orientation);
+
+    Ok(img)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{
+        codecs::jpeg::JpegEncoder, GenericImageView, ImageBuffer, ImageEncoder, ImageFormat, Rgb,
+    };
+
+    fn encode(width: u32, height: u32, format: ImageFormat) -> Vec<u8> {
+        let img = ImageBuffer::<Rgb<u8>, Vec<u8>>::from_pixel(width, height, Rgb([255, 0, 0]));
+        let mut image_data = Cursor::new(Vec::new());
+        img.write_to(&mut image_data, format).unwrap();
+        image_data.into_inner()
+    }
+
+    #[test]
+    fn test_decode_image_applies_exif_orientation() {
+        // Minimal little-endian TIFF header with a single orientation entry (6 = rotate 90°)
+        let exif = vec![
+            0x49, 0x49, 0x2a, 0x00, 0x08, 0x00, 0x00, 0x00, 0x01, 0x00, 0x12, 0x01, 0x03, 0x00,
+            0x01, 0x00, 0x00, 0x00, 0x06, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        ];
+        let img = ImageBuffer::<Rgb<u8>, Vec<u8>>::from_pixel(100, 50, Rgb([255, 0, 0]));
+        let mut image_data = Vec::new();
+        let mut encoder = JpegEncoder::new(&mut image_data);
+        encoder.set_exif_metadata(exif).unwrap();
+        encoder
+            .write_image(img.as_raw(), 100, 50, image::ExtendedColorType::Rgb8)
+            .unwrap();
+
+        let decoded = decode_image(&image_data, &ImageConfig::default()).unwrap();
+
+        assert_eq!(decoded.dimensions(), (50, 100));
+    }
+
+    #[test]
+    fn test_decode_image_formats() {
+        for format in [ImageFormat::Png, ImageFormat::Bmp, ImageFormat::Tiff] {
+            let decoded = decode_image(&encode(20, 10, format), &ImageConfig::default()).unwrap();
+            assert_eq!(decoded.dimensions(), (20, 10));
+        }
+    }
+
+    #[test]
+    fn test_decode_image_limits() {
+        let image_cfg = ImageConfig {
+            max_bytes: 1 << 20,
+            max_pixels: 100,
+            ..Default::default()
+        };
+
+        let err = decode_image(&encode(20, 10, ImageFormat::Png), &image_cfg).unwrap_err();
+        assert_eq!(err.reason(), "IMAGE_DIMENSIONS_TOO_LARGE");
+
+        let err = decode_image(&vec![0; (1 << 20) + 1], &image_cfg).unwrap_err();
+        assert_eq!(err.reason(), "IMAGE_TOO_LARGE");
+
+        let err = decode_image(&[], &image_cfg).unwrap_err();
+        assert_eq!(err.reason(), "EMPTY_IMAGE");
+
+        let err = decode_image(b"definitely not an image", &image_cfg).unwrap_err();
+        assert_eq!(err.reason(), "UNSUPPORTED_IMAGE_FORMAT");
+    }
+
+    /// Only the `ftyp` box, enough for the format to be recognized as AVIF
+    const AVIF_HEADER: &[u8] = b"\0\0\0\x18ftypavif\0\0\0\0avifmif1";
+
+    #[cfg(feature = "avif")]
+    #[test]
+    fn test_decode_image_avif() {
+        // The AVIF decoder is compiled in and reads the container
+        let err = decode_image(AVIF_HEADER, &ImageConfig::default()).unwrap_err();
+        assert_eq!(err.reason(), "MALFORMED_IMAGE");
+    }
+
+    #[cfg(not(feature = "avif"))]
+    #[test]
+    fn test_decode_image_avif_disabled() {
+        let err = decode_image(AVIF_HEADER, &ImageConfig::default()).unwrap_err();
+        assert_eq!(err.reason(), "UNSUPPORTED_IMAGE_FORMAT");
+    }
+}
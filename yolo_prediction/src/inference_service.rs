@@ -1,34 +1,157 @@
-use crate::{model_service::ModelService, state::State};
-use std::sync::Arc;
+use crate::{
+    auth::{authorize, Principal, Scope},
+    error::ServiceError,
+    extras::select_scores,
+    model_service::{Deadline, ModelService},
+    rate_limit::RateLimiter,
+    regions::RegionFilter,
+    result_cache::ResultCache,
+    shadow::ShadowModel,
+    state::State,
+    telemetry::{extract_context, Metrics},
+};
+use std::{
+    sync::Arc,
+    time::{Instant, SystemTime},
+};
 use tonic::{async_trait, Request, Response, Status};
+use tracing::Instrument;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
 use yolo_proto::{
-    yolo_service_server::YoloService, Empty, ImageFrame, PredictionBatch, YoloClassLabels,
+    yolo_service_server::YoloService, ColorLabel, Empty, ImageFrame, InferenceSettings,
+    PredictionBatch, UpdateSettingsRequest, YoloClassLabels,
 };
 
+/// Response metadata with the settings version the labels and predictions come from,
+/// clients fetch the labels again when it changes.
+pub const SETTINGS_VERSION_HEADER: &str = "settings-version";
+
+fn with_settings_version<T>(message: T, version: u64) -> Response<T> {
+    let mut response = Response::new(message);
+    response
+        .metadata_mut()
+        .insert(SETTINGS_VERSION_HEADER, version.into());
+    response
+}
+
 #[derive(Debug, Clone)]
 pub struct InferenceService<M: ModelService, S: State> {
     model_service: Arc<M>,
     service_state: Arc<S>,
+    region_filter: Arc<RegionFilter>,
+    rate_limiter: Arc<RateLimiter>,
+    result_cache: Arc<ResultCache>,
+    shadow: Option<Arc<ShadowModel<M>>>,
+    metrics: Arc<Metrics>,
 }
 
 impl<M: ModelService, S: State> InferenceService<M, S> {
-    pub fn new(model_service: M, state: S) -> Result<Self, String> {
+    pub fn new(
+        model_service: M,
+        state: S,
+        region_filter: RegionFilter,
+        rate_limiter: RateLimiter,
+        result_cache: ResultCache,
+        shadow: Option<ShadowModel<M>>,
+        metrics: Arc<Metrics>,
+    ) -> Result<Self, String> {
         Ok(Self {
             model_service: Arc::new(model_service),
             service_state: Arc::new(state),
+            region_filter: Arc::new(region_filter),
+            rate_limiter: Arc::new(rate_limiter),
+            result_cache: Arc::new(result_cache),
+            shadow: shadow.map(Arc::new),
+            metrics,
         })
     }
-}
 
-#[async_trait]
-impl<M: ModelService, S: State> YoloService for InferenceService<M, S> {
-    async fn predict(
+    /// Counts the error and converts it into a status with error details
+    fn error_status(&self, err: ServiceError) -> Status {
+        self.metrics.record_error(err.code());
+        err.into()
+    }
+
+    /// Labels indexed by the class ids of the returned detections
+    pub fn labels(&self) -> Vec<ColorLabel> {
+        self.service_state.get_settings().current().labels.clone()
+    }
+
+    async fn run_prediction(
         &self,
         request: Request<ImageFrame>,
-    ) -> Result<Response<PredictionBatch>, Status> {
+    ) -> Result<PredictionBatch, ServiceError> {
+        authorize(&request, Scope::Predict)?;
+        let _permit = self.rate_limiter.acquire(&request)?;
+        let deadline = Deadline::from_metadata(request.metadata());
+        let cache_key = self.result_cache.key(request.get_ref(), request.metadata());
         let image_frame = request.into_inner();
+
+        let region_filter = match &image_frame.region_filter {
+            Some(request_filter) => &self.region_filter.with_request(request_filter)?,
+            None => self.region_filter.as_ref(),
+        };
+        let settings = self.service_state.get_settings();
+        let recording = settings
+            .recorder()
+            .filter(|recorder| recorder.is_enabled())
+            .map(|recorder| (recorder, SystemTime::now(), image_frame.clone()));
+        let record = |batch: &PredictionBatch| {
+            if let Some((recorder, received_at, frame)) = recording {
+                recorder.record(
+                    received_at,
+                    frame,
+                    settings.get(),
+                    self.region_filter.to_proto(),
+                    batch,
+                );
+            }
+        };
+
+        let settings_version = settings.current().version;
+        if let Some(mut batch) =
+            cache_key.and_then(|key| self.result_cache.get(key, settings_version))
+        {
+            batch.timestamp = image_frame.timestamp;
+            tracing::debug!("Returning {} cached detections", batch.detections.len());
+            record(&batch);
+            return Ok(batch);
+        }
+
+        let extras = image_frame.extras;
+
+        let shadow_frame = self
+            .shadow
+            .as_ref()
+            .and_then(|shadow| shadow.sample(&image_frame));
+
         let model_service = self.model_service.clone();
-        let batch = model_service.predict(image_frame).await?;
+        let started = Instant::now();
+        let mut batch = model_service.predict(image_frame, deadline).await?;
+        let latency = started.elapsed();
+        batch.detections =
+            region_filter.apply(batch.detections, batch.image_width, batch.image_height);
+        batch.detections = self
+            .service_state
+            .get_settings()
+            .class_remapper()
+            .apply(batch.detections);
+
+        if let (Some(shadow), Some(shadow_frame)) = (&self.shadow, shadow_frame) {
+            shadow.mirror(
+                shadow_frame,
+                batch.clone(),
+                latency,
+                region_filter.clone(),
+                self.service_state.get_settings().clone(),
+            );
+        }
+        select_scores(&mut batch.detections, extras.as_ref());
+
+        if let Some(key) = cache_key {
+            self.result_cache.insert(key, settings_version, &batch);
+        }
+        record(&batch);
 
         tracing::debug!("Returning {} detections", batch.detections.len());
         for (i, detection) in batch.detections.iter().enumerate() {
@@ -44,36 +167,103 @@ impl<M: ModelService, S: State> YoloService for InferenceService<M, S> {
             );
         }
 
-        Ok(Response::new(batch))
+        Ok(batch)
+    }
+}
+
+#[async_trait]
+impl<M: ModelService, S: State> YoloService for InferenceService<M, S> {
+    async fn predict(
+        &self,
+        request: Request<ImageFrame>,
+    ) -> Result<Response<PredictionBatch>, Status> {
+        // Continues the client's trace when it sent a `traceparent` header
+        let span = tracing::info_span!("predict", otel.kind = "server");
+        let _ = span.set_parent(extract_context(request.metadata()));
+
+        let batch = self
+            .run_prediction(request)
+            .instrument(span)
+            .await
+            .map_err(|e| self.error_status(e))?;
+        self.metrics.record_detections(batch.detections.len());
+
+        // Read after the prediction, a change in between only costs an extra labels fetch
+        let settings_version = self.service_state.get_settings().current().version;
+        Ok(with_settings_version(batch, settings_version))
     }
 
     async fn get_yolo_class_labels(
         &self,
-        _request: Request<Empty>,
+        request: Request<Empty>,
     ) -> Result<Response<YoloClassLabels>, Status> {
-        let labels = self.service_state.get_labels().clone();
+        authorize(&request, Scope::Labels).map_err(|e| self.error_status(e))?;
+        let settings = self.service_state.get_settings().current();
         let response = YoloClassLabels {
-            class_labels: labels,
+            class_labels: settings.labels.clone(),
         };
 
-        Ok(Response::new(response))
+        Ok(with_settings_version(response, settings.version))
+    }
+
+    async fn get_settings(
+        &self,
+        request: Request<Empty>,
+    ) -> Result<Response<InferenceSettings>, Status> {
+        authorize(&request, Scope::Admin).map_err(|e| self.error_status(e))?;
+
+        Ok(Response::new(self.service_state.get_settings().get()))
+    }
+
+    async fn update_settings(
+        &self,
+        request: Request<UpdateSettingsRequest>,
+    ) -> Result<Response<InferenceSettings>, Status> {
+        authorize(&request, Scope::Admin).map_err(|e| self.error_status(e))?;
+        let changed_by = match (
+            request.extensions().get::<Principal>(),
+            request.remote_addr(),
+        ) {
+            (Some(principal), _) => format!("key:{}", principal.name),
+            (None, Some(addr)) => format!("peer:{}", addr.ip()),
+            (None, None) => "peer:unknown".to_string(),
+        };
+
+        let settings = self
+            .service_state
+            .get_settings()
+            .update(request.into_inner(), &changed_by)
+            .map_err(|e| self.error_status(e))?;
+
+        Ok(Response::new(settings))
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::config::LabelsConfig;
+    use crate::{
+        config::{
+            Backend, CacheConfig, ClassFilterConfig, ClassRemapConfig, LabelPalette, LabelsConfig,
+            ModelConfig, RateLimitConfig,
+        },
+        settings::LiveSettings,
+        telemetry::Metrics,
+    };
     use std::path::PathBuf;
 
-    use yolo_proto::{BoundingBox, ColorLabel};
+    use yolo_proto::BoundingBox;
 
     #[derive(Clone)]
     struct MockModelService {}
 
     #[async_trait]
     impl ModelService for MockModelService {
-        async fn predict(&self, frame: ImageFrame) -> Result<PredictionBatch, Status> {
+        async fn predict(
+            &self,
+            frame: ImageFrame,
+            _deadline: Deadline,
+        ) -> Result<PredictionBatch, ServiceError> {
             let detections = vec![
                 BoundingBox {
                     class_id: 7,
@@ -82,6 +272,7 @@ mod tests {
                     y1: 20.0,
                     x2: 100.0,
                     y2: 150.0,
+                    ..Default::default()
                 },
                 BoundingBox {
                     class_id: 42,
@@ -90,48 +281,62 @@ mod tests {
                     y1: 50.0,
                     x2: 300.0,
                     y2: 200.0,
+                    ..Default::default()
                 },
             ];
 
             Ok(PredictionBatch {
                 detections,
                 timestamp: frame.timestamp,
+                image_width: 640,
+                image_height: 480,
             })
         }
     }
 
     pub struct MockState {
-        class_labels: Vec<ColorLabel>,
+        settings: Arc<LiveSettings>,
     }
 
     impl State for MockState {
-        fn new(_labels_file: &LabelsConfig) -> Result<Self, String> {
+        fn new(
+            _labels_cfg: Option<&LabelsConfig>,
+            model_cfg: &ModelConfig,
+        ) -> Result<Self, ServiceError> {
+            let class_labels = vec![
+                ColorLabel {
+                    label: "class1".to_string(),
+                    red: 255,
+                    green: 0,
+                    blue: 0,
+                    ..Default::default()
+                },
+                ColorLabel {
+                    label: "class2".to_string(),
+                    red: 255,
+                    green: 0,
+                    blue: 0,
+                    ..Default::default()
+                },
+                ColorLabel {
+                    label: "class3".to_string(),
+                    red: 255,
+                    green: 0,
+                    blue: 0,
+                    ..Default::default()
+                },
+            ];
+
             Ok(MockState {
-                class_labels: vec![
-                    ColorLabel {
-                        label: "class1".to_string(),
-                        red: 255,
-                        green: 0,
-                        blue: 0,
-                    },
-                    ColorLabel {
-                        label: "class2".to_string(),
-                        red: 255,
-                        green: 0,
-                        blue: 0,
-                    },
-                    ColorLabel {
-                        label: "class3".to_string(),
-                        red: 255,
-                        green: 0,
-                        blue: 0,
-                    },
-                ],
+                settings: Arc::new(
+                    LiveSettings::new(model_cfg, class_labels)
+                        .map_err(ServiceError::InvalidSettings)?,
+                ),
             })
         }
 
-        fn get_labels(&self) -> &Vec<ColorLabel> {
-            &self.class_labels
+        fn get_settings(&self) -> &Arc<LiveSettings> {
+            &self.settings
         }
     }
 
@@ -141,19 +346,50 @@ mod tests {
             labels_file: "dummy_labels.txt".to_string(),
             labels_dir: PathBuf::from("./dummy_labels_dir"),
         };
+        let mock_model_config = ModelConfig {
+            onnx_file: "dummy_model.onnx".to_string(),
+            num_instances: 1,
+            model_dir: PathBuf::from("./dummy_model_dir"),
+            min_probability: 0.5,
+            nms_iou_threshold: 0.7,
+            class_filter: ClassFilterConfig::default(),
+            class_remap: ClassRemapConfig::default(),
+            backend: Backend::default(),
+            label_palette: LabelPalette::default(),
+        };
 
         let mock_model = MockModelService {};
-        let mock_state = MockState::new(&mock_labels_config).unwrap();
-        let inference_service = InferenceService::new(mock_model, mock_state)?;
+        let mock_state = MockState::new(Some(&mock_labels_config), &mock_model_config).unwrap();
+        let metrics = Arc::new(Metrics::new("test"));
+        let rate_limiter = RateLimiter::new(&RateLimitConfig::default(), metrics.clone());
+        let inference_service = InferenceService::new(
+            mock_model,
+            mock_state,
+            RegionFilter::default(),
+            rate_limiter,
+            ResultCache::new(&CacheConfig::default(), metrics.clone()),
+            None,
+            metrics,
+        )?;
 
         let image_frame = ImageFrame {
             image_data: vec![0; 100],
             timestamp: 12345,
+            ..Default::default()
         };
 
         let request = Request::new(image_frame);
         let response = inference_service.predict(request).await?;
 
+        let settings_version = inference_service
+            .service_state
+            .get_settings()
+            .current()
+            .version;
+        assert_eq!(
+            response.metadata().get(SETTINGS_VERSION_HEADER).unwrap(),
+            settings_version.to_string().as_str()
+        );
         let batch = response.into_inner();
         assert_eq!(batch.detections.len(), 2);
         assert_eq!(batch.detections[0].class_id, 7);
@@ -0,0 +1,320 @@
+use crate::{config::LabelPalette, palette::class_color};
+use serde::Deserialize;
+use std::{
+    collections::BTreeMap,
+    fs::{self, File},
+    io::{self, BufRead},
+    path::Path,
+};
+use yolo_proto::ColorLabel;
+
+fn invalid_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// Loads class labels, picking the parser from the file extension:
+/// `.yaml`/`.yml` for Ultralytics `data.yaml`, `.json` for the JSON schema and
+/// anything else for `label,r,g,b` / `label,#RRGGBB` lines.
+pub fn load_labels(filepath: &Path, palette: &LabelPalette) -> io::Result<Vec<ColorLabel>> {
+    let extension = filepath
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .map(str::to_lowercase);
+
+    match extension.as_deref() {
+        Some("yaml") | Some("yml") => load_ultralytics_data_yaml(filepath, palette),
+        Some("json") => load_json_labels(filepath, palette),
+        _ => load_yolov8_labels(filepath),
+    }
+}
+
+pub fn parse_hex_color(value: &str) -> Result<(u32, u32, u32), String> {
+    let hex = value
+        .strip_prefix('#')
+        .filter(|hex| hex.len() == 6 && hex.chars().all(|c| c.is_ascii_hexdigit()))
+        .ok_or_else(|| format!("Invalid hex color `{}`, expected #RRGGBB", value))?;
+    let channel = |i: usize| u32::from_str_radix(&hex[i..i + 2], 16).unwrap();
+    Ok((channel(0), channel(2), channel(4)))
+}
+
+pub fn load_yolov8_labels(filepath: &Path) -> io::Result<Vec<ColorLabel>> {
+    let file = File::open(filepath)?;
+    let reader = io::BufReader::new(file);
+    let mut color_labels = Vec::new();
+
+    for (index, line_result) in reader.lines().enumerate() {
+        let line = line_result?;
+        let line_number = index + 1;
+        let line = line.trim();
+
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let color_label = parse_label_line(line)
+            .map_err(|e| invalid_data(format!("line {}: {}", line_number, e)))?;
+        color_labels.push(color_label);
+    }
+
+    Ok(color_labels)
+}
+
+fn parse_label_line(line: &str) -> Result<ColorLabel, String> {
+    // Colors are always the trailing fields, so labels themselves may contain commas
+    let (label, (red, green, blue)) = match line.rsplit_once(',') {
+        Some((label, color)) if color.trim().starts_with('#') => {
+            (label, parse_hex_color(color.trim())?)
+        }
+        _ => {
+            let parts: Vec<&str> = line.rsplitn(4, ',').collect();
+            if parts.len() != 4 {
+                return Err(format!(
+                    "Invalid line format, expected `label,r,g,b` or `label,#RRGGBB`: {}",
+                    line
+                ));
+            }
+            let channel = |value: &str, name: &str| {
+                value
+                    .trim()
+                    .parse::<u32>()
+                    .map_err(|_| format!("Invalid {} value `{}`", name, value.trim()))
+            };
+            (
+                parts[3],
+                (
+                    channel(parts[2], "red")?,
+                    channel(parts[1], "green")?,
+                    channel(parts[0], "blue")?,
+                ),
+            )
+        }
+    };
+
+    let label = label.trim();
+    if label.is_empty() {
+        return Err("Empty label".to_string());
+    }
+
+    Ok(ColorLabel {
+        label: label.to_string(),
+        red,
+        green,
+        blue,
+        enabled: true,
+        ..Default::default()
+    })
+}
+
+#[derive(Deserialize)]
+struct UltralyticsDataYaml {
+    names: UltralyticsNames,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum UltralyticsNames {
+    List(Vec<String>),
+    Map(BTreeMap<usize, String>),
+}
+
+fn load_ultralytics_data_yaml(
+    filepath: &Path,
+    palette: &LabelPalette,
+) -> io::Result<Vec<ColorLabel>> {
+    let content = fs::read_to_string(filepath)?;
+    let data: UltralyticsDataYaml = serde_yaml::from_str(&content)
+        .map_err(|e| invalid_data(format!("Invalid data.yaml: {}", e)))?;
+
+    let names = match data.names {
+        UltralyticsNames::List(names) => names,
+        UltralyticsNames::Map(names) => {
+            if names.keys().copied().ne(0..names.len()) {
+                return Err(invalid_data(
+                    "Invalid data.yaml: class ids in `names` must be contiguous and start at 0"
+                        .to_string(),
+                ));
+            }
+            names.into_values().collect()
+        }
+    };
+
+    Ok(names
+        .into_iter()
+        .enumerate()
+        .map(|(class_id, label)| {
+            let (red, green, blue) = class_color(class_id, palette);
+            ColorLabel {
+                label,
+                red,
+                green,
+                blue,
+                enabled: true,
+                ..Default::default()
+            }
+        })
+        .collect())
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawColor {
+    Hex(String),
+    Rgb([u32; 3]),
+}
+
+#[derive(Deserialize)]
+#[serde(try_from = "RawColor")]
+struct Color(u32, u32, u32);
+
+impl TryFrom<RawColor> for Color {
+    type Error = String;
+
+    fn try_from(raw: RawColor) -> Result<Self, Self::Error> {
+        let (red, green, blue) = match raw {
+            RawColor::Hex(hex) => parse_hex_color(&hex)?,
+            RawColor::Rgb([red, green, blue]) => (red, green, blue),
+        };
+        Ok(Color(red, green, blue))
+    }
+}
+
+#[derive(Deserialize)]
+struct JsonLabels {
+    classes: Vec<JsonLabel>,
+}
+
+#[derive(Deserialize)]
+struct JsonLabel {
+    name: String,
+    #[serde(default)]
+    display_name: Option<String>,
+    #[serde(default)]
+    color: Option<Color>,
+    #[serde(default = "default_enabled")]
+    enabled: bool,
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+fn load_json_labels(filepath: &Path, palette: &LabelPalette) -> io::Result<Vec<ColorLabel>> {
+    let content = fs::read_to_string(filepath)?;
+    let labels: JsonLabels = serde_json::from_str(&content)
+        .map_err(|e| invalid_data(format!("Invalid labels JSON: {}", e)))?;
+
+    Ok(labels
+        .classes
+        .into_iter()
+        .enumerate()
+        .map(|(class_id, json_label)| {
+            let Color(red, green, blue) = json_label.color.unwrap_or_else(|| {
+                let (red, green, blue) = class_color(class_id, palette);
+                Color(red, green, blue)
+            });
+            ColorLabel {
+                label: json_label.name,
+                red,
+                green,
+                blue,
+                enabled: json_label.enabled,
+                display_name: json_label.display_name.unwrap_or_default(),
+                ..Default::default()
+            }
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write_labels(file_name: &str, content: &str) -> std::path::PathBuf {
+        let path = std::env::temp_dir().join(file_name);
+        fs::write(&path, content).unwrap();
+        path
+    }
+
+    #[test]
+    fn test_load_yolov8_labels() {
+        let path = write_labels(
+            "yolo_prediction_test_labels.txt",
+            "# COCO subset\nperson, 255, 0, 0\n\nhot dog, with mustard,0,128,255\ncar,#00FF7f\n",
+        );
+
+        let labels = load_labels(&path, &LabelPalette::default()).unwrap();
+
+        assert_eq!(labels.len(), 3);
+        assert_eq!(labels[1].label, "hot dog, with mustard");
+        assert_eq!(
+            (labels[1].red, labels[1].green, labels[1].blue),
+            (0, 128, 255)
+        );
+        assert_eq!(
+            (labels[2].red, labels[2].green, labels[2].blue),
+            (0, 255, 127)
+        );
+
+        let path = write_labels(
+            "yolo_prediction_test_invalid_labels.txt",
+            "person,255,0,0\n\ncar,0,x,0\n",
+        );
+        let err = load_labels(&path, &LabelPalette::default()).unwrap_err();
+
+        assert!(err.to_string().starts_with("line 3:"), "{}", err);
+    }
+
+    #[test]
+    fn test_load_ultralytics_data_yaml() {
+        let path = write_labels(
+            "yolo_prediction_test_data_list.yaml",
+            "path: ../datasets\nnames: [person, bicycle]\n",
+        );
+        let labels = load_labels(&path, &LabelPalette::default()).unwrap();
+        assert_eq!(labels[1].label, "bicycle");
+
+        let path = write_labels(
+            "yolo_prediction_test_data_map.yml",
+            "names:\n  0: person\n  1: bicycle\n",
+        );
+        let labels = load_labels(&path, &LabelPalette::default()).unwrap();
+        assert_eq!(labels[1].label, "bicycle");
+
+        let path = write_labels(
+            "yolo_prediction_test_data_gap.yaml",
+            "names:\n  0: person\n  2: car\n",
+        );
+        assert!(load_labels(&path, &LabelPalette::default()).is_err());
+    }
+
+    #[test]
+    fn test_load_json_labels() {
+        let path = write_labels(
+            "yolo_prediction_test_labels.json",
+            r##"{"classes": [
+                {"name": "person", "display_name": "Person", "color": "#FF0000"},
+                {"name": "tie", "color": [1, 2, 3], "enabled": false},
+                {"name": "car"}
+            ]}"##,
+        );
+
+        let labels = load_labels(&path, &LabelPalette::default()).unwrap();
+
+        assert_eq!(labels[0].display_name, "Person");
+        assert_eq!(
+            (labels[0].red, labels[0].green, labels[0].blue),
+            (255, 0, 0)
+        );
+        assert!(!labels[1].enabled);
+        assert_eq!((labels[1].red, labels[1].green, labels[1].blue), (1, 2, 3));
+        assert!(labels[2].enabled);
+
+        let path = write_labels(
+            "yolo_prediction_test_invalid_labels.json",
+            "{\"classes\": [\n  {\"name\": \"person\", \"color\": \"#FF00\"}\n]}",
+        );
+        let err = load_labels(&path, &LabelPalette::default()).unwrap_err();
+
+        assert!(err.to_string().contains("line 2"), "{}", err);
+    }
+}
@@ -1,9 +1,45 @@
+mod auth;
+mod batch;
+mod bench;
+mod class_filter;
+mod class_remap;
+mod error;
+mod evaluate;
+mod extras;
+mod http_gateway;
+mod image_decoder;
 mod inference_service;
+mod labels;
 mod model_service;
+mod onnx_metadata;
+#[cfg(feature = "ort")]
 mod ort_service;
+mod palette;
+mod postprocessing;
+mod preprocessing;
+mod rate_limit;
+mod recorder;
+mod regions;
+mod replay;
+mod result_cache;
 mod server;
+mod settings;
+mod shadow;
 mod state;
+mod telemetry;
+mod tls;
+#[cfg(feature = "tract")]
+mod tract_service;
 
 pub mod config;
 
+pub use batch::{run_batch, BatchOptions, BatchSummary, OutputFormat};
+pub use bench::{run_bench, BenchOptions, BenchReport, LatencySummary};
+pub use evaluate::{run_evaluation, EvaluateOptions, EvaluationReport};
+pub use replay::{run_replay, ReplayOptions, ReplayReport};
 pub use server::start_server;
+pub use settings::LogFilterHandle;
+pub use telemetry::init_tracer;
+
+#[cfg(not(any(feature = "ort", feature = "tract")))]
+compile_error!("At least one inference backend feature must be enabled: `ort` or `tract`");
@@ -1,19 +1,115 @@
-use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
-use yolo_prediction::{config, start_server};
+use clap::Parser;
+use opentelemetry::trace::TracerProvider;
+use std::{path::PathBuf, process::ExitCode};
+use tracing_subscriber::{layer::SubscriberExt, reload, util::SubscriberInitExt, EnvFilter};
+use yolo_prediction::{
+    config::{self, ConfigOptions},
+    init_tracer, start_server,
+};
+
+#[derive(Debug, Parser)]
+#[command(version, about = "YOLO object detection over gRPC")]
+struct Cli {
+    /// Directory holding `base.yaml` and the profile files
+    #[arg(long, default_value = "configuration")]
+    config_dir: PathBuf,
+    /// Profile merged over `base.yaml`, `<profile>.yaml` in the config directory
+    #[arg(long, env = "APP_ENVIRONMENT", default_value = "local")]
+    profile: String,
+    /// Overrides a setting, e.g. `--set server.port=50052`. Can be repeated
+    #[arg(long = "set", value_name = "KEY=VALUE", value_parser = config::parse_override)]
+    overrides: Vec<(String, String)>,
+    /// Prints the merged configuration, defaults included, and exits
+    #[arg(long)]
+    print_config: bool,
+    /// Validates the configuration and exits, with a non-zero status on errors
+    #[arg(long)]
+    check_config: bool,
+}
+
+/// Prints the configuration errors to the terminal, the JSON logs are not set up yet.
+fn print_errors(errors: &[String]) {
+    eprintln!("Found {} configuration errors:", errors.len());
+    for error in errors {
+        eprintln!("  - {}", error);
+    }
+}
+
+/// Handles `--print-config` and `--check-config`, output goes to the terminal
+/// rather than the JSON logs. The webcam has its own copy: the two binaries only
+/// share the protocol crate, and each prints and checks its own `Config`.
+fn inspect_config(cli: &Cli, options: &ConfigOptions) -> ExitCode {
+    let config = match config::read_configuration(options) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("Invalid configuration:\n  - {}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    if cli.print_config {
+        match serde_yaml::to_string(&config) {
+            Ok(yaml) => print!("{}", yaml),
+            Err(e) => {
+                eprintln!("Failed to print the configuration: {}", e);
+                return ExitCode::FAILURE;
+            }
+        }
+    }
+
+    if cli.check_config {
+        let errors = config::check_configuration(&config);
+        if !errors.is_empty() {
+            print_errors(&errors);
+            return ExitCode::FAILURE;
+        }
+        eprintln!("Configuration is valid");
+    }
+
+    ExitCode::SUCCESS
+}
 
 #[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let config = config::get_configuration().expect("failed to load config");
+async fn main() -> Result<ExitCode, Box<dyn std::error::Error>> {
+    let cli = Cli::parse();
+    let options = ConfigOptions {
+        config_dir: cli.config_dir.clone(),
+        profile: cli.profile.clone(),
+        overrides: cli.overrides.clone(),
+    };
+
+    if cli.print_config || cli.check_config {
+        return Ok(inspect_config(&cli, &options));
+    }
+
+    let config = config::get_configuration(&options).expect("failed to load config");
+    // The same checks as `--check-config`, the server never starts on a config it rejects
+    let errors = config::check_configuration(&config);
+    if !errors.is_empty() {
+        print_errors(&errors);
+        return Ok(ExitCode::FAILURE);
+    }
 
     let log_level = config.log_level.as_str();
     let log_level = &format!("{},ort=info", log_level);
+    let tracer_provider = init_tracer(&config.tracing)?;
+    let otel_layer = tracer_provider.as_ref().map(|provider| {
+        tracing_opentelemetry::layer().with_tracer(provider.tracer("yolo_prediction"))
+    });
+    // Reloadable so the admin API can change the log level at runtime
+    let (log_filter, log_filter_handle) =
+        reload::Layer::new(EnvFilter::try_from_default_env().unwrap_or_else(|_| log_level.into()));
     tracing_subscriber::registry()
-        .with(
-            tracing_subscriber::EnvFilter::try_from_default_env()
-                .unwrap_or_else(|_| log_level.into()),
-        )
+        .with(log_filter)
         .with(tracing_subscriber::fmt::layer().json().with_level(true))
+        .with(otel_layer)
         .init();
 
-    start_server(config).await
+    let result = start_server(config, log_filter_handle).await;
+
+    // Flush the spans still batched in memory
+    if let Some(provider) = tracer_provider {
+        let _ = provider.shutdown();
+    }
+    result.map(|()| ExitCode::SUCCESS)
 }
@@ -1,7 +1,96 @@
-use tonic::{async_trait, Status};
+use crate::error::ServiceError;
+use std::time::{Duration, Instant};
+use tonic::{async_trait, metadata::MetadataMap};
 use yolo_proto::{ImageFrame, PredictionBatch};
 
+/// Point in time after which the client has given up on the response.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Deadline(Option<Instant>);
+
+impl Deadline {
+    pub fn after(timeout: Duration) -> Self {
+        Self(Instant::now().checked_add(timeout))
+    }
+
+    /// Reads the `grpc-timeout` header, a request without one never expires.
+    pub fn from_metadata(metadata: &MetadataMap) -> Self {
+        metadata
+            .get("grpc-timeout")
+            .and_then(|value| value.to_str().ok())
+            .and_then(parse_grpc_timeout)
+            .map_or_else(Self::default, Self::after)
+    }
+
+    /// Fails with `DEADLINE_EXCEEDED` once expired, so expensive stages can be skipped.
+    pub fn check(&self, stage: &'static str) -> Result<(), ServiceError> {
+        match self.0 {
+            Some(deadline) if Instant::now() >= deadline => {
+                Err(ServiceError::DeadlineExceeded { stage })
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Parses the gRPC wire format: at most 8 digits followed by a unit (H, M, S, m, u or n).
+fn parse_grpc_timeout(value: &str) -> Option<Duration> {
+    let (amount, unit) = value.split_at_checked(value.len().checked_sub(1)?)?;
+    if amount.is_empty() || amount.len() > 8 {
+        return None;
+    }
+    let amount: u64 = amount.parse().ok()?;
+
+    match unit {
+        "H" => Some(Duration::from_secs(amount * 60 * 60)),
+        "M" => Some(Duration::from_secs(amount * 60)),
+        "S" => Some(Duration::from_secs(amount)),
+        "m" => Some(Duration::from_millis(amount)),
+        "u" => Some(Duration::from_micros(amount)),
+        "n" => Some(Duration::from_nanos(amount)),
+        _ => None,
+    }
+}
+
 #[async_trait]
 pub trait ModelService: Send + Sync + Clone + 'static {
-    async fn predict(&self, frame: ImageFrame) -> Result<PredictionBatch, Status>;
+    async fn predict(
+        &self,
+        frame: ImageFrame,
+        deadline: Deadline,
+    ) -> Result<PredictionBatch, ServiceError>;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tonic::Code;
+
+    #[test]
+    fn test_parse_grpc_timeout() {
+        assert_eq!(parse_grpc_timeout("250m"), Some(Duration::from_millis(250)));
+        assert_eq!(parse_grpc_timeout("2S"), Some(Duration::from_secs(2)));
+        assert_eq!(parse_grpc_timeout("1H"), Some(Duration::from_secs(3600)));
+        assert_eq!(parse_grpc_timeout("123456789m"), None);
+        assert_eq!(parse_grpc_timeout("m"), None);
+        assert_eq!(parse_grpc_timeout("10x"), None);
+    }
+
+    #[test]
+    fn test_deadline() {
+        let mut metadata = MetadataMap::new();
+        assert!(Deadline::from_metadata(&metadata)
+            .check("inference")
+            .is_ok());
+
+        metadata.insert("grpc-timeout", "0n".parse().unwrap());
+        let status = Deadline::from_metadata(&metadata)
+            .check("inference")
+            .unwrap_err();
+        assert_eq!(status.code(), Code::DeadlineExceeded);
+
+        metadata.insert("grpc-timeout", "10S".parse().unwrap());
+        assert!(Deadline::from_metadata(&metadata)
+            .check("inference")
+            .is_ok());
+    }
 }
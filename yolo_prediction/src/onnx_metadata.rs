@@ -0,0 +1,195 @@
+use prost::Message;
+use std::{fs, path::Path};
+
+// Minimal subset of the ONNX schema: prost skips every undeclared field,
+// including the weight initializers, so this works for any backend.
+#[derive(Clone, PartialEq, Message)]
+struct ModelProto {
+    #[prost(message, optional, tag = "7")]
+    graph: Option<GraphProto>,
+    #[prost(message, repeated, tag = "14")]
+    metadata_props: Vec<StringStringEntryProto>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct StringStringEntryProto {
+    #[prost(string, tag = "1")]
+    key: String,
+    #[prost(string, tag = "2")]
+    value: String,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct GraphProto {
+    #[prost(message, repeated, tag = "12")]
+    output: Vec<ValueInfoProto>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct ValueInfoProto {
+    #[prost(string, tag = "1")]
+    name: String,
+    #[prost(message, optional, tag = "2")]
+    r#type: Option<TypeProto>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct TypeProto {
+    #[prost(message, optional, tag = "1")]
+    tensor_type: Option<TensorTypeProto>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct TensorTypeProto {
+    #[prost(message, optional, tag = "2")]
+    shape: Option<TensorShapeProto>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct TensorShapeProto {
+    #[prost(message, repeated, tag = "1")]
+    dim: Vec<Dimension>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct Dimension {
+    #[prost(int64, optional, tag = "1")]
+    dim_value: Option<i64>,
+}
+
+#[derive(Debug, Default)]
+pub struct OnnxModelInfo {
+    /// Class names from the Ultralytics `names` metadata entry, ordered by class id
+    pub class_names: Option<Vec<String>>,
+    /// Number of classes predicted by the `[1, 4 + num_classes, num_anchors]` output
+    pub num_classes: Option<usize>,
+}
+
+impl OnnxModelInfo {
+    pub fn from_path(path: &Path) -> Result<Self, String> {
+        let bytes = fs::read(path).map_err(|e| format!("Failed to read {:?}: {}", path, e))?;
+        let model = ModelProto::decode(bytes.as_slice())
+            .map_err(|e| format!("Failed to parse ONNX model {:?}: {}", path, e))?;
+
+        let class_names = model
+            .metadata_props
+            .iter()
+            .find(|entry| entry.key == "names")
+            .map(|entry| parse_ultralytics_names(&entry.value))
+            .transpose()?;
+
+        let num_classes = model
+            .graph
+            .as_ref()
+            .and_then(|graph| graph.output.first())
+            .and_then(|output| output.r#type.as_ref())
+            .and_then(|output_type| output_type.tensor_type.as_ref())
+            .and_then(|tensor_type| tensor_type.shape.as_ref())
+            .and_then(|shape| shape.dim.get(1))
+            .and_then(|dim| dim.dim_value)
+            .and_then(|channels| usize::try_from(channels - 4).ok());
+
+        Ok(Self {
+            class_names,
+            num_classes,
+        })
+    }
+}
+
+/// Parses the Python dict literal Ultralytics writes, e.g. `{0: 'person', 1: 'bicycle'}`.
+pub fn parse_ultralytics_names(value: &str) -> Result<Vec<String>, String> {
+    let invalid = |reason: &str| format!("Invalid `names` metadata ({}): {}", reason, value);
+
+    let inner = value
+        .trim()
+        .strip_prefix('{')
+        .and_then(|v| v.strip_suffix('}'))
+        .ok_or_else(|| invalid("expected a dict"))?;
+
+    let mut names = Vec::new();
+    let mut chars = inner.chars().peekable();
+
+    loop {
+        while chars.next_if(|c| c.is_whitespace() || *c == ',').is_some() {}
+        if chars.peek().is_none() {
+            break;
+        }
+
+        let key: String = std::iter::from_fn(|| chars.next_if(char::is_ascii_digit)).collect();
+        let key: usize = key.parse().map_err(|_| invalid("expected a class id"))?;
+        if key != names.len() {
+            return Err(invalid("class ids must be contiguous and start at 0"));
+        }
+
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+        if chars.next() != Some(':') {
+            return Err(invalid("expected `:`"));
+        }
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+
+        let quote = chars
+            .next()
+            .filter(|c| *c == '\'' || *c == '"')
+            .ok_or_else(|| invalid("expected a quoted name"))?;
+        let mut name = String::new();
+        loop {
+            match chars.next() {
+                Some('\\') => name.extend(chars.next()),
+                Some(c) if c == quote => break,
+                Some(c) => name.push(c),
+                None => return Err(invalid("unterminated name")),
+            }
+        }
+        names.push(name);
+    }
+
+    Ok(names)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_ultralytics_names() {
+        let names =
+            parse_ultralytics_names("{0: 'person', 1: \"hair drier\", 2: 'it\\'s, odd'}").unwrap();
+
+        assert_eq!(names, vec!["person", "hair drier", "it's, odd"]);
+        assert!(parse_ultralytics_names("{1: 'person'}").is_err());
+        assert!(parse_ultralytics_names("['person']").is_err());
+    }
+
+    #[test]
+    fn test_onnx_model_info() {
+        let model = ModelProto {
+            graph: Some(GraphProto {
+                output: vec![ValueInfoProto {
+                    name: "output0".to_string(),
+                    r#type: Some(TypeProto {
+                        tensor_type: Some(TensorTypeProto {
+                            shape: Some(TensorShapeProto {
+                                dim: [1, 6, 8400]
+                                    .into_iter()
+                                    .map(|d| Dimension { dim_value: Some(d) })
+                                    .collect(),
+                            }),
+                        }),
+                    }),
+                }],
+            }),
+            metadata_props: vec![StringStringEntryProto {
+                key: "names".to_string(),
+                value: "{0: 'cat', 1: 'dog'}".to_string(),
+            }],
+        };
+        let path = std::env::temp_dir().join("yolo_prediction_test_model_info.onnx");
+        fs::write(&path, model.encode_to_vec()).unwrap();
+
+        let info = OnnxModelInfo::from_path(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(info.num_classes, Some(2));
+        assert_eq!(info.class_names.unwrap(), vec!["cat", "dog"]);
+    }
+}
@@ -0,0 +1,86 @@
+use crate::config::LabelPalette;
+use yolo_proto::ColorLabel;
+
+// Okabe-Ito palette without black, distinguishable under the common color vision deficiencies
+const OKABE_ITO: [(f32, f32, f32); 7] = [
+    (230., 159., 0.),
+    (86., 180., 233.),
+    (0., 158., 115.),
+    (240., 228., 66.),
+    (0., 114., 178.),
+    (213., 94., 0.),
+    (204., 121., 167.),
+];
+
+const GOLDEN_RATIO_CONJUGATE: f32 = 0.618_034;
+
+fn hsv_to_rgb(hue: f32, saturation: f32, value: f32) -> (f32, f32, f32) {
+    let sector = hue * 6.;
+    let chroma = value * saturation;
+    let x = chroma * (1. - (sector % 2. - 1.).abs());
+    let (r, g, b) = match sector as u32 {
+        0 => (chroma, x, 0.),
+        1 => (x, chroma, 0.),
+        2 => (0., chroma, x),
+        3 => (0., x, chroma),
+        4 => (x, 0., chroma),
+        _ => (chroma, 0., x),
+    };
+    let m = value - chroma;
+    ((r + m) * 255., (g + m) * 255., (b + m) * 255.)
+}
+
+/// Deterministic color for a class id: neighbouring ids get far-apart colors.
+pub fn class_color(class_id: usize, palette: &LabelPalette) -> (u32, u32, u32) {
+    let (r, g, b) = match palette {
+        LabelPalette::Distinct => {
+            let hue = (class_id as f32 * GOLDEN_RATIO_CONJUGATE).fract();
+            let value = [0.95, 0.75, 0.55][class_id / 3 % 3];
+            hsv_to_rgb(hue, 0.85, value)
+        }
+        LabelPalette::ColorblindSafe => {
+            let (r, g, b) = OKABE_ITO[class_id % OKABE_ITO.len()];
+            let shade = 1. - 0.2 * (class_id / OKABE_ITO.len() % 4) as f32;
+            (r * shade, g * shade, b * shade)
+        }
+    };
+    (r.round() as u32, g.round() as u32, b.round() as u32)
+}
+
+pub fn generate_color_labels(names: &[String], palette: &LabelPalette) -> Vec<ColorLabel> {
+    names
+        .iter()
+        .enumerate()
+        .map(|(class_id, name)| {
+            let (red, green, blue) = class_color(class_id, palette);
+            ColorLabel {
+                label: name.clone(),
+                red,
+                green,
+                blue,
+                enabled: true,
+                ..Default::default()
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    #[test]
+    fn test_class_color_is_deterministic_and_distinct() {
+        for palette in [LabelPalette::Distinct, LabelPalette::ColorblindSafe] {
+            let colors: Vec<_> = (0..28).map(|id| class_color(id, &palette)).collect();
+            let unique: HashSet<_> = colors.iter().collect();
+
+            assert_eq!(unique.len(), colors.len());
+            assert_eq!(colors[5], class_color(5, &palette));
+            assert!(colors
+                .iter()
+                .all(|(r, g, b)| *r <= 255 && *g <= 255 && *b <= 255));
+        }
+    }
+}
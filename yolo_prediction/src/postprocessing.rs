@@ -0,0 +1,137 @@
+use crate::class_filter::ClassFilter;
+use ndarray::{s, ArrayD, Axis};
+use yolo_proto::BoundingBox;
+
+fn intersection(box1: &BoundingBox, box2: &BoundingBox) -> f32 {
+    // Each overlap is clamped, boxes apart on both axes would otherwise intersect
+    (box1.x2.min(box2.x2) - box1.x1.max(box2.x1)).max(0.)
+        * (box1.y2.min(box2.y2) - box1.y1.max(box2.y1)).max(0.)
+}
+
+fn union(box1: &BoundingBox, box2: &BoundingBox) -> f32 {
+    ((box1.x2 - box1.x1) * (box1.y2 - box1.y1)) + ((box2.x2 - box2.x1) * (box2.y2 - box2.y1))
+        - intersection(box1, box2)
+}
+
+pub fn iou(box1: &BoundingBox, box2: &BoundingBox) -> f32 {
+    intersection(box1, box2) / union(box1, box2)
+}
+
+/// Turns the raw `[1, 4 + num_classes, num_anchors]` YOLOv8 output into boxes
+/// in original image coordinates, keeping only those accepted by the class filter.
+/// With `keep_scores`, each box also carries the score of every class.
+pub fn decode_detections(
+    outputs: &ArrayD<f32>,
+    img_width: u32,
+    img_height: u32,
+    class_filter: &ClassFilter,
+    keep_scores: bool,
+) -> Vec<BoundingBox> {
+    let mut boxes = Vec::new();
+    let output = outputs.slice(s![0, .., ..]).t().to_owned();
+
+    tracing::debug!("Output shape: {:?}", output.shape());
+
+    for row in output.axis_iter(Axis(0)) {
+        let row: Vec<_> = row.iter().copied().collect();
+        let (class_id, prob) = row
+            .iter()
+            .skip(4)
+            .enumerate()
+            .map(|(index, value)| (index, *value))
+            .reduce(|accum, row| if row.1 > accum.1 { row } else { accum })
+            .unwrap();
+
+        if !class_filter.accepts(class_id, prob) {
+            tracing::trace!(
+                "Skipping detection with class_id {} and prob {} (threshold {})",
+                class_id,
+                prob,
+                class_filter.threshold(class_id)
+            );
+            continue;
+        }
+
+        tracing::debug!("Found detection: class_id={}, prob={}", class_id, prob);
+
+        let xc = row[0] / 640. * (img_width as f32);
+        let yc = row[1] / 640. * (img_height as f32);
+        let w = row[2] / 640. * (img_width as f32);
+        let h = row[3] / 640. * (img_height as f32);
+
+        boxes.push(BoundingBox {
+            class_id: class_id.try_into().unwrap(),
+            confidence: prob,
+            x1: xc - w / 2.,
+            y1: yc - h / 2.,
+            x2: xc + w / 2.,
+            y2: yc + h / 2.,
+            class_scores: if keep_scores {
+                row[4..].to_vec()
+            } else {
+                Vec::new()
+            },
+            ..Default::default()
+        });
+    }
+
+    tracing::debug!("Found {} boxes before NMS", boxes.len());
+
+    boxes
+}
+
+pub fn non_max_suppression(mut boxes: Vec<BoundingBox>, iou_threshold: f32) -> Vec<BoundingBox> {
+    boxes.sort_by(|box1, box2| box2.confidence.total_cmp(&box1.confidence));
+    let mut result = Vec::new();
+
+    while !boxes.is_empty() {
+        let best = boxes.remove(0);
+        boxes.retain(|box1| iou(&best, box1) < iou_threshold);
+        result.push(best);
+    }
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bbox(x1: f32, y1: f32, x2: f32, y2: f32, confidence: f32) -> BoundingBox {
+        BoundingBox {
+            x1,
+            y1,
+            x2,
+            y2,
+            class_id: 0,
+            confidence,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_non_max_suppression() {
+        let boxes = vec![
+            bbox(0., 0., 10., 10., 0.6),
+            bbox(1., 1., 10., 10., 0.9),
+            bbox(50., 50., 60., 60., 0.7),
+        ];
+
+        let result = non_max_suppression(boxes, 0.7);
+
+        assert_eq!(result.len(), 2);
+        assert_eq!(result[0].confidence, 0.9);
+        assert_eq!(result[1].confidence, 0.7);
+    }
+
+    #[test]
+    fn test_non_max_suppression_apart() {
+        // Both overlaps are -10, their product alone would make an IoU of 1
+        let boxes = vec![bbox(0., 0., 10., 10., 0.9), bbox(20., 20., 30., 30., 0.8)];
+
+        let result = non_max_suppression(boxes, 0.7);
+
+        assert_eq!(result.len(), 2);
+        assert_eq!(iou(&result[0], &result[1]), 0.);
+    }
+}
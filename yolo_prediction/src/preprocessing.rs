@@ -0,0 +1,72 @@
+use crate::{
+    config::ImageConfig,
+    image_decoder::{decode_image, ImageDecodeError},
+    telemetry::Metrics,
+};
+use image::{imageops::FilterType, DynamicImage, GenericImageView};
+use ndarray::{Array, Ix4};
+use std::time::Instant;
+use yolo_proto::ImageFrame;
+
+/// Decodes the frame into the model input. The decoded image is returned as well,
+/// for the detection crops and the original dimensions.
+pub fn transform_image_frame(
+    image_frame: &ImageFrame,
+    image_cfg: &ImageConfig,
+    metrics: &Metrics,
+) -> Result<(Array<f32, Ix4>, DynamicImage), ImageDecodeError> {
+    let started = Instant::now();
+    let original_img = tracing::info_span!("decode")
+        .in_scope(|| decode_image(&image_frame.image_data, image_cfg))?;
+    metrics.record_decode_duration(started.elapsed());
+
+    let _span = tracing::info_span!("preprocess").entered();
+    let started = Instant::now();
+    let img = original_img.resize_exact(640, 640, FilterType::CatmullRom);
+
+    let mut input = Array::zeros((1, 3, 640, 640));
+    for pixel in img.pixels() {
+        let x = pixel.0 as _;
+        let y = pixel.1 as _;
+        let [r, g, b, _] = pixel.2 .0;
+        input[[0, 0, y, x]] = (r as f32) / 255.;
+        input[[0, 1, y, x]] = (g as f32) / 255.;
+        input[[0, 2, y, x]] = (b as f32) / 255.;
+    }
+    metrics.record_preprocess_duration(started.elapsed());
+
+    Ok((input, original_img))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{ImageBuffer, Rgb};
+    use ndarray::{Array, Ix4};
+    use std::io::Cursor;
+
+    #[test]
+    fn test_transform_image_frame() {
+        let img = ImageBuffer::<Rgb<u8>, Vec<u8>>::from_pixel(100, 100, Rgb([255, 0, 0]));
+        let mut image_data: Vec<u8> = Vec::new();
+        let mut cursor = Cursor::new(&mut image_data);
+        img.write_to(&mut cursor, image::ImageFormat::Png).unwrap();
+
+        let image_frame = ImageFrame {
+            image_data: cursor.get_ref().to_vec(),
+            timestamp: 0,
+            ..Default::default()
+        };
+
+        let input_array_result =
+            transform_image_frame(&image_frame, &ImageConfig::default(), &Metrics::new("test"));
+
+        assert!(input_array_result.is_ok());
+
+        let (input_array, original_img): (Array<f32, Ix4>, DynamicImage) =
+            input_array_result.unwrap();
+
+        assert_eq!(input_array.shape(), &[1, 3, 640, 640]);
+        assert_eq!(original_img.dimensions(), (100, 100));
+    }
+}
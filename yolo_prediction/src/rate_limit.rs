@@ -0,0 +1,240 @@
+use crate::{auth::Principal, config::RateLimitConfig, error::ServiceError, telemetry::Metrics};
+use hashlink::LruCache;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tonic::Request;
+
+/// Above this many tracked clients, the least recently seen one is forgotten
+const MAX_TRACKED_CLIENTS: usize = 10_000;
+/// Hint sent when the concurrency cap is hit, there is no better estimate
+const SHED_RETRY_AFTER: Duration = Duration::from_millis(100);
+
+#[derive(Debug, Clone, Copy)]
+struct Rate {
+    per_second: f64,
+    burst: f64,
+}
+
+impl Rate {
+    /// `None` when `per_second` is 0, i.e. unlimited
+    fn new(per_second: f64, burst: Option<u32>) -> Option<Self> {
+        (per_second > 0.).then(|| Self {
+            per_second,
+            burst: burst.map_or(per_second.ceil(), f64::from).max(1.),
+        })
+    }
+}
+
+#[derive(Debug)]
+struct TokenBucket {
+    rate: Rate,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: Rate, now: Instant) -> Self {
+        Self {
+            rate,
+            tokens: rate.burst,
+            last_refill: now,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last_refill);
+        self.tokens =
+            (self.tokens + elapsed.as_secs_f64() * self.rate.per_second).min(self.rate.burst);
+        self.last_refill = now;
+    }
+
+    /// Takes a token, or returns how long until the next one is available.
+    fn try_take(&mut self, now: Instant) -> Result<(), Duration> {
+        self.refill(now);
+        if self.tokens >= 1. {
+            self.tokens -= 1.;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64(
+                (1. - self.tokens) / self.rate.per_second,
+            ))
+        }
+    }
+}
+
+/// Held for the duration of a call, frees a concurrency slot when dropped.
+pub struct Permit {
+    _permit: Option<OwnedSemaphorePermit>,
+}
+
+#[derive(Debug)]
+pub struct RateLimiter {
+    default_rate: Option<Rate>,
+    client_rates: HashMap<String, Option<Rate>>,
+    buckets: Mutex<LruCache<String, TokenBucket>>,
+    concurrency: Option<Arc<Semaphore>>,
+    metrics: Arc<Metrics>,
+}
+
+impl RateLimiter {
+    pub fn new(rate_limit_cfg: &RateLimitConfig, metrics: Arc<Metrics>) -> Self {
+        Self {
+            default_rate: Rate::new(rate_limit_cfg.requests_per_second, rate_limit_cfg.burst),
+            client_rates: rate_limit_cfg
+                .clients
+                .iter()
+                .map(|(name, client)| {
+                    (
+                        name.clone(),
+                        Rate::new(client.requests_per_second, client.burst),
+                    )
+                })
+                .collect(),
+            buckets: Mutex::new(LruCache::new(MAX_TRACKED_CLIENTS)),
+            concurrency: (rate_limit_cfg.max_concurrent_requests > 0)
+                .then(|| Arc::new(Semaphore::new(rate_limit_cfg.max_concurrent_requests))),
+            metrics,
+        }
+    }
+
+    /// Sheds the call when every concurrency slot is taken, then charges the
+    /// caller's token bucket. Rejections are `RESOURCE_EXHAUSTED` with a
+    /// `retry-after-ms` trailer.
+    pub fn acquire<T>(&self, request: &Request<T>) -> Result<Permit, ServiceError> {
+        let principal = request.extensions().get::<Principal>();
+        // Peers are only used as bucket keys, not as metric labels
+        let client_label = principal.map_or("anonymous", |p| p.name.as_str());
+
+        let permit = match &self.concurrency {
+            Some(semaphore) => Some(semaphore.clone().try_acquire_owned().map_err(|_| {
+                self.metrics
+                    .record_rejected_request("concurrency", client_label);
+                ServiceError::AtCapacity {
+                    retry_after: SHED_RETRY_AFTER,
+                }
+            })?),
+            None => None,
+        };
+
+        let (client, rate) = match principal {
+            Some(principal) => (
+                format!("key:{}", principal.name),
+                self.client_rates
+                    .get(&principal.name)
+                    .copied()
+                    .unwrap_or(self.default_rate),
+            ),
+            None => (
+                request.remote_addr().map_or_else(
+                    || "peer:unknown".to_string(),
+                    |a| format!("peer:{}", a.ip()),
+                ),
+                self.default_rate,
+            ),
+        };
+
+        if let Some(rate) = rate {
+            self.take_token(client, rate, Instant::now())
+                .map_err(|retry_after| {
+                    self.metrics
+                        .record_rejected_request("rate_limit", client_label);
+                    ServiceError::RateLimited { retry_after }
+                })?;
+        }
+
+        Ok(Permit { _permit: permit })
+    }
+
+    fn take_token(&self, client: String, rate: Rate, now: Instant) -> Result<(), Duration> {
+        let mut buckets = self.buckets.lock().unwrap();
+        if let Some(bucket) = buckets.get_mut(&client) {
+            return bucket.try_take(now);
+        }
+
+        let mut bucket = TokenBucket::new(rate, now);
+        let result = bucket.try_take(now);
+        buckets.insert(client, bucket);
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{auth::Scope, config::ClientRateLimitConfig};
+    use tonic::{Code, Status};
+
+    fn request(api_key: &str) -> Request<()> {
+        let mut request = Request::new(());
+        request
+            .extensions_mut()
+            .insert(Principal::new(api_key.to_string(), vec![Scope::Predict]));
+        request
+    }
+
+    #[test]
+    fn test_token_bucket() {
+        let now = Instant::now();
+        let mut bucket = TokenBucket::new(Rate::new(10., Some(2)).unwrap(), now);
+
+        assert!(bucket.try_take(now).is_ok());
+        assert!(bucket.try_take(now).is_ok());
+        assert_eq!(bucket.try_take(now), Err(Duration::from_millis(100)));
+        assert!(bucket.try_take(now + Duration::from_millis(100)).is_ok());
+    }
+
+    #[test]
+    fn test_tracked_clients_bounded() {
+        let rate_limit_cfg = RateLimitConfig {
+            requests_per_second: 1.,
+            ..Default::default()
+        };
+        let mut rate_limiter = RateLimiter::new(&rate_limit_cfg, Arc::new(Metrics::new("test")));
+        rate_limiter.buckets = Mutex::new(LruCache::new(2));
+        let rate = rate_limiter.default_rate.unwrap();
+        let now = Instant::now();
+
+        assert!(rate_limiter.take_token("a".to_string(), rate, now).is_ok());
+        assert!(rate_limiter.take_token("b".to_string(), rate, now).is_ok());
+        assert!(rate_limiter.take_token("a".to_string(), rate, now).is_err());
+        // Evicts "b", the least recently seen client
+        assert!(rate_limiter.take_token("c".to_string(), rate, now).is_ok());
+        assert_eq!(rate_limiter.buckets.lock().unwrap().len(), 2);
+        assert!(rate_limiter.take_token("a".to_string(), rate, now).is_err());
+        assert!(rate_limiter.take_token("b".to_string(), rate, now).is_ok());
+    }
+
+    #[test]
+    fn test_rate_limiter() {
+        let rate_limit_cfg = RateLimitConfig {
+            requests_per_second: 1.,
+            clients: HashMap::from([(
+                "batch".to_string(),
+                ClientRateLimitConfig {
+                    requests_per_second: 0.,
+                    burst: None,
+                },
+            )]),
+            max_concurrent_requests: 2,
+            ..Default::default()
+        };
+        let rate_limiter = RateLimiter::new(&rate_limit_cfg, Arc::new(Metrics::new("test")));
+
+        let _camera = rate_limiter.acquire(&request("camera")).unwrap();
+        let status = Status::from(rate_limiter.acquire(&request("camera")).err().unwrap());
+        assert_eq!(status.code(), Code::ResourceExhausted);
+        assert!(status.metadata().get("retry-after-ms").is_some());
+
+        // Unlimited rate, but only one concurrency slot is left
+        let batch = rate_limiter.acquire(&request("batch")).unwrap();
+        let status = Status::from(rate_limiter.acquire(&request("batch")).err().unwrap());
+        assert_eq!(status.message(), "server is at capacity");
+
+        drop(batch);
+        assert!(rate_limiter.acquire(&request("batch")).is_ok());
+    }
+}
@@ -0,0 +1,414 @@
+use crate::{config::RecorderConfig, telemetry::Metrics};
+use prost::Message;
+use std::{
+    collections::VecDeque,
+    fs::{File, OpenOptions},
+    io::{self, BufRead, BufReader, Read, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{self, SyncSender, TrySendError},
+        Arc,
+    },
+    thread,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use yolo_proto::{
+    ImageFrame, InferenceSettings, PredictionBatch, RecordedRequest,
+    RegionFilter as ProtoRegionFilter,
+};
+
+const ARCHIVE_EXTENSION: &str = "rec";
+
+/// Records waiting for the writer thread, further ones are dropped
+const QUEUE_CAPACITY: usize = 64;
+
+fn unix_millis(time: SystemTime) -> i64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as i64
+}
+
+/// Archive files in one directory, named so they sort oldest first, and capped in
+/// total size. Records are written one at a time, a crash loses at most the one
+/// being written.
+struct Archive {
+    dir: PathBuf,
+    max_file_bytes: u64,
+    max_total_bytes: u64,
+    /// Oldest first with their size, the last one is written to
+    files: VecDeque<(PathBuf, u64)>,
+    current: Option<File>,
+    // Tells apart files opened within the same millisecond
+    sequence: u64,
+}
+
+impl Archive {
+    /// Files left by previous runs count towards the cap and are deleted first.
+    fn open(recorder_cfg: &RecorderConfig) -> io::Result<Self> {
+        std::fs::create_dir_all(&recorder_cfg.dir)?;
+        let files = archive_files(&recorder_cfg.dir)?
+            .into_iter()
+            .map(|path| Ok((path.clone(), std::fs::metadata(&path)?.len())))
+            .collect::<io::Result<_>>()?;
+
+        Ok(Self {
+            dir: recorder_cfg.dir.clone(),
+            max_file_bytes: recorder_cfg.max_file_bytes,
+            max_total_bytes: recorder_cfg.max_total_bytes,
+            files,
+            current: None,
+            sequence: 0,
+        })
+    }
+
+    fn append(&mut self, record: &RecordedRequest) -> io::Result<()> {
+        let data = record.encode_length_delimited_to_vec();
+        let full = match (&self.current, self.files.back()) {
+            (Some(_), Some((_, size))) => {
+                *size > 0 && size + data.len() as u64 > self.max_file_bytes
+            }
+            _ => true,
+        };
+        if full {
+            self.rotate()?;
+        }
+
+        let (Some(current), Some((_, size))) = (&mut self.current, self.files.back_mut()) else {
+            unreachable!("rotate opens a file");
+        };
+        current.write_all(&data)?;
+        *size += data.len() as u64;
+
+        self.enforce_cap();
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        let millis = unix_millis(SystemTime::now());
+        loop {
+            let path = self.dir.join(format!(
+                "requests-{:013}-{:06}.{}",
+                millis, self.sequence, ARCHIVE_EXTENSION
+            ));
+            self.sequence += 1;
+
+            match OpenOptions::new().create_new(true).append(true).open(&path) {
+                Ok(file) => {
+                    self.current = Some(file);
+                    self.files.push_back((path, 0));
+                    return Ok(());
+                }
+                // Left by a run started within the same millisecond
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Deletes the oldest files until the archive fits, the file being written is kept.
+    fn enforce_cap(&mut self) {
+        let mut total: u64 = self.files.iter().map(|(_, size)| size).sum();
+        while total > self.max_total_bytes && self.files.len() > 1 {
+            let Some((path, size)) = self.files.pop_front() else {
+                break;
+            };
+            if let Err(e) = std::fs::remove_file(&path) {
+                if e.kind() != io::ErrorKind::NotFound {
+                    tracing::warn!("Failed to delete recorder file {}: {}", path.display(), e);
+                }
+            }
+            total -= size;
+        }
+    }
+}
+
+/// The archive file itself, or the archive files of a directory, oldest first.
+pub fn archive_files(path: &Path) -> io::Result<Vec<PathBuf>> {
+    if !path.is_dir() {
+        return Ok(vec![path.to_path_buf()]);
+    }
+
+    let mut files = Vec::new();
+    for entry in std::fs::read_dir(path)? {
+        let path = entry?.path();
+        if path.is_file()
+            && path
+                .extension()
+                .is_some_and(|extension| extension == ARCHIVE_EXTENSION)
+        {
+            files.push(path);
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// Reads the records of an archive file one at a time. A record cut short by a
+/// crash ends the file with a warning rather than an error.
+pub struct ArchiveReader {
+    path: PathBuf,
+    reader: BufReader<File>,
+}
+
+impl ArchiveReader {
+    pub fn open(path: &Path) -> io::Result<Self> {
+        Ok(Self {
+            path: path.to_path_buf(),
+            reader: BufReader::new(File::open(path)?),
+        })
+    }
+
+    /// Length prefix of the next record, `None` at the end of the file
+    fn read_length(&mut self) -> io::Result<Option<u64>> {
+        // Gathered across refills when the delimiter straddles the end of the buffer
+        let mut delimiter = Vec::with_capacity(10);
+        loop {
+            let buf = self.reader.fill_buf()?;
+            if buf.is_empty() {
+                return match delimiter.is_empty() {
+                    true => Ok(None),
+                    false => Err(io::ErrorKind::UnexpectedEof.into()),
+                };
+            }
+            let taken = buf.len().min(10 - delimiter.len());
+            delimiter.extend_from_slice(&buf[..taken]);
+
+            let mut remaining = delimiter.as_slice();
+            match prost::decode_length_delimiter(&mut remaining) {
+                Ok(length) => {
+                    self.reader.consume(taken - remaining.len());
+                    return Ok(Some(length as u64));
+                }
+                // Every byte so far continues the delimiter
+                Err(_) if delimiter.len() < 10 => self.reader.consume(taken),
+                Err(e) => return Err(io::Error::new(io::ErrorKind::InvalidData, e)),
+            }
+        }
+    }
+
+    fn read_record(&mut self) -> io::Result<Option<RecordedRequest>> {
+        let Some(length) = self.read_length()? else {
+            return Ok(None);
+        };
+        // A corrupted length does not allocate more than what is left in the file
+        let mut data = Vec::new();
+        (&mut self.reader).take(length).read_to_end(&mut data)?;
+        if (data.len() as u64) < length {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+
+        RecordedRequest::decode(data.as_slice())
+            .map(Some)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+}
+
+impl Iterator for ArchiveReader {
+    type Item = io::Result<RecordedRequest>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.read_record() {
+            Ok(record) => record.map(Ok),
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::UnexpectedEof | io::ErrorKind::InvalidData
+                ) =>
+            {
+                tracing::warn!(
+                    "Skipping truncated record in {}: {}",
+                    self.path.display(),
+                    e
+                );
+                None
+            }
+            Err(e) => Some(Err(e)),
+        }
+    }
+}
+
+/// Writes the `Predict` calls to a size-capped archive while enabled. Records are
+/// handed to a writer thread, so the responses never wait for the disk.
+#[derive(Debug)]
+pub struct Recorder {
+    enabled: AtomicBool,
+    model: String,
+    sender: SyncSender<RecordedRequest>,
+    metrics: Arc<Metrics>,
+}
+
+impl Recorder {
+    pub fn new(
+        recorder_cfg: &RecorderConfig,
+        model_name: &str,
+        metrics: Arc<Metrics>,
+    ) -> Result<Self, String> {
+        let mut archive = Archive::open(recorder_cfg).map_err(|e| {
+            format!(
+                "failed to open recorder archive {}: {}",
+                recorder_cfg.dir.display(),
+                e
+            )
+        })?;
+
+        let (sender, receiver) = mpsc::sync_channel::<RecordedRequest>(QUEUE_CAPACITY);
+        let writer_metrics = metrics.clone();
+        thread::Builder::new()
+            .name("recorder".to_string())
+            .spawn(move || {
+                for record in receiver {
+                    match archive.append(&record) {
+                        Ok(()) => writer_metrics.record_recorded_request("written"),
+                        Err(e) => {
+                            tracing::warn!("Failed to write to the recorder archive: {}", e);
+                            writer_metrics.record_recorded_request("failed");
+                        }
+                    }
+                }
+            })
+            .map_err(|e| format!("failed to start the recorder thread: {}", e))?;
+
+        tracing::info!(
+            "Recorder writing to {} ({})",
+            recorder_cfg.dir.display(),
+            if recorder_cfg.enabled {
+                "enabled"
+            } else {
+                "disabled until started through UpdateSettings"
+            }
+        );
+
+        Ok(Self {
+            enabled: AtomicBool::new(recorder_cfg.enabled),
+            model: model_name.to_string(),
+            sender,
+            metrics,
+        })
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+
+    pub fn set_enabled(&self, enabled: bool) {
+        self.enabled.store(enabled, Ordering::Relaxed);
+    }
+
+    /// Queues the request for the archive, it is dropped when the writer lags behind.
+    pub fn record(
+        &self,
+        received_at: SystemTime,
+        frame: ImageFrame,
+        settings: InferenceSettings,
+        region_filter: ProtoRegionFilter,
+        batch: &PredictionBatch,
+    ) {
+        let record = RecordedRequest {
+            received_at: unix_millis(received_at),
+            frame: Some(frame),
+            settings: Some(settings),
+            region_filter: Some(region_filter),
+            batch: Some(batch.clone()),
+            model: self.model.clone(),
+        };
+
+        if let Err(TrySendError::Full(_)) = self.sender.try_send(record) {
+            self.metrics.record_recorded_request("dropped");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(received_at: i64) -> RecordedRequest {
+        RecordedRequest {
+            received_at,
+            frame: Some(ImageFrame {
+                image_data: vec![0; 100],
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_archive_rotation() {
+        let dir =
+            std::env::temp_dir().join(format!("yolo_prediction_recorder_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let record_size = record(1).encode_length_delimited_to_vec().len() as u64;
+        let recorder_cfg = RecorderConfig {
+            dir: dir.clone(),
+            enabled: true,
+            max_file_bytes: 2 * record_size,
+            max_total_bytes: 4 * record_size,
+        };
+
+        let mut archive = Archive::open(&recorder_cfg).unwrap();
+        for received_at in 0..7 {
+            archive.append(&record(received_at)).unwrap();
+        }
+
+        // Files of two records, the oldest ones deleted to stay under the cap
+        let files = archive_files(&dir).unwrap();
+        assert_eq!(files.len(), 2);
+        let received_at: Vec<_> = files
+            .iter()
+            .flat_map(|path| ArchiveReader::open(path).unwrap().map(Result::unwrap))
+            .map(|record| record.received_at)
+            .collect();
+        assert_eq!(received_at, [4, 5, 6]);
+
+        // A truncated last record is skipped
+        let mut data = std::fs::read(&files[0]).unwrap();
+        data.truncate(data.len() - 10);
+        std::fs::write(&files[0], data).unwrap();
+        assert_eq!(ArchiveReader::open(&files[0]).unwrap().count(), 1);
+
+        // Files left by a previous run count towards the cap
+        let mut archive = Archive::open(&recorder_cfg).unwrap();
+        for received_at in 7..11 {
+            archive.append(&record(received_at)).unwrap();
+        }
+        assert_eq!(archive_files(&dir).unwrap().len(), 2);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_archive_reader_small_buffer() {
+        let path = std::env::temp_dir().join(format!(
+            "yolo_prediction_archive_reader_{}",
+            std::process::id()
+        ));
+        let large_record = |received_at| {
+            let mut record = record(received_at);
+            record.frame.as_mut().unwrap().image_data = vec![0; 200];
+            record
+        };
+        let mut data = Vec::new();
+        for received_at in 0..3 {
+            large_record(received_at)
+                .encode_length_delimited(&mut data)
+                .unwrap();
+        }
+        // Two-byte length prefixes, split over the refills of a one-byte buffer
+        assert_eq!(
+            prost::length_delimiter_len(large_record(0).encoded_len()),
+            2
+        );
+        std::fs::write(&path, data).unwrap();
+
+        let reader = ArchiveReader {
+            path: path.clone(),
+            reader: BufReader::with_capacity(1, File::open(&path).unwrap()),
+        };
+        let received_at: Vec<_> = reader.map(|record| record.unwrap().received_at).collect();
+        assert_eq!(received_at, [0, 1, 2]);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
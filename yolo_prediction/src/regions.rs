@@ -0,0 +1,411 @@
+use crate::{
+    config::{AnchorPoint, RegionsConfig},
+    error::ServiceError,
+};
+use yolo_proto::{BoundingBox, Point, Polygon as ProtoPolygon, RegionFilter as ProtoRegionFilter};
+
+/// Closed polygon in normalized image coordinates, `(0, 0)` being the top-left corner.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Polygon {
+    points: Vec<(f32, f32)>,
+}
+
+impl Polygon {
+    pub fn new(points: Vec<(f32, f32)>) -> Result<Self, String> {
+        if points.len() < 3 {
+            return Err(format!(
+                "A polygon needs at least 3 points, got {}",
+                points.len()
+            ));
+        }
+        Ok(Self { points })
+    }
+
+    /// Even-odd ray casting test.
+    pub fn contains(&self, x: f32, y: f32) -> bool {
+        let mut inside = false;
+        let mut j = self.points.len() - 1;
+        for i in 0..self.points.len() {
+            let (xi, yi) = self.points[i];
+            let (xj, yj) = self.points[j];
+            if (yi > y) != (yj > y) && x < (xj - xi) * (y - yi) / (yj - yi) + xi {
+                inside = !inside;
+            }
+            j = i;
+        }
+        inside
+    }
+}
+
+impl From<&Polygon> for ProtoPolygon {
+    fn from(polygon: &Polygon) -> Self {
+        ProtoPolygon {
+            points: polygon
+                .points
+                .iter()
+                .map(|&(x, y)| Point { x, y })
+                .collect(),
+        }
+    }
+}
+
+impl TryFrom<&ProtoPolygon> for Polygon {
+    type Error = String;
+
+    fn try_from(polygon: &ProtoPolygon) -> Result<Self, Self::Error> {
+        Polygon::new(polygon.points.iter().map(|p| (p.x, p.y)).collect())
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct RegionFilter {
+    include: Vec<Polygon>,
+    exclude: Vec<Polygon>,
+    anchor: AnchorPoint,
+    /// Zones of the request, checked with its own anchor on top of the configured ones
+    request: Option<Box<RegionFilter>>,
+    /// Zones per request and points per zone, recorded requests are not checked again
+    request_limits: Option<(usize, usize)>,
+}
+
+impl RegionFilter {
+    pub fn new(regions_cfg: &RegionsConfig) -> Result<Self, String> {
+        let polygons = |polygons: &[Vec<[f32; 2]>]| {
+            polygons
+                .iter()
+                .map(|points| Polygon::new(points.iter().map(|[x, y]| (*x, *y)).collect()))
+                .collect::<Result<Vec<_>, _>>()
+        };
+
+        Ok(Self {
+            include: polygons(&regions_cfg.include)?,
+            exclude: polygons(&regions_cfg.exclude)?,
+            anchor: regions_cfg.anchor.clone(),
+            request: None,
+            request_limits: Some((regions_cfg.max_request_zones, regions_cfg.max_zone_points)),
+        })
+    }
+
+    /// Applies a per-request override. The request can only narrow the configured
+    /// zones: a detection must pass the configured zones with the configured anchor,
+    /// then the request's zones with the request's anchor.
+    pub fn with_request(&self, request: &ProtoRegionFilter) -> Result<Self, ServiceError> {
+        let polygons = |polygons: &[ProtoPolygon]| {
+            polygons
+                .iter()
+                .map(Polygon::try_from)
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(ServiceError::InvalidRegion)
+        };
+
+        if let Some((max_zones, max_points)) = self.request_limits {
+            if request.include.len() + request.exclude.len() > max_zones {
+                return Err(ServiceError::InvalidRegion(format!(
+                    "at most {} zones per request",
+                    max_zones
+                )));
+            }
+            if request
+                .include
+                .iter()
+                .chain(&request.exclude)
+                .any(|zone| zone.points.len() > max_points)
+            {
+                return Err(ServiceError::InvalidRegion(format!(
+                    "at most {} points per zone",
+                    max_points
+                )));
+            }
+        }
+
+        let anchor = match request.anchor() {
+            yolo_proto::AnchorPoint::Unspecified => self.anchor.clone(),
+            yolo_proto::AnchorPoint::BottomCenter => AnchorPoint::BottomCenter,
+            yolo_proto::AnchorPoint::Centroid => AnchorPoint::Centroid,
+        };
+
+        Ok(Self {
+            request: Some(Box::new(Self {
+                include: polygons(&request.include)?,
+                exclude: polygons(&request.exclude)?,
+                anchor,
+                request: None,
+                request_limits: None,
+            })),
+            ..self.clone()
+        })
+    }
+
+    /// Filter with exactly the given zones and anchor, as recorded by `to_proto`.
+    pub fn from_proto(region_filter: &ProtoRegionFilter) -> Result<Self, ServiceError> {
+        let polygons = |polygons: &[ProtoPolygon]| {
+            polygons
+                .iter()
+                .map(Polygon::try_from)
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(ServiceError::InvalidRegion)
+        };
+
+        Ok(Self {
+            include: polygons(&region_filter.include)?,
+            exclude: polygons(&region_filter.exclude)?,
+            anchor: match region_filter.anchor() {
+                yolo_proto::AnchorPoint::Unspecified => AnchorPoint::default(),
+                yolo_proto::AnchorPoint::BottomCenter => AnchorPoint::BottomCenter,
+                yolo_proto::AnchorPoint::Centroid => AnchorPoint::Centroid,
+            },
+            request: None,
+            request_limits: None,
+        })
+    }
+
+    /// The configured zones, a request override is not part of it.
+    pub fn to_proto(&self) -> ProtoRegionFilter {
+        let anchor = match self.anchor {
+            AnchorPoint::BottomCenter => yolo_proto::AnchorPoint::BottomCenter,
+            AnchorPoint::Centroid => yolo_proto::AnchorPoint::Centroid,
+        };
+
+        ProtoRegionFilter {
+            include: self.include.iter().map(ProtoPolygon::from).collect(),
+            exclude: self.exclude.iter().map(ProtoPolygon::from).collect(),
+            anchor: anchor.into(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.include.is_empty()
+            && self.exclude.is_empty()
+            && self
+                .request
+                .as_ref()
+                .is_none_or(|request| request.is_empty())
+    }
+
+    fn accepts(&self, detection: &BoundingBox, img_width: f32, img_height: f32) -> bool {
+        let x = (detection.x1 + detection.x2) / 2. / img_width;
+        let y = match self.anchor {
+            AnchorPoint::BottomCenter => detection.y2 / img_height,
+            AnchorPoint::Centroid => (detection.y1 + detection.y2) / 2. / img_height,
+        };
+
+        (self.include.is_empty() || self.include.iter().any(|zone| zone.contains(x, y)))
+            && !self.exclude.iter().any(|zone| zone.contains(x, y))
+            && self
+                .request
+                .as_ref()
+                .is_none_or(|request| request.accepts(detection, img_width, img_height))
+    }
+
+    pub fn apply(
+        &self,
+        detections: Vec<BoundingBox>,
+        img_width: u32,
+        img_height: u32,
+    ) -> Vec<BoundingBox> {
+        if self.is_empty() || img_width == 0 || img_height == 0 {
+            return detections;
+        }
+
+        detections
+            .into_iter()
+            .filter(|detection| self.accepts(detection, img_width as f32, img_height as f32))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ImageConfig;
+    use prost::Message;
+    use yolo_proto::{DetectionExtras, ImageFrame};
+
+    fn bbox(x1: f32, y1: f32, x2: f32, y2: f32) -> BoundingBox {
+        BoundingBox {
+            x1,
+            y1,
+            x2,
+            y2,
+            class_id: 0,
+            confidence: 0.9,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_region_filter() {
+        let regions_cfg = RegionsConfig {
+            include: vec![vec![[0., 0.], [1., 0.], [1., 0.6], [0., 0.6]]],
+            exclude: vec![vec![[0., 0.], [0.2, 0.], [0.2, 1.], [0., 1.]]],
+            anchor: AnchorPoint::BottomCenter,
+            ..Default::default()
+        };
+        let region_filter = RegionFilter::new(&regions_cfg).unwrap();
+
+        let detections = region_filter.apply(
+            vec![
+                // Feet inside the ROI
+                bbox(40., 10., 60., 50.),
+                // Feet below the ROI, although its centroid is inside
+                bbox(40., 10., 60., 80.),
+                // Inside the exclusion zone
+                bbox(0., 10., 10., 50.),
+            ],
+            100,
+            100,
+        );
+
+        assert_eq!(detections.len(), 1);
+        assert_eq!(detections[0].y2, 50.);
+    }
+
+    #[test]
+    fn test_region_filter_request_override() {
+        let regions_cfg = RegionsConfig {
+            exclude: vec![vec![[0., 0.], [0.2, 0.], [0.2, 1.], [0., 1.]]],
+            ..Default::default()
+        };
+        let region_filter = RegionFilter::new(&regions_cfg).unwrap();
+
+        let point = |x, y| Point { x, y };
+        let request = ProtoRegionFilter {
+            include: vec![ProtoPolygon {
+                points: vec![point(0., 0.), point(1., 0.), point(1., 1.), point(0., 1.)],
+            }],
+            exclude: vec![],
+            anchor: yolo_proto::AnchorPoint::Centroid.into(),
+        };
+        let overridden = region_filter.with_request(&request).unwrap();
+
+        let detections = overridden.apply(
+            vec![bbox(0., 10., 10., 50.), bbox(40., 10., 60., 80.)],
+            100,
+            100,
+        );
+
+        assert_eq!(detections.len(), 1);
+
+        // The configured zones are recorded, the request override is applied again
+        let replayed = RegionFilter::from_proto(&region_filter.to_proto())
+            .unwrap()
+            .with_request(&request)
+            .unwrap();
+        assert_eq!(
+            replayed.apply(
+                vec![bbox(0., 10., 10., 50.), bbox(40., 10., 60., 80.)],
+                100,
+                100
+            ),
+            detections
+        );
+
+        // An empty or wider request cannot widen the configured region of interest
+        let regions_cfg = RegionsConfig {
+            include: vec![vec![[0., 0.], [0.5, 0.], [0.5, 1.], [0., 1.]]],
+            ..Default::default()
+        };
+        let roi_filter = RegionFilter::new(&regions_cfg).unwrap();
+        let detections = vec![bbox(10., 10., 20., 50.), bbox(70., 10., 80., 50.)];
+        let apply = |request: &ProtoRegionFilter| {
+            roi_filter
+                .with_request(request)
+                .unwrap()
+                .apply(detections.clone(), 100, 100)
+                .len()
+        };
+        assert_eq!(apply(&ProtoRegionFilter::default()), 1);
+        assert_eq!(apply(&request), 1);
+        let narrower = ProtoRegionFilter {
+            include: vec![ProtoPolygon {
+                points: vec![point(0.5, 0.), point(1., 0.), point(1., 1.), point(0.5, 1.)],
+            }],
+            ..Default::default()
+        };
+        assert_eq!(apply(&narrower), 0);
+
+        // The request's anchor does not apply to the configured zones: feet below the
+        // region of interest stay out although the centroid is inside
+        let regions_cfg = RegionsConfig {
+            include: vec![vec![[0., 0.], [1., 0.], [1., 0.6], [0., 0.6]]],
+            anchor: AnchorPoint::BottomCenter,
+            ..Default::default()
+        };
+        let roi_filter = RegionFilter::new(&regions_cfg).unwrap();
+        let centroid = ProtoRegionFilter {
+            anchor: yolo_proto::AnchorPoint::Centroid.into(),
+            ..Default::default()
+        };
+        assert!(roi_filter
+            .with_request(&centroid)
+            .unwrap()
+            .apply(vec![bbox(40., 10., 60., 80.)], 100, 100)
+            .is_empty());
+
+        assert!(region_filter
+            .with_request(&ProtoRegionFilter {
+                include: vec![ProtoPolygon {
+                    points: vec![point(0., 0.)],
+                }],
+                ..Default::default()
+            })
+            .is_err());
+    }
+
+    #[test]
+    fn test_region_filter_request_limits() {
+        let regions_cfg = RegionsConfig {
+            max_request_zones: 2,
+            max_zone_points: 4,
+            ..Default::default()
+        };
+        let region_filter = RegionFilter::new(&regions_cfg).unwrap();
+        let zone = |num_points| ProtoPolygon {
+            points: (0..num_points)
+                .map(|i| Point {
+                    x: (i as f32 / num_points as f32 * 6.).cos(),
+                    y: (i as f32 / num_points as f32 * 6.).sin(),
+                })
+                .collect(),
+        };
+
+        let request = |include: Vec<ProtoPolygon>, exclude| ProtoRegionFilter {
+            include,
+            exclude,
+            ..Default::default()
+        };
+        assert!(region_filter
+            .with_request(&request(vec![zone(4)], vec![zone(3)]))
+            .is_ok());
+        assert!(region_filter
+            .with_request(&request(vec![zone(3), zone(3)], vec![zone(3)]))
+            .is_err());
+        assert!(region_filter
+            .with_request(&request(vec![zone(5)], vec![]))
+            .is_err());
+
+        // A frame with the largest request zones and an image at `max_bytes` fits the
+        // message limit, so the image is what gets rejected
+        let image_cfg = ImageConfig {
+            max_bytes: 100_000,
+            ..Default::default()
+        };
+        let regions_cfg = RegionsConfig::default();
+        let frame = ImageFrame {
+            image_data: vec![0; image_cfg.max_bytes],
+            timestamp: -1,
+            region_filter: Some(ProtoRegionFilter {
+                include: vec![zone(regions_cfg.max_zone_points); regions_cfg.max_request_zones],
+                exclude: Vec::new(),
+                anchor: yolo_proto::AnchorPoint::BottomCenter.into(),
+            }),
+            extras: Some(DetectionExtras {
+                crops: true,
+                crop_padding: Some(-0.5),
+                top_k: 1000,
+                all_scores: true,
+            }),
+        };
+        assert!(frame.encoded_len() <= image_cfg.max_message_bytes(&regions_cfg));
+    }
+}
@@ -0,0 +1,574 @@
+#[cfg(feature = "ort")]
+use crate::ort_service::OrtModelService;
+#[cfg(feature = "tract")]
+use crate::tract_service::TractModelService;
+use crate::{
+    config::{Backend, Config},
+    extras::select_scores,
+    http_gateway::PredictionResponse,
+    model_service::{Deadline, ModelService},
+    recorder::{archive_files, ArchiveReader},
+    regions::RegionFilter,
+    settings::LiveSettings,
+    shadow::Agreement,
+    state::{ServiceState, State},
+    telemetry::Metrics,
+};
+use serde::Serialize;
+use std::{
+    fmt,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+use tokio::task::JoinSet;
+use yolo_proto::{
+    BoundingBox, InferenceSettings, PredictionBatch, RecordedRequest, UpdateSettingsRequest,
+};
+
+/// Largest difference between two coordinates or confidences still considered identical
+const TOLERANCE: f32 = 1e-3;
+
+/// Options of `yolo-predict replay`
+#[derive(Debug, Clone, clap::Args)]
+pub struct ReplayOptions {
+    /// Archive files or recorder directories
+    #[arg(required = true)]
+    pub inputs: Vec<PathBuf>,
+    /// Skips the requests received earlier, in unix milliseconds or RFC 3339 UTC
+    /// such as `2024-05-01T12:00:00Z`
+    #[arg(long, value_parser = parse_timestamp)]
+    pub from: Option<i64>,
+    /// Skips the requests received later, same formats as `--from`
+    #[arg(long, value_parser = parse_timestamp)]
+    pub to: Option<i64>,
+    /// Runs with the configured settings instead of those recorded with each request
+    #[arg(long)]
+    pub current_settings: bool,
+    /// Minimum IoU for a replayed box to match a recorded one
+    #[arg(long, default_value_t = 0.5)]
+    pub iou_threshold: f32,
+    /// Writes the image and both results of every changed request to this directory
+    #[arg(long, value_name = "DIR")]
+    pub diff_dir: Option<PathBuf>,
+    /// Prints the report as JSON
+    #[arg(long)]
+    pub json: bool,
+    /// Requests processed at once, defaults to `model.num_instances`
+    #[arg(long)]
+    pub concurrency: Option<usize>,
+}
+
+/// A request whose replayed detections differ from the recorded ones
+#[derive(Debug, Serialize)]
+pub struct ReplayDifference {
+    pub received_at: String,
+    pub archive: PathBuf,
+    pub recorded: usize,
+    pub replayed: usize,
+    #[serde(flatten)]
+    pub agreement: Agreement,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct ReplayReport {
+    /// Requests run again, identical or changed
+    pub replayed: usize,
+    pub identical: usize,
+    pub changed: usize,
+    pub failed: usize,
+    pub differences: Vec<ReplayDifference>,
+}
+
+impl fmt::Display for ReplayReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "Replayed: {} ({} identical, {} changed, {} failed)",
+            self.replayed, self.identical, self.changed, self.failed
+        )?;
+        if self.differences.is_empty() {
+            return Ok(());
+        }
+
+        writeln!(f)?;
+        writeln!(
+            f,
+            "{:<24} {:>8} {:>8} {:>7} {:>14} {:>13} {:>11}",
+            "received_at",
+            "recorded",
+            "replayed",
+            "matched",
+            "class_mismatch",
+            "recorded_only",
+            "replay_only"
+        )?;
+        for difference in &self.differences {
+            writeln!(
+                f,
+                "{:<24} {:>8} {:>8} {:>7} {:>14} {:>13} {:>11}",
+                difference.received_at,
+                difference.recorded,
+                difference.replayed,
+                difference.agreement.matched,
+                difference.agreement.class_mismatches,
+                difference.agreement.primary_only,
+                difference.agreement.shadow_only
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Serialize)]
+struct Dump<'a> {
+    agreement: &'a Agreement,
+    recorded: PredictionResponse,
+    replayed: PredictionResponse,
+}
+
+struct Recorded {
+    archive: PathBuf,
+    request: RecordedRequest,
+}
+
+/// Runs the recorded requests again against the configured model, with the settings
+/// and region filter each was served with, and compares the detections.
+pub async fn run_replay(config: Config, options: ReplayOptions) -> Result<ReplayReport, String> {
+    let metrics = Arc::new(Metrics::new(&config.model.get_name()));
+    let service_state =
+        ServiceState::new(config.labels.as_ref(), &config.model).map_err(|e| e.to_string())?;
+    let settings = service_state.get_settings().clone();
+    let concurrency = options
+        .concurrency
+        .unwrap_or(config.model.num_instances)
+        .max(1);
+
+    match &config.model.backend {
+        #[cfg(feature = "ort")]
+        Backend::Ort => {
+            let model_service =
+                OrtModelService::new(&config.model, &config.image, settings.clone(), metrics)
+                    .map_err(|e| format!("failed to instantiate ort model service: {}", e))?;
+            Replayer {
+                model_service,
+                settings,
+                options,
+            }
+            .replay_all(concurrency)
+            .await
+        }
+        #[cfg(feature = "tract")]
+        Backend::Tract => {
+            let model_service =
+                TractModelService::new(&config.model, &config.image, settings.clone(), metrics)
+                    .map_err(|e| format!("failed to instantiate tract model service: {}", e))?;
+            Replayer {
+                model_service,
+                settings,
+                options,
+            }
+            .replay_all(concurrency)
+            .await
+        }
+        #[allow(unreachable_patterns)]
+        backend => Err(format!(
+            "The {} backend is not compiled in, rebuild with `--features {}`",
+            backend.as_str(),
+            backend.as_str()
+        )),
+    }
+}
+
+/// The recorded settings that change predictions, without the version and the
+/// server-side flags
+fn prediction_settings(request: &RecordedRequest) -> Option<InferenceSettings> {
+    request.settings.as_ref().map(|settings| InferenceSettings {
+        min_probability: settings.min_probability,
+        nms_iou_threshold: settings.nms_iou_threshold,
+        class_filter: settings.class_filter.clone(),
+        ..Default::default()
+    })
+}
+
+struct Replayer<M: ModelService> {
+    model_service: M,
+    settings: Arc<LiveSettings>,
+    options: ReplayOptions,
+}
+
+impl<M: ModelService> Replayer<M> {
+    /// Requests recorded with the same settings run concurrently, the settings are
+    /// switched between runs so no prediction sees a mix of both. The archives are
+    /// read one record at a time, in the order of the inputs.
+    async fn replay_all(self, concurrency: usize) -> Result<ReplayReport, String> {
+        let replayer = Arc::new(self);
+        let options = &replayer.options;
+        let mut report = ReplayReport::default();
+        let mut tasks = JoinSet::new();
+        // Settings of the requests in flight, and whether they could be applied
+        let mut applied: Option<(Option<InferenceSettings>, bool)> = None;
+
+        for input in &options.inputs {
+            let archives = archive_files(input)
+                .map_err(|e| format!("failed to list {}: {}", input.display(), e))?;
+            for archive in archives {
+                let requests = ArchiveReader::open(&archive)
+                    .map_err(|e| format!("failed to read {}: {}", archive.display(), e))?;
+                for request in requests {
+                    let request = request
+                        .map_err(|e| format!("failed to read {}: {}", archive.display(), e))?;
+                    if options.from.is_some_and(|from| request.received_at < from)
+                        || options.to.is_some_and(|to| request.received_at > to)
+                    {
+                        continue;
+                    }
+
+                    if !options.current_settings {
+                        let settings = prediction_settings(&request);
+                        if applied
+                            .as_ref()
+                            .is_none_or(|(current, _)| *current != settings)
+                        {
+                            replayer.join(&mut tasks, 0, &mut report).await?;
+                            let result = replayer.apply_settings(settings.clone());
+                            if let Err(e) = &result {
+                                tracing::warn!(
+                                    "Skipping the requests whose settings cannot be applied: {}",
+                                    e
+                                );
+                            }
+                            applied = Some((settings, result.is_ok()));
+                        }
+                        if let Some((_, false)) = applied {
+                            report.failed += 1;
+                            continue;
+                        }
+                    }
+
+                    replayer
+                        .join(&mut tasks, concurrency - 1, &mut report)
+                        .await?;
+                    let recorded = Recorded {
+                        archive: archive.clone(),
+                        request,
+                    };
+                    let replayer = replayer.clone();
+                    tasks.spawn(async move {
+                        let result = replayer.replay(&recorded.request).await;
+                        (recorded, result)
+                    });
+                }
+            }
+        }
+        replayer.join(&mut tasks, 0, &mut report).await?;
+
+        // Same fixed-width format, sorts in the order the requests were received
+        report.differences.sort_by(|difference1, difference2| {
+            difference1.received_at.cmp(&difference2.received_at)
+        });
+        tracing::info!(
+            "Replayed {} requests: {} identical, {} changed, {} failed",
+            report.replayed,
+            report.identical,
+            report.changed,
+            report.failed
+        );
+        Ok(report)
+    }
+
+    /// Compares the replays as they finish, until at most `max_in_flight` are left
+    async fn join(
+        &self,
+        tasks: &mut JoinSet<(Recorded, Result<PredictionBatch, String>)>,
+        max_in_flight: usize,
+        report: &mut ReplayReport,
+    ) -> Result<(), String> {
+        while tasks.len() > max_in_flight {
+            let Some(joined) = tasks.join_next().await else {
+                break;
+            };
+            let (recorded, result) = joined.map_err(|e| format!("replay task failed: {}", e))?;
+            self.compare(recorded, result, report);
+        }
+        Ok(())
+    }
+
+    fn apply_settings(&self, settings: Option<InferenceSettings>) -> Result<(), String> {
+        let Some(settings) = settings else {
+            return Err("no settings recorded".to_string());
+        };
+        self.settings
+            .update(
+                UpdateSettingsRequest {
+                    min_probability: Some(settings.min_probability),
+                    nms_iou_threshold: Some(settings.nms_iou_threshold),
+                    class_filter: settings.class_filter,
+                    ..Default::default()
+                },
+                "replay",
+            )
+            .map(|_| ())
+            .map_err(|e| e.to_string())
+    }
+
+    /// Post-processes like the gRPC service, from the configured zones recorded with
+    /// the request and the override it carried
+    async fn replay(&self, request: &RecordedRequest) -> Result<PredictionBatch, String> {
+        let frame = request
+            .frame
+            .clone()
+            .ok_or_else(|| "no frame recorded".to_string())?;
+        let mut region_filter = match &request.region_filter {
+            Some(region_filter) => {
+                RegionFilter::from_proto(region_filter).map_err(|e| e.to_string())?
+            }
+            None => RegionFilter::default(),
+        };
+        if let Some(request_filter) = &frame.region_filter {
+            region_filter = region_filter
+                .with_request(request_filter)
+                .map_err(|e| e.to_string())?;
+        }
+
+        let extras = frame.extras;
+        let mut batch = self
+            .model_service
+            .predict(frame, Deadline::default())
+            .await
+            .map_err(|e| e.to_string())?;
+        batch.detections =
+            region_filter.apply(batch.detections, batch.image_width, batch.image_height);
+        batch.detections = self.settings.class_remapper().apply(batch.detections);
+        select_scores(&mut batch.detections, extras.as_ref());
+
+        Ok(batch)
+    }
+
+    fn compare(
+        &self,
+        recorded: Recorded,
+        result: Result<PredictionBatch, String>,
+        report: &mut ReplayReport,
+    ) {
+        let received_at = format_timestamp(recorded.request.received_at);
+        let replayed = match result {
+            Ok(replayed) => replayed,
+            Err(e) => {
+                tracing::warn!(
+                    "Failed to replay the request received at {}: {}",
+                    received_at,
+                    e
+                );
+                report.failed += 1;
+                return;
+            }
+        };
+        report.replayed += 1;
+
+        let recorded_batch = recorded.request.batch.unwrap_or_default();
+        if identical(&recorded_batch.detections, &replayed.detections) {
+            report.identical += 1;
+            return;
+        }
+        report.changed += 1;
+
+        let agreement = Agreement::new(
+            &recorded_batch.detections,
+            &replayed.detections,
+            self.options.iou_threshold,
+        );
+        let difference = ReplayDifference {
+            received_at,
+            archive: recorded.archive,
+            recorded: recorded_batch.detections.len(),
+            replayed: replayed.detections.len(),
+            agreement,
+        };
+
+        if let Some(diff_dir) = &self.options.diff_dir {
+            let name = format!(
+                "{}_{}",
+                recorded.request.received_at,
+                report.differences.len()
+            );
+            let image_data = recorded.request.frame.unwrap_or_default().image_data;
+            let labels = &self.settings.current().labels;
+            let dump = Dump {
+                agreement: &difference.agreement,
+                recorded: PredictionResponse::new(recorded_batch, labels),
+                replayed: PredictionResponse::new(replayed, labels),
+            };
+            if let Err(e) = write_dump(diff_dir, &name, &image_data, &dump) {
+                tracing::warn!("Failed to write the difference to {:?}: {}", diff_dir, e);
+            }
+        }
+        report.differences.push(difference);
+    }
+}
+
+/// Writes the image as it was received and a JSON file with both results
+fn write_dump(diff_dir: &Path, name: &str, image_data: &[u8], dump: &Dump) -> std::io::Result<()> {
+    let extension = image::guess_format(image_data)
+        .ok()
+        .and_then(|format| format.extensions_str().first().copied())
+        .unwrap_or("bin");
+
+    std::fs::create_dir_all(diff_dir)?;
+    std::fs::write(diff_dir.join(format!("{}.{}", name, extension)), image_data)?;
+    std::fs::write(
+        diff_dir.join(format!("{}.json", name)),
+        serde_json::to_vec_pretty(dump).unwrap(),
+    )
+}
+
+/// Same boxes in the same order, up to floating point noise between runs
+fn identical(recorded: &[BoundingBox], replayed: &[BoundingBox]) -> bool {
+    recorded.len() == replayed.len()
+        && recorded.iter().zip(replayed).all(|(box1, box2)| {
+            box1.class_id == box2.class_id
+                && [
+                    (box1.x1, box2.x1),
+                    (box1.y1, box2.y1),
+                    (box1.x2, box2.x2),
+                    (box1.y2, box2.y2),
+                    (box1.confidence, box2.confidence),
+                ]
+                .iter()
+                .all(|(value1, value2)| (value1 - value2).abs() <= TOLERANCE)
+        })
+}
+
+/// Days since 1970-01-01 of a date in the proleptic Gregorian calendar
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * (month + if month > 2 { -3 } else { 9 }) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+/// Inverse of `days_from_civil`
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days - era * 146097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+/// Unix milliseconds, or an RFC 3339 UTC time such as `2024-05-01T12:00:00.250Z`
+fn parse_timestamp(value: &str) -> Result<i64, String> {
+    if let Ok(millis) = value.parse() {
+        return Ok(millis);
+    }
+
+    let invalid = || {
+        format!(
+            "invalid time {}, expected unix milliseconds or YYYY-MM-DDTHH:MM:SS[.fff]Z",
+            value
+        )
+    };
+    let (date, time) = value
+        .strip_suffix('Z')
+        .and_then(|value| value.split_once(['T', ' ']))
+        .ok_or_else(invalid)?;
+    let (time, fraction) = time.split_once('.').unwrap_or((time, ""));
+    let numbers = |value: &str, separator| {
+        value
+            .split(separator)
+            .map(|number| number.parse::<i64>().ok())
+            .collect::<Option<Vec<_>>>()
+            .filter(|numbers| numbers.len() == 3)
+            .ok_or_else(invalid)
+    };
+    let (date, time) = (numbers(date, '-')?, numbers(time, ':')?);
+    if date.iter().chain(&time).any(|number| *number < 0)
+        || !(1..=12).contains(&date[1])
+        || !(1..=31).contains(&date[2])
+        || time[0] > 23
+        || time[1] > 59
+        || time[2] > 60
+        || fraction.len() > 9
+        || !fraction.chars().all(|c| c.is_ascii_digit())
+    {
+        return Err(invalid());
+    }
+
+    let millis = format!("{:0<3}", fraction)[..3].parse::<i64>().unwrap();
+    let seconds = days_from_civil(date[0], date[1], date[2]) * 86400
+        + time[0] * 3600
+        + time[1] * 60
+        + time[2];
+    Ok(seconds * 1000 + millis)
+}
+
+fn format_timestamp(millis: i64) -> String {
+    let seconds = millis.div_euclid(1000);
+    let (year, month, day) = civil_from_days(seconds.div_euclid(86400));
+    let time = seconds.rem_euclid(86400);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        time / 3600,
+        time % 3600 / 60,
+        time % 60,
+        millis.rem_euclid(1000)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_timestamps() {
+        assert_eq!(parse_timestamp("1714564800000").unwrap(), 1714564800000);
+        assert_eq!(
+            parse_timestamp("2024-05-01T12:00:00Z").unwrap(),
+            1714564800000
+        );
+        assert_eq!(
+            parse_timestamp("2024-02-29T23:59:59.25Z").unwrap(),
+            1709251199250
+        );
+        assert_eq!(parse_timestamp("1970-01-01T00:00:00Z").unwrap(), 0);
+        assert!(parse_timestamp("2024-05-01T12:00:00").is_err());
+        assert!(parse_timestamp("2024-13-01T12:00:00Z").is_err());
+        assert!(parse_timestamp("2024-05-01T-1:00:00Z").is_err());
+        assert!(parse_timestamp("2024-05-01T12:-5:00Z").is_err());
+        assert!(parse_timestamp("yesterday").is_err());
+
+        assert_eq!(format_timestamp(1709251199250), "2024-02-29T23:59:59.250Z");
+        assert_eq!(format_timestamp(0), "1970-01-01T00:00:00.000Z");
+        for millis in [-86_400_001, 951_782_400_000, 4_102_444_800_123] {
+            assert_eq!(parse_timestamp(&format_timestamp(millis)).unwrap(), millis);
+        }
+    }
+
+    #[test]
+    fn test_identical() {
+        let bbox = |x1, class_id| BoundingBox {
+            x1,
+            y1: 0.,
+            x2: x1 + 10.,
+            y2: 10.,
+            class_id,
+            confidence: 0.9,
+            ..Default::default()
+        };
+
+        assert!(identical(&[bbox(0., 0)], &[bbox(0.0001, 0)]));
+        assert!(!identical(&[bbox(0., 0)], &[bbox(0., 1)]));
+        assert!(!identical(&[bbox(0., 0)], &[bbox(1., 0)]));
+        assert!(!identical(&[bbox(0., 0)], &[]));
+    }
+}
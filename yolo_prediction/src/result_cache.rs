@@ -0,0 +1,215 @@
+use crate::{config::CacheConfig, telemetry::Metrics};
+use hashlink::LruCache;
+use prost::Message;
+use std::{
+    hash::{BuildHasher, Hash, Hasher, RandomState},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tonic::metadata::MetadataMap;
+use yolo_proto::{ImageFrame, PredictionBatch};
+
+/// Identifies a frame by a hash of its bytes and of the request options that
+/// change the result.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct CacheKey {
+    hash: u64,
+    len: usize,
+}
+
+#[derive(Debug)]
+struct Entry {
+    batch: PredictionBatch,
+    expires_at: Option<Instant>,
+}
+
+#[derive(Debug)]
+struct Entries {
+    lru: LruCache<CacheKey, Entry>,
+    /// Settings version the results were computed with
+    settings_version: u64,
+}
+
+/// LRU cache of the results of byte-identical frames, such as a static scene
+/// sent over and over or an upload retried by a client.
+///
+/// Results are only served for the settings version they were computed with, a
+/// settings update clears the cache. The model and labels are loaded at startup,
+/// so a new model always starts with an empty cache.
+#[derive(Debug)]
+pub struct ResultCache {
+    // `None` when disabled
+    entries: Option<Mutex<Entries>>,
+    ttl: Option<Duration>,
+    // Keyed per process, so clients cannot craft colliding frames
+    hasher: RandomState,
+    metrics: Arc<Metrics>,
+}
+
+impl ResultCache {
+    pub fn new(cache_cfg: &CacheConfig, metrics: Arc<Metrics>) -> Self {
+        Self {
+            entries: (cache_cfg.max_entries > 0).then(|| {
+                Mutex::new(Entries {
+                    lru: LruCache::new(cache_cfg.max_entries),
+                    settings_version: 0,
+                })
+            }),
+            ttl: (cache_cfg.ttl_secs > 0).then(|| Duration::from_secs(cache_cfg.ttl_secs)),
+            hasher: RandomState::new(),
+            metrics,
+        }
+    }
+
+    /// `None` when the cache is disabled or the client sent `cache-control: no-store`
+    /// or `no-cache`, in which case the frame is neither looked up nor stored.
+    pub fn key(&self, frame: &ImageFrame, metadata: &MetadataMap) -> Option<CacheKey> {
+        if self.entries.is_none() || skips_cache(metadata) {
+            return None;
+        }
+
+        let mut hasher = self.hasher.build_hasher();
+        frame.image_data.hash(&mut hasher);
+        frame
+            .region_filter
+            .as_ref()
+            .map(Message::encode_to_vec)
+            .hash(&mut hasher);
+        frame
+            .extras
+            .as_ref()
+            .map(Message::encode_to_vec)
+            .hash(&mut hasher);
+        Some(CacheKey {
+            hash: hasher.finish(),
+            len: frame.image_data.len(),
+        })
+    }
+
+    /// Returns the cached result and records the lookup. Results computed with
+    /// other settings are dropped.
+    pub fn get(&self, key: CacheKey, settings_version: u64) -> Option<PredictionBatch> {
+        let mut entries = self.entries.as_ref()?.lock().unwrap();
+        if entries.settings_version != settings_version {
+            entries.lru.clear();
+            entries.settings_version = settings_version;
+        }
+
+        let now = Instant::now();
+        let batch = match entries.lru.get(&key) {
+            Some(entry) if entry.expires_at.is_none_or(|expires_at| now < expires_at) => {
+                Some(entry.batch.clone())
+            }
+            Some(_) => {
+                entries.lru.remove(&key);
+                None
+            }
+            None => None,
+        };
+        self.metrics.record_cache_lookup(batch.is_some());
+        batch
+    }
+
+    /// Stores a result computed with `settings_version`, unless the settings
+    /// changed in the meantime.
+    pub fn insert(&self, key: CacheKey, settings_version: u64, batch: &PredictionBatch) {
+        let Some(entries) = &self.entries else {
+            return;
+        };
+        let mut entries = entries.lock().unwrap();
+        if entries.settings_version != settings_version {
+            return;
+        }
+        entries.lru.insert(
+            key,
+            Entry {
+                batch: batch.clone(),
+                expires_at: self.ttl.map(|ttl| Instant::now() + ttl),
+            },
+        );
+    }
+}
+
+fn skips_cache(metadata: &MetadataMap) -> bool {
+    metadata
+        .get_all("cache-control")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|directive| {
+            let directive = directive.trim();
+            directive.eq_ignore_ascii_case("no-store") || directive.eq_ignore_ascii_case("no-cache")
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use yolo_proto::RegionFilter;
+
+    fn frame(image_data: &[u8]) -> ImageFrame {
+        ImageFrame {
+            image_data: image_data.to_vec(),
+            ..Default::default()
+        }
+    }
+
+    fn batch(image_width: u32) -> PredictionBatch {
+        PredictionBatch {
+            image_width,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_result_cache() {
+        let cache_cfg = CacheConfig {
+            max_entries: 2,
+            ttl_secs: 0,
+        };
+        let cache = ResultCache::new(&cache_cfg, Arc::new(Metrics::new("test")));
+        let metadata = MetadataMap::new();
+
+        let a = cache.key(&frame(b"a"), &metadata).unwrap();
+        let b = cache.key(&frame(b"b"), &metadata).unwrap();
+        let c = cache.key(&frame(b"c"), &metadata).unwrap();
+        assert_eq!(cache.key(&frame(b"a"), &metadata), Some(a));
+        assert_ne!(a, b);
+        // Request options are part of the key
+        let with_regions = ImageFrame {
+            region_filter: Some(RegionFilter::default()),
+            ..frame(b"a")
+        };
+        assert_ne!(cache.key(&with_regions, &metadata), Some(a));
+
+        assert_eq!(cache.get(a, 1), None);
+        cache.insert(a, 1, &batch(1));
+        cache.insert(b, 1, &batch(2));
+        assert_eq!(cache.get(a, 1), Some(batch(1)));
+        // `b` is the least recently used
+        cache.insert(c, 1, &batch(3));
+        assert_eq!(cache.get(b, 1), None);
+        assert_eq!(cache.get(a, 1), Some(batch(1)));
+
+        // A settings update invalidates everything, late results are not stored
+        assert_eq!(cache.get(a, 2), None);
+        cache.insert(c, 1, &batch(3));
+        assert_eq!(cache.get(c, 2), None);
+    }
+
+    #[test]
+    fn test_result_cache_skipped() {
+        let metrics = Arc::new(Metrics::new("test"));
+        let disabled = ResultCache::new(&CacheConfig::default(), metrics.clone());
+        assert_eq!(disabled.key(&frame(b"a"), &MetadataMap::new()), None);
+
+        let cache_cfg = CacheConfig {
+            max_entries: 10,
+            ttl_secs: 60,
+        };
+        let cache = ResultCache::new(&cache_cfg, metrics);
+        let mut metadata = MetadataMap::new();
+        metadata.insert("cache-control", "max-age=0, No-Store".parse().unwrap());
+        assert_eq!(cache.key(&frame(b"a"), &metadata), None);
+    }
+}
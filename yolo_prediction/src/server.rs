@@ -1,62 +1,278 @@
+#[cfg(feature = "ort")]
+use crate::ort_service::OrtModelService;
+#[cfg(feature = "tract")]
+use crate::tract_service::TractModelService;
 use crate::{
-    config::Config,
+    auth::{load_api_keys, AuthInterceptor},
+    config::{Backend, Config, ModelConfig, TlsConfig},
+    http_gateway::HttpGateway,
     inference_service::InferenceService,
     model_service::ModelService,
-    ort_service::OrtModelService,
+    rate_limit::RateLimiter,
+    recorder::Recorder,
+    regions::RegionFilter,
+    result_cache::ResultCache,
+    settings::LogFilterHandle,
+    shadow::{self, ShadowModel},
     state::{ServiceState, State},
+    telemetry::{self, Metrics},
+    tls,
 };
-use tokio::signal;
-use tonic::transport::{server::Router, Server};
-use yolo_proto::yolo_service_server::YoloServiceServer;
+use std::{
+    io,
+    os::unix::fs::FileTypeExt,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+use tokio::{
+    net::{TcpListener, UnixListener},
+    signal,
+};
+use tokio_stream::wrappers::UnixListenerStream;
+use tonic::{
+    service::{interceptor::InterceptedService, Routes},
+    transport::Server,
+};
+use yolo_proto::{yolo_service_server::YoloServiceServer, ColorLabel};
 
 pub struct GrpcServer {
-    router: Router,
+    routes: Routes,
     addr: String,
+    tls: Option<TlsConfig>,
+    unix_socket: Option<PathBuf>,
+    http_gateway: Option<HttpGateway>,
 }
 
 impl GrpcServer {
-    pub fn new(model_service: impl ModelService, service_state: impl State, addr: &str) -> Self {
-        let inference_service = InferenceService::new(model_service, service_state).unwrap();
+    pub fn new<M: ModelService>(
+        model_service: M,
+        shadow: Option<ShadowModel<M>>,
+        service_state: impl State,
+        metrics: Arc<Metrics>,
+        config: &Config,
+    ) -> Self {
+        let region_filter = RegionFilter::new(&config.regions).expect("invalid regions config");
+        let rate_limiter = RateLimiter::new(&config.rate_limit, metrics.clone());
+        let result_cache = ResultCache::new(&config.cache, metrics.clone());
+        let inference_service = Arc::new(
+            InferenceService::new(
+                model_service,
+                service_state,
+                region_filter,
+                rate_limiter,
+                result_cache,
+                shadow,
+                metrics,
+            )
+            .unwrap(),
+        );
         let reflection_service = tonic_reflection::server::Builder::configure()
             .register_encoded_file_descriptor_set(yolo_proto::FILE_DESCRIPTOR_SET)
             .build_v1alpha()
             .unwrap();
         let (_, health_service) = tonic_health::server::health_reporter();
 
-        let router = Server::builder()
-            .add_service(YoloServiceServer::new(inference_service))
+        let api_keys = config
+            .auth
+            .as_ref()
+            .map(load_api_keys)
+            .transpose()
+            .expect("invalid auth config");
+        // Health checks and reflection stay open, only the Yolo service is authenticated
+        let auth_interceptor = AuthInterceptor::new(api_keys);
+
+        let yolo_service = InterceptedService::new(
+            YoloServiceServer::from_arc(inference_service.clone())
+                .max_decoding_message_size(config.image.max_message_bytes(&config.regions)),
+            auth_interceptor.clone(),
+        );
+
+        let http_gateway = config.server.http.as_ref().map(|http_cfg| {
+            HttpGateway::new(
+                inference_service,
+                yolo_service.clone(),
+                auth_interceptor,
+                http_cfg,
+                config,
+            )
+            .expect("invalid HTTP gateway config")
+        });
+
+        let routes = Routes::new(yolo_service)
             .add_service(reflection_service)
             .add_service(health_service);
 
         Self {
-            router,
-            addr: addr.to_string(),
+            routes,
+            addr: config.server.get_address(),
+            tls: config.server.tls.clone(),
+            unix_socket: config.server.unix_socket.clone(),
+            http_gateway,
         }
     }
 
     pub async fn run(self) -> Result<(), Box<dyn std::error::Error>> {
-        let addr = self.addr.parse().expect("failed to parse address");
-
-        tracing::info!("Inference service listening on {}", self.addr);
+        let addr: std::net::SocketAddr = self.addr.parse().expect("failed to parse address");
 
         let shutdown = async {
             shutdown_signal().await;
             tracing::info!("Shutdown signal received, starting graceful shutdown")
         };
 
-        self.router.serve_with_shutdown(addr, shutdown).await?;
+        let http_gateway = async {
+            match self.http_gateway {
+                Some(http_gateway) => http_gateway.run(shutdown_signal()).await,
+                None => Ok(()),
+            }
+        };
+
+        // Served next to TCP, which stays available for health checks and remote clients
+        let unix_server = async {
+            let Some(path) = &self.unix_socket else {
+                return Ok(());
+            };
+            let listener = match bind_unix_socket(path) {
+                Ok(listener) => listener,
+                Err(e) => {
+                    tracing::warn!(
+                        "Failed to bind Unix socket {:?}, serving TCP only: {}",
+                        path,
+                        e
+                    );
+                    return Ok(());
+                }
+            };
+
+            tracing::info!("Inference service listening on unix://{}", path.display());
+            Server::builder()
+                .add_routes(self.routes.clone())
+                .serve_with_incoming_shutdown(UnixListenerStream::new(listener), shutdown_signal())
+                .await?;
+            let _ = std::fs::remove_file(path);
+            Ok::<(), Box<dyn std::error::Error>>(())
+        };
+
+        let grpc_server = async {
+            let router = Server::builder().add_routes(self.routes.clone());
+            match self.tls {
+                Some(tls_cfg) => {
+                    let mutual = tls_cfg.client_ca_file.is_some();
+                    let listener = TcpListener::bind(addr).await?;
+                    let incoming = tls::incoming(listener, tls_cfg)?;
+
+                    tracing::info!(
+                        "Inference service listening on {} (TLS, client auth: {})",
+                        self.addr,
+                        mutual
+                    );
+                    router
+                        .serve_with_incoming_shutdown(incoming, shutdown)
+                        .await?;
+                }
+                None => {
+                    tracing::info!("Inference service listening on {}", self.addr);
+                    router.serve_with_shutdown(addr, shutdown).await?;
+                }
+            }
+            Ok(())
+        };
+
+        tokio::try_join!(grpc_server, unix_server, http_gateway)?;
         Ok(())
     }
 }
 
-pub async fn start_server(config: Config) -> Result<(), Box<dyn std::error::Error>> {
-    let ort_model_service =
-        OrtModelService::new(&config.model).expect("failed to instantiate ort model service");
-    let service_state = ServiceState::new(&config.labels).unwrap();
+/// Loads the shadow model, when configured, with the same backend as the primary.
+/// It records to its own metrics, labeled with its name, and shares the primary's
+/// labels, so a model predicting other classes is refused.
+fn load_shadow<M: ModelService>(
+    config: &Config,
+    labels: &[ColorLabel],
+    new_model_service: impl FnOnce(&ModelConfig, Arc<Metrics>) -> Result<M, Box<dyn std::error::Error>>,
+) -> Result<Option<ShadowModel<M>>, String> {
+    let Some(shadow_cfg) = config.shadow.as_ref() else {
+        return Ok(None);
+    };
+    let model_cfg = shadow_cfg.model_config(&config.model);
+    shadow::check_model(&model_cfg, labels)
+        .map_err(|e| format!("shadow model {}: {}", model_cfg.get_name(), e))?;
+
+    let metrics = Arc::new(Metrics::new(&model_cfg.get_name()));
+    let model_service = new_model_service(&model_cfg, metrics.clone())
+        .map_err(|e| format!("failed to instantiate shadow model service: {}", e))?;
+
+    tracing::info!(
+        "Mirroring {:.0}% of the predictions to shadow model {}",
+        shadow_cfg.sample_ratio * 100.,
+        model_cfg.get_name()
+    );
+    Ok(Some(ShadowModel::new(model_service, shadow_cfg, metrics)))
+}
+
+/// Binds the socket, replacing the one left behind by a previous run. Anything
+/// else at that path is left alone.
+fn bind_unix_socket(path: &Path) -> io::Result<UnixListener> {
+    match std::fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => std::fs::remove_file(path)?,
+        Ok(_) => {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                "path exists and is not a socket",
+            ))
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e),
+    }
+    UnixListener::bind(path)
+}
+
+pub async fn start_server(
+    config: Config,
+    log_filter: LogFilterHandle,
+) -> Result<(), Box<dyn std::error::Error>> {
+    telemetry::init_metrics(&config.metrics)?;
+    let metrics = Arc::new(Metrics::new(&config.model.get_name()));
+
+    let service_state = ServiceState::new(config.labels.as_ref(), &config.model)?;
+    let settings = service_state.get_settings().clone();
+    settings.set_log_filter(log_filter);
+    if let Some(recorder_cfg) = &config.recorder {
+        let recorder = Recorder::new(recorder_cfg, &config.model.get_name(), metrics.clone())?;
+        settings.set_recorder(Arc::new(recorder));
+    }
 
-    let addr = config.server.get_address();
-    let grpc_server = GrpcServer::new(ort_model_service, service_state, &addr);
-    tracing::info!("Listening on {}", &addr);
+    tracing::info!("Using {} inference backend", config.model.backend.as_str());
+    let grpc_server = match config.model.backend {
+        #[cfg(feature = "ort")]
+        Backend::Ort => {
+            let shadow = load_shadow(&config, settings.source_labels(), |model_cfg, metrics| {
+                OrtModelService::new(model_cfg, &config.image, settings.clone(), metrics)
+            })?;
+            let model_service =
+                OrtModelService::new(&config.model, &config.image, settings, metrics.clone())
+                    .expect("failed to instantiate ort model service");
+            GrpcServer::new(model_service, shadow, service_state, metrics, &config)
+        }
+        #[cfg(feature = "tract")]
+        Backend::Tract => {
+            let shadow = load_shadow(&config, settings.source_labels(), |model_cfg, metrics| {
+                TractModelService::new(model_cfg, &config.image, settings.clone(), metrics)
+            })?;
+            let model_service =
+                TractModelService::new(&config.model, &config.image, settings, metrics.clone())
+                    .expect("failed to instantiate tract model service");
+            GrpcServer::new(model_service, shadow, service_state, metrics, &config)
+        }
+        #[allow(unreachable_patterns)]
+        backend => {
+            return Err(format!(
+                "The {} backend is not compiled in, rebuild with `--features {}`",
+                backend.as_str(),
+                backend.as_str()
+            )
+            .into())
+        }
+    };
 
     grpc_server.run().await?;
 
@@ -82,3 +298,26 @@ async fn shutdown_signal() {
         _ = terminate => {},
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_bind_unix_socket() {
+        let dir = std::env::temp_dir();
+        let path = dir.join("yolo_prediction_test.sock");
+        let _ = std::fs::remove_file(&path);
+
+        // A stale socket from a previous run is replaced
+        drop(bind_unix_socket(&path).unwrap());
+        assert!(bind_unix_socket(&path).is_ok());
+
+        let file = dir.join("yolo_prediction_test_not_a_socket");
+        std::fs::write(&file, b"").unwrap();
+        assert_eq!(
+            bind_unix_socket(&file).unwrap_err().kind(),
+            io::ErrorKind::AlreadyExists
+        );
+    }
+}
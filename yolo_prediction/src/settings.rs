@@ -0,0 +1,407 @@
+use crate::{
+    class_filter::ClassFilter,
+    class_remap::ClassRemapper,
+    config::{ClassFilterConfig, ModelConfig},
+    error::ServiceError,
+    recorder::Recorder,
+};
+use std::sync::{Arc, Mutex, OnceLock, RwLock};
+use tracing_subscriber::{reload, EnvFilter, Registry};
+use yolo_proto::{ClassFilterSettings, ColorLabel, InferenceSettings, UpdateSettingsRequest};
+
+/// Handle on the log filter installed in `main`, used to change the log level at runtime
+pub type LogFilterHandle = reload::Handle<EnvFilter, Registry>;
+
+/// Settings used by a prediction. Requests read the snapshot once, so a concurrent
+/// update never mixes old and new values within a frame.
+#[derive(Debug)]
+pub struct SettingsSnapshot {
+    pub min_probability: f32,
+    pub nms_iou_threshold: f32,
+    pub class_filter_cfg: ClassFilterConfig,
+    pub class_filter: ClassFilter,
+    /// Remapped labels annotated with the resolved thresholds and enabled flags
+    pub labels: Vec<ColorLabel>,
+    pub version: u64,
+}
+
+/// Inference settings that can be changed through the admin RPCs without a restart.
+///
+/// An update builds and validates a complete snapshot before swapping it in, a
+/// rejected update leaves everything untouched.
+#[derive(Debug)]
+pub struct LiveSettings {
+    current: RwLock<Arc<SettingsSnapshot>>,
+    // Serializes updates, readers only wait for the pointer swap
+    update_lock: Mutex<()>,
+    source_labels: Vec<ColorLabel>,
+    class_remapper: ClassRemapper,
+    log_filter: OnceLock<LogFilterHandle>,
+    recorder: OnceLock<Arc<Recorder>>,
+}
+
+impl LiveSettings {
+    /// `source_labels` are indexed by the class ids predicted by the model.
+    pub fn new(model_cfg: &ModelConfig, source_labels: Vec<ColorLabel>) -> Result<Self, String> {
+        let class_remapper = ClassRemapper::new(&model_cfg.class_remap, &source_labels)?;
+        let snapshot = build_snapshot(
+            &source_labels,
+            &class_remapper,
+            model_cfg.min_probability,
+            model_cfg.nms_iou_threshold,
+            model_cfg.class_filter.clone(),
+            1,
+        )?;
+
+        Ok(Self {
+            current: RwLock::new(Arc::new(snapshot)),
+            update_lock: Mutex::new(()),
+            source_labels,
+            class_remapper,
+            log_filter: OnceLock::new(),
+            recorder: OnceLock::new(),
+        })
+    }
+
+    /// Enables log level updates, only the first handle is kept.
+    pub fn set_log_filter(&self, log_filter: LogFilterHandle) {
+        let _ = self.log_filter.set(log_filter);
+    }
+
+    /// Enables starting and stopping the recorder, only the first one is kept.
+    pub fn set_recorder(&self, recorder: Arc<Recorder>) {
+        let _ = self.recorder.set(recorder);
+    }
+
+    pub fn recorder(&self) -> Option<&Arc<Recorder>> {
+        self.recorder.get()
+    }
+
+    pub fn current(&self) -> Arc<SettingsSnapshot> {
+        self.current.read().unwrap().clone()
+    }
+
+    /// Labels indexed by the class ids predicted by the model, before remapping
+    pub fn source_labels(&self) -> &[ColorLabel] {
+        &self.source_labels
+    }
+
+    pub fn class_remapper(&self) -> &ClassRemapper {
+        &self.class_remapper
+    }
+
+    pub fn get(&self) -> InferenceSettings {
+        self.to_proto(&self.current())
+    }
+
+    /// Applies the fields set in the request and logs what changed. Errors are
+    /// `INVALID_ARGUMENT`, or `ABORTED` when `expected_version` is stale.
+    pub fn update(
+        &self,
+        request: UpdateSettingsRequest,
+        changed_by: &str,
+    ) -> Result<InferenceSettings, ServiceError> {
+        let _guard = self.update_lock.lock().unwrap();
+        let current = self.current();
+
+        if request.expected_version != 0 && request.expected_version != current.version {
+            return Err(ServiceError::VersionMismatch {
+                current: current.version,
+                expected: request.expected_version,
+            });
+        }
+
+        let snapshot = build_snapshot(
+            &self.source_labels,
+            &self.class_remapper,
+            request.min_probability.unwrap_or(current.min_probability),
+            request
+                .nms_iou_threshold
+                .unwrap_or(current.nms_iou_threshold),
+            request
+                .class_filter
+                .map(class_filter_config)
+                .unwrap_or_else(|| current.class_filter_cfg.clone()),
+            current.version + 1,
+        )
+        .map_err(ServiceError::InvalidSettings)?;
+
+        let log_filter = match &request.log_level {
+            Some(directives) => {
+                let handle = self
+                    .log_filter
+                    .get()
+                    .ok_or(ServiceError::LogLevelReadOnly)?;
+                let filter = EnvFilter::try_new(directives).map_err(|e| {
+                    ServiceError::InvalidSettings(format!(
+                        "invalid log level {}: {}",
+                        directives, e
+                    ))
+                })?;
+                Some((handle, filter))
+            }
+            None => None,
+        };
+        let recorder = match request.recording {
+            Some(recording) => Some((
+                self.recorder
+                    .get()
+                    .ok_or(ServiceError::RecorderNotConfigured)?,
+                recording,
+            )),
+            None => None,
+        };
+
+        let mut changes = Vec::new();
+        if snapshot.min_probability != current.min_probability {
+            changes.push(format!(
+                "min_probability: {} -> {}",
+                current.min_probability, snapshot.min_probability
+            ));
+        }
+        if snapshot.nms_iou_threshold != current.nms_iou_threshold {
+            changes.push(format!(
+                "nms_iou_threshold: {} -> {}",
+                current.nms_iou_threshold, snapshot.nms_iou_threshold
+            ));
+        }
+        let old_class_filter = describe_class_filter(&current.class_filter_cfg);
+        let new_class_filter = describe_class_filter(&snapshot.class_filter_cfg);
+        if new_class_filter != old_class_filter {
+            changes.push(format!(
+                "class_filter: {} -> {}",
+                old_class_filter, new_class_filter
+            ));
+        }
+
+        // The reload is the last step that can fail, so both changes land or neither does
+        if let Some((handle, filter)) = log_filter {
+            let old_log_level = log_level(handle);
+            handle.reload(filter).map_err(|e| {
+                ServiceError::Internal(format!("failed to reload the log filter: {}", e))
+            })?;
+            changes.push(format!(
+                "log_level: {} -> {}",
+                old_log_level,
+                log_level(handle)
+            ));
+        }
+
+        if let Some((recorder, recording)) = recorder {
+            if recorder.is_enabled() != recording {
+                changes.push(format!(
+                    "recording: {} -> {}",
+                    recorder.is_enabled(),
+                    recording
+                ));
+            }
+            recorder.set_enabled(recording);
+        }
+
+        let snapshot = Arc::new(snapshot);
+        *self.current.write().unwrap() = snapshot.clone();
+
+        // Logged as a warning so the history is kept when the log level is raised
+        tracing::warn!(
+            version = snapshot.version,
+            changed_by,
+            "Inference settings updated: {}",
+            if changes.is_empty() {
+                "no changes".to_string()
+            } else {
+                changes.join(", ")
+            }
+        );
+
+        Ok(self.to_proto(&snapshot))
+    }
+
+    fn to_proto(&self, snapshot: &SettingsSnapshot) -> InferenceSettings {
+        InferenceSettings {
+            min_probability: snapshot.min_probability,
+            nms_iou_threshold: snapshot.nms_iou_threshold,
+            class_filter: Some(ClassFilterSettings {
+                thresholds: snapshot.class_filter_cfg.thresholds.clone(),
+                allow: snapshot.class_filter_cfg.allow.clone(),
+                deny: snapshot.class_filter_cfg.deny.clone(),
+            }),
+            log_level: self.log_filter.get().map(log_level).unwrap_or_default(),
+            version: snapshot.version,
+            recording: self
+                .recorder
+                .get()
+                .is_some_and(|recorder| recorder.is_enabled()),
+        }
+    }
+}
+
+fn build_snapshot(
+    source_labels: &[ColorLabel],
+    class_remapper: &ClassRemapper,
+    min_probability: f32,
+    nms_iou_threshold: f32,
+    class_filter_cfg: ClassFilterConfig,
+    version: u64,
+) -> Result<SettingsSnapshot, String> {
+    if !(0.0..=1.0).contains(&min_probability) {
+        return Err(format!(
+            "Invalid min_probability {}: must be between 0 and 1",
+            min_probability
+        ));
+    }
+    if !(0.0..=1.0).contains(&nms_iou_threshold) {
+        return Err(format!(
+            "Invalid nms_iou_threshold {}: must be between 0 and 1",
+            nms_iou_threshold
+        ));
+    }
+
+    let class_filter = ClassFilter::new(&class_filter_cfg, min_probability, source_labels)?;
+    let labels = class_remapper.annotate_labels(&class_filter);
+
+    Ok(SettingsSnapshot {
+        min_probability,
+        nms_iou_threshold,
+        class_filter_cfg,
+        class_filter,
+        labels,
+        version,
+    })
+}
+
+fn class_filter_config(settings: ClassFilterSettings) -> ClassFilterConfig {
+    ClassFilterConfig {
+        thresholds: settings.thresholds,
+        allow: settings.allow,
+        deny: settings.deny,
+    }
+}
+
+/// Stable rendering for the change log, the thresholds are sorted by label
+fn describe_class_filter(class_filter_cfg: &ClassFilterConfig) -> String {
+    let mut thresholds: Vec<_> = class_filter_cfg.thresholds.iter().collect();
+    thresholds.sort_by(|a, b| a.0.cmp(b.0));
+    format!(
+        "{{thresholds: {:?}, allow: {:?}, deny: {:?}}}",
+        thresholds, class_filter_cfg.allow, class_filter_cfg.deny
+    )
+}
+
+fn log_level(handle: &LogFilterHandle) -> String {
+    handle
+        .with_current(|filter| filter.to_string())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{Backend, ClassRemapConfig, LabelPalette};
+    use std::{collections::HashMap, path::PathBuf};
+    use tonic::Code;
+
+    fn live_settings() -> LiveSettings {
+        let model_cfg = ModelConfig {
+            onnx_file: "dummy_model.onnx".to_string(),
+            num_instances: 1,
+            model_dir: PathBuf::from("./dummy_model_dir"),
+            min_probability: 0.5,
+            nms_iou_threshold: 0.7,
+            class_filter: ClassFilterConfig::default(),
+            class_remap: ClassRemapConfig::default(),
+            backend: Backend::default(),
+            label_palette: LabelPalette::default(),
+        };
+        let labels = ["person", "car"]
+            .iter()
+            .map(|name| ColorLabel {
+                label: name.to_string(),
+                enabled: true,
+                ..Default::default()
+            })
+            .collect();
+        LiveSettings::new(&model_cfg, labels).unwrap()
+    }
+
+    #[test]
+    fn test_update_settings() {
+        let settings = live_settings();
+        let before = settings.current();
+
+        let updated = settings
+            .update(
+                UpdateSettingsRequest {
+                    min_probability: Some(0.3),
+                    class_filter: Some(ClassFilterSettings {
+                        thresholds: HashMap::from([("car".to_string(), 0.8)]),
+                        ..Default::default()
+                    }),
+                    expected_version: 1,
+                    ..Default::default()
+                },
+                "admin",
+            )
+            .unwrap();
+        assert_eq!(updated.version, 2);
+        assert_eq!(updated.nms_iou_threshold, 0.7);
+
+        // Snapshots taken before the update are left as they were
+        assert!(before.class_filter.accepts(1, 0.6));
+        let current = settings.current();
+        assert!(current.class_filter.accepts(0, 0.35));
+        assert!(!current.class_filter.accepts(1, 0.6));
+        assert_eq!(current.labels[0].min_probability, 0.3);
+        assert_eq!(current.labels[1].min_probability, 0.8);
+    }
+
+    #[test]
+    fn test_update_settings_rejected() {
+        let settings = live_settings();
+
+        let update = |request| settings.update(request, "admin").unwrap_err().code();
+        assert_eq!(
+            update(UpdateSettingsRequest {
+                min_probability: Some(0.2),
+                nms_iou_threshold: Some(1.5),
+                ..Default::default()
+            }),
+            Code::InvalidArgument
+        );
+        assert_eq!(
+            update(UpdateSettingsRequest {
+                class_filter: Some(ClassFilterSettings {
+                    deny: vec!["unicorn".to_string()],
+                    ..Default::default()
+                }),
+                ..Default::default()
+            }),
+            Code::InvalidArgument
+        );
+        assert_eq!(
+            update(UpdateSettingsRequest {
+                expected_version: 7,
+                ..Default::default()
+            }),
+            Code::Aborted
+        );
+        // No log filter handle is installed in tests
+        assert_eq!(
+            update(UpdateSettingsRequest {
+                log_level: Some("debug".to_string()),
+                ..Default::default()
+            }),
+            Code::FailedPrecondition
+        );
+        assert_eq!(
+            update(UpdateSettingsRequest {
+                recording: Some(true),
+                ..Default::default()
+            }),
+            Code::FailedPrecondition
+        );
+
+        let current = settings.current();
+        assert_eq!(current.version, 1);
+        assert_eq!(current.min_probability, 0.5);
+    }
+}
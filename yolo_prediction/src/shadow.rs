@@ -0,0 +1,405 @@
+use crate::{
+    config::{ModelConfig, ShadowConfig, Validatable},
+    http_gateway::PredictionResponse,
+    model_service::{Deadline, ModelService},
+    onnx_metadata::OnnxModelInfo,
+    postprocessing::iou,
+    regions::RegionFilter,
+    settings::LiveSettings,
+    telemetry::Metrics,
+};
+use serde::Serialize;
+use std::{
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tracing::Instrument;
+use yolo_proto::{BoundingBox, ColorLabel, ImageFrame, PredictionBatch};
+
+/// How the shadow model's boxes compare with the primary's on one frame.
+#[derive(Debug, Default, PartialEq, Serialize)]
+pub struct Agreement {
+    /// Pairs overlapping more than the IoU threshold, whatever their class
+    pub matched: usize,
+    /// Matched pairs predicting different classes
+    pub class_mismatches: usize,
+    pub primary_only: usize,
+    pub shadow_only: usize,
+}
+
+impl Agreement {
+    /// Pairs boxes greedily, the most overlapping first.
+    pub fn new(primary: &[BoundingBox], shadow: &[BoundingBox], iou_threshold: f32) -> Self {
+        let mut pairs: Vec<(f32, usize, usize)> = primary
+            .iter()
+            .enumerate()
+            .flat_map(|(i, primary_box)| {
+                shadow
+                    .iter()
+                    .enumerate()
+                    .map(move |(j, shadow_box)| (iou(primary_box, shadow_box), i, j))
+            })
+            .filter(|(overlap, _, _)| *overlap >= iou_threshold)
+            .collect();
+        pairs.sort_by(|pair1, pair2| pair2.0.total_cmp(&pair1.0));
+
+        let mut primary_matched = vec![false; primary.len()];
+        let mut shadow_matched = vec![false; shadow.len()];
+        let mut agreement = Self::default();
+        for (_, i, j) in pairs {
+            if primary_matched[i] || shadow_matched[j] {
+                continue;
+            }
+            primary_matched[i] = true;
+            shadow_matched[j] = true;
+            agreement.matched += 1;
+            if primary[i].class_id != shadow[j].class_id {
+                agreement.class_mismatches += 1;
+            }
+        }
+
+        agreement.primary_only = primary.len() - agreement.matched;
+        agreement.shadow_only = shadow.len() - agreement.matched;
+        agreement
+    }
+
+    pub fn agrees(&self) -> bool {
+        self.class_mismatches == 0 && self.primary_only == 0 && self.shadow_only == 0
+    }
+}
+
+/// The shadow model runs with the primary's labels, class filter and remapping, so
+/// it has to predict the same classes in the same order.
+pub fn check_classes(shadow_info: &OnnxModelInfo, labels: &[ColorLabel]) -> Result<(), String> {
+    if let Some(num_classes) = shadow_info.num_classes {
+        if num_classes != labels.len() {
+            return Err(format!(
+                "the shadow model predicts {} classes but the primary has {} labels",
+                num_classes,
+                labels.len()
+            ));
+        }
+    }
+
+    if let Some(class_names) = &shadow_info.class_names {
+        if class_names.len() != labels.len() {
+            return Err(format!(
+                "the shadow model has {} class names but the primary has {} labels",
+                class_names.len(),
+                labels.len()
+            ));
+        }
+        if let Some((class_id, (name, label))) = class_names
+            .iter()
+            .zip(labels)
+            .enumerate()
+            .find(|(_, (name, label))| **name != label.label)
+        {
+            return Err(format!(
+                "class {} is {} in the shadow model but {} in the primary",
+                class_id, name, label.label
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+/// Reads the shadow model's metadata and checks its classes against the primary's
+/// labels, at startup and by `--check-config`.
+pub fn check_model(model_cfg: &ModelConfig, labels: &[ColorLabel]) -> Result<(), String> {
+    let shadow_info = OnnxModelInfo::from_path(&model_cfg.get_path())?;
+    check_classes(&shadow_info, labels)
+}
+
+#[derive(Serialize)]
+struct Dump {
+    agreement: Agreement,
+    primary: PredictionResponse,
+    shadow: PredictionResponse,
+}
+
+/// A frame picked for the shadow model, holding its concurrency slot.
+#[derive(Debug)]
+pub struct ShadowFrame {
+    frame: ImageFrame,
+    _permit: OwnedSemaphorePermit,
+}
+
+/// Candidate model evaluated on a sample of the production frames. Its predictions
+/// run in the background once the primary result is known, they never delay nor
+/// change the response.
+#[derive(Debug)]
+pub struct ShadowModel<M: ModelService> {
+    model_service: M,
+    sample_ratio: f64,
+    iou_threshold: f32,
+    frames: AtomicU64,
+    in_flight: Arc<Semaphore>,
+    dump_dir: Option<PathBuf>,
+    dumps_left: AtomicUsize,
+    metrics: Arc<Metrics>,
+}
+
+impl<M: ModelService> ShadowModel<M> {
+    /// `metrics` are labeled with the shadow model, the model service should record to them too.
+    pub fn new(model_service: M, shadow_cfg: &ShadowConfig, metrics: Arc<Metrics>) -> Self {
+        Self {
+            model_service,
+            sample_ratio: shadow_cfg.sample_ratio,
+            iou_threshold: shadow_cfg.iou_threshold,
+            frames: AtomicU64::new(0),
+            in_flight: Arc::new(Semaphore::new(shadow_cfg.max_concurrent)),
+            dump_dir: shadow_cfg.dump_dir.clone(),
+            dumps_left: AtomicUsize::new(shadow_cfg.max_dumps),
+            metrics,
+        }
+    }
+
+    /// Picks every n-th frame to follow `sample_ratio` exactly, rather than at random.
+    /// Sampled frames are skipped while `max_concurrent` shadow predictions are running.
+    pub fn sample(&self, frame: &ImageFrame) -> Option<ShadowFrame> {
+        let n = self.frames.fetch_add(1, Ordering::Relaxed) as f64;
+        if ((n + 1.) * self.sample_ratio).floor() <= (n * self.sample_ratio).floor() {
+            return None;
+        }
+
+        let Ok(permit) = self.in_flight.clone().try_acquire_owned() else {
+            self.metrics.record_shadow_frame("skipped");
+            return None;
+        };
+        Some(ShadowFrame {
+            frame: ImageFrame {
+                extras: None,
+                ..frame.clone()
+            },
+            _permit: permit,
+        })
+    }
+
+    /// Runs the frame on the shadow model on a blocking thread, bounded by
+    /// `max_concurrent` through the frame's permit, and compares the result with the
+    /// primary's, post-processed the same way.
+    pub fn mirror(
+        self: &Arc<Self>,
+        shadow_frame: ShadowFrame,
+        primary: PredictionBatch,
+        primary_latency: Duration,
+        region_filter: RegionFilter,
+        settings: Arc<LiveSettings>,
+    ) {
+        let shadow = self.clone();
+        let span = tracing::info_span!("shadow");
+        tokio::spawn(
+            async move {
+                let ShadowFrame { frame, _permit } = shadow_frame;
+                let image_data = shadow.dump_dir.is_some().then(|| frame.image_data.clone());
+
+                // Inference blocks, it must not hold a worker the primary requests need
+                let model_service = shadow.model_service.clone();
+                let runtime = tokio::runtime::Handle::current();
+                let started = Instant::now();
+                let predicted = tokio::task::spawn_blocking(move || {
+                    runtime.block_on(model_service.predict(frame, Deadline::default()))
+                })
+                .await;
+                let mut batch = match predicted {
+                    Ok(Ok(batch)) => batch,
+                    Ok(Err(e)) => {
+                        tracing::warn!("Shadow prediction failed: {}", e);
+                        shadow.metrics.record_shadow_frame("error");
+                        return;
+                    }
+                    Err(e) => {
+                        tracing::warn!("Shadow prediction task failed: {}", e);
+                        shadow.metrics.record_shadow_frame("error");
+                        return;
+                    }
+                };
+                shadow
+                    .metrics
+                    .record_shadow_latency_delta(started.elapsed(), primary_latency);
+
+                batch.detections =
+                    region_filter.apply(batch.detections, batch.image_width, batch.image_height);
+                batch.detections = settings.class_remapper().apply(batch.detections);
+
+                let agreement =
+                    Agreement::new(&primary.detections, &batch.detections, shadow.iou_threshold);
+                shadow.record(&agreement);
+                if let Some(image_data) = image_data.filter(|_| !agreement.agrees()) {
+                    let dump = Dump {
+                        agreement,
+                        primary: PredictionResponse::new(primary, &settings.current().labels),
+                        shadow: PredictionResponse::new(batch, &settings.current().labels),
+                    };
+                    shadow.dump(&image_data, &dump).await;
+                }
+            }
+            .instrument(span),
+        );
+    }
+
+    fn record(&self, agreement: &Agreement) {
+        let result = if agreement.agrees() {
+            "agree"
+        } else {
+            "disagree"
+        };
+        self.metrics.record_shadow_frame(result);
+        self.metrics
+            .record_shadow_boxes("matched", agreement.matched - agreement.class_mismatches);
+        self.metrics
+            .record_shadow_boxes("class_mismatch", agreement.class_mismatches);
+        self.metrics
+            .record_shadow_boxes("primary_only", agreement.primary_only);
+        self.metrics
+            .record_shadow_boxes("shadow_only", agreement.shadow_only);
+    }
+
+    /// Writes the image as it was received and a JSON file with both results, named
+    /// after the time the frame was dumped.
+    async fn dump(&self, image_data: &[u8], dump: &Dump) {
+        let Some(dump_dir) = &self.dump_dir else {
+            return;
+        };
+        if self
+            .dumps_left
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |left| {
+                left.checked_sub(1)
+            })
+            .is_err()
+        {
+            return;
+        }
+
+        let millis = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        let name = format!("{}_{}", millis, self.frames.load(Ordering::Relaxed));
+        let extension = image::guess_format(image_data)
+            .ok()
+            .and_then(|format| format.extensions_str().first().copied())
+            .unwrap_or("bin");
+
+        let json = serde_json::to_vec_pretty(dump).unwrap();
+        let written = async {
+            tokio::fs::write(dump_dir.join(format!("{}.{}", name, extension)), image_data).await?;
+            tokio::fs::write(dump_dir.join(format!("{}.json", name)), json).await
+        };
+        if let Err(e) = written.await {
+            tracing::warn!("Failed to dump shadow frame to {:?}: {}", dump_dir, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::ServiceError;
+    use tonic::async_trait;
+
+    #[derive(Debug, Clone)]
+    struct MockModelService;
+
+    #[async_trait]
+    impl ModelService for MockModelService {
+        async fn predict(
+            &self,
+            _frame: ImageFrame,
+            _deadline: Deadline,
+        ) -> Result<PredictionBatch, ServiceError> {
+            Ok(PredictionBatch::default())
+        }
+    }
+
+    fn bbox(x1: f32, class_id: i32) -> BoundingBox {
+        BoundingBox {
+            x1,
+            y1: 0.,
+            x2: x1 + 10.,
+            y2: 10.,
+            class_id,
+            confidence: 0.9,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_agreement() {
+        let primary = [bbox(0., 0), bbox(20., 1), bbox(100., 2)];
+        let shadow = [bbox(1., 0), bbox(21., 3), bbox(200., 2)];
+
+        let agreement = Agreement::new(&primary, &shadow, 0.5);
+        assert_eq!(
+            agreement,
+            Agreement {
+                matched: 2,
+                class_mismatches: 1,
+                primary_only: 1,
+                shadow_only: 1,
+            }
+        );
+        assert!(!agreement.agrees());
+        assert!(Agreement::new(&primary, &primary, 0.5).agrees());
+        assert!(Agreement::new(&[], &[], 0.5).agrees());
+    }
+
+    #[test]
+    fn test_check_classes() {
+        let labels: Vec<_> = ["person", "car"]
+            .iter()
+            .map(|name| ColorLabel {
+                label: name.to_string(),
+                ..Default::default()
+            })
+            .collect();
+        let shadow_info = |class_names: Option<&[&str]>, num_classes| OnnxModelInfo {
+            class_names: class_names
+                .map(|names| names.iter().map(|name| name.to_string()).collect()),
+            num_classes,
+        };
+
+        assert!(check_classes(&shadow_info(Some(&["person", "car"]), Some(2)), &labels).is_ok());
+        assert!(check_classes(&shadow_info(None, None), &labels).is_ok());
+        assert!(check_classes(&shadow_info(None, Some(3)), &labels).is_err());
+        assert!(check_classes(&shadow_info(Some(&["car", "person"]), Some(2)), &labels).is_err());
+        assert!(check_classes(&shadow_info(Some(&["person"]), None), &labels).is_err());
+    }
+
+    #[test]
+    fn test_sample() {
+        let shadow_cfg = ShadowConfig {
+            onnx_file: "shadow.onnx".to_string(),
+            model_dir: None,
+            num_instances: 1,
+            sample_ratio: 0.25,
+            max_concurrent: 1,
+            iou_threshold: 0.5,
+            dump_dir: None,
+            max_dumps: 0,
+        };
+        let shadow = ShadowModel::new(
+            MockModelService,
+            &shadow_cfg,
+            Arc::new(Metrics::new("test")),
+        );
+        let frame = ImageFrame::default();
+
+        let sampled: Vec<_> = (0..8).map(|_| shadow.sample(&frame).is_some()).collect();
+        assert_eq!(
+            sampled,
+            [false, false, false, true, false, false, false, true]
+        );
+
+        // Frames sampled while the shadow model is busy are skipped
+        let busy: Vec<_> = (0..8).filter_map(|_| shadow.sample(&frame)).collect();
+        assert_eq!(busy.len(), 1);
+    }
+}
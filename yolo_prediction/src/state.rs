@@ -1,75 +1,71 @@
-use crate::config::{LabelsConfig, Validatable};
-use std::{
-    fs::File,
-    io::{self, BufRead},
-    path::PathBuf,
+use crate::{
+    config::{LabelsConfig, ModelConfig, Validatable},
+    error::ServiceError,
+    labels::load_labels,
+    onnx_metadata::OnnxModelInfo,
+    palette::generate_color_labels,
+    settings::LiveSettings,
 };
-use yolo_proto::ColorLabel;
+use std::sync::Arc;
 
 pub trait State: Send + Sync + 'static {
-    fn new(labels_file: &LabelsConfig) -> Result<Self, String>
+    fn new(
+        labels_cfg: Option<&LabelsConfig>,
+        model_cfg: &ModelConfig,
+    ) -> Result<Self, ServiceError>
     where
         Self: Sized;
-    fn get_labels(&self) -> &Vec<ColorLabel>;
+    fn get_settings(&self) -> &Arc<LiveSettings>;
 }
 
 #[derive(Debug)]
 pub struct ServiceState {
-    class_labels: Vec<ColorLabel>,
+    settings: Arc<LiveSettings>,
 }
 
 impl State for ServiceState {
-    fn new(labels_cfg: &LabelsConfig) -> Result<ServiceState, String> {
-        match load_yolov8_labels(&labels_cfg.get_path()) {
-            Ok(labels) => Ok(ServiceState {
-                class_labels: labels,
-            }),
-            Err(e) => Err(format!("Failed to load labels: {}", e)),
-        }
-    }
-
-    fn get_labels(&self) -> &Vec<ColorLabel> {
-        &self.class_labels
-    }
-}
+    fn new(
+        labels_cfg: Option<&LabelsConfig>,
+        model_cfg: &ModelConfig,
+    ) -> Result<ServiceState, ServiceError> {
+        let model_info = OnnxModelInfo::from_path(&model_cfg.get_path())
+            .map_err(ServiceError::ModelUnavailable)?;
 
-pub fn load_yolov8_labels(filepath: &PathBuf) -> io::Result<Vec<ColorLabel>> {
-    let file = File::open(filepath)?;
-    let reader = io::BufReader::new(file);
-    let mut color_labels = Vec::new();
+        let class_labels = match labels_cfg {
+            Some(labels_cfg) => load_labels(&labels_cfg.get_path(), &model_cfg.label_palette)
+                .map_err(|e| {
+                    ServiceError::ModelUnavailable(format!("Failed to load labels: {}", e))
+                })?,
+            None => {
+                let names = model_info.class_names.as_ref().ok_or_else(|| {
+                    ServiceError::ModelUnavailable(
+                        "No labels file configured and the model metadata has no `names` entry"
+                            .to_string(),
+                    )
+                })?;
+                tracing::info!("Loaded {} class labels from model metadata", names.len());
+                generate_color_labels(names, &model_cfg.label_palette)
+            }
+        };
 
-    for line_result in reader.lines() {
-        let line = line_result?;
-        let parts: Vec<&str> = line.split(",").collect();
-
-        if parts.len() == 4 {
-            let label = parts[0].trim().to_string();
-            let red: u32 = parts[1]
-                .trim()
-                .parse()
-                .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Invalid red value"))?;
-            let green: u32 = parts[2]
-                .trim()
-                .parse()
-                .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Invalid green value"))?;
-            let blue: u32 = parts[3]
-                .trim()
-                .parse()
-                .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Invalid blue value"))?;
-
-            color_labels.push(ColorLabel {
-                label,
-                red,
-                green,
-                blue,
-            });
-        } else {
-            return Err(io::Error::new(
-                io::ErrorKind::InvalidData,
-                format!("Invalid line format: {}", line),
-            ));
+        if let Some(num_classes) = model_info.num_classes {
+            if num_classes != class_labels.len() {
+                return Err(ServiceError::ModelUnavailable(format!(
+                    "Label count mismatch: the model predicts {} classes but {} labels were loaded",
+                    num_classes,
+                    class_labels.len()
+                )));
+            }
         }
+        let settings =
+            LiveSettings::new(model_cfg, class_labels).map_err(ServiceError::InvalidSettings)?;
+
+        Ok(ServiceState {
+            settings: Arc::new(settings),
+        })
     }
 
-    Ok(color_labels)
+    fn get_settings(&self) -> &Arc<LiveSettings> {
+        &self.settings
+    }
 }
@@ -0,0 +1,407 @@
+use crate::config::{MetricsConfig, TracingConfig};
+use opentelemetry::{
+    global,
+    metrics::{Counter, Histogram},
+    propagation::Extractor,
+    Context, KeyValue,
+};
+use opentelemetry_sdk::{
+    metrics::SdkMeterProvider,
+    propagation::TraceContextPropagator,
+    trace::{Sampler, SdkTracerProvider},
+    Resource,
+};
+use std::time::Duration;
+use tonic::{
+    metadata::{KeyRef, MetadataMap},
+    Code,
+};
+
+/// Exports spans over OTLP, returns `None` when tracing export is disabled.
+/// W3C `traceparent` headers are used to join the client's trace.
+pub fn init_tracer(tracing_cfg: &TracingConfig) -> Result<Option<SdkTracerProvider>, String> {
+    global::set_text_map_propagator(TraceContextPropagator::new());
+
+    if !tracing_cfg.otlp {
+        return Ok(None);
+    }
+
+    // Uses OTEL_EXPORTER_OTLP_ENDPOINT as well
+    let exporter = opentelemetry_otlp::SpanExporter::builder()
+        .with_tonic()
+        .build()
+        .map_err(|e| format!("failed to create OTLP span exporter: {}", e))?;
+
+    let provider = SdkTracerProvider::builder()
+        .with_batch_exporter(exporter)
+        .with_sampler(Sampler::ParentBased(Box::new(Sampler::TraceIdRatioBased(
+            tracing_cfg.sample_ratio,
+        ))))
+        .with_resource(
+            Resource::builder()
+                .with_service_name("yolo_prediction")
+                .build(),
+        )
+        .build();
+
+    global::set_tracer_provider(provider.clone());
+    Ok(Some(provider))
+}
+
+struct MetadataExtractor<'a>(&'a MetadataMap);
+
+impl Extractor for MetadataExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|value| value.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0
+            .keys()
+            .map(|key| match key {
+                KeyRef::Ascii(key) => key.as_str(),
+                KeyRef::Binary(key) => key.as_str(),
+            })
+            .collect()
+    }
+}
+
+/// Trace context sent by the client in the gRPC metadata.
+pub fn extract_context(metadata: &MetadataMap) -> Context {
+    global::get_text_map_propagator(|propagator| propagator.extract(&MetadataExtractor(metadata)))
+}
+
+/// Installs the global meter provider. Until it is called, instruments are no-ops,
+/// which keeps unit tests from exporting anything. Fails when the Prometheus endpoint
+/// cannot be bound.
+pub fn init_metrics(metrics_cfg: &MetricsConfig) -> Result<(), String> {
+    let mut provider = SdkMeterProvider::builder().with_resource(
+        Resource::builder()
+            .with_service_name("yolo_prediction")
+            .build(),
+    );
+
+    if metrics_cfg.otlp {
+        // Configure OTLP exporter to push metrics to collector
+        // Uses OTEL_EXPORTER_OTLP_ENDPOINT environment variable
+        // Default: http://localhost:4317
+        let exporter = opentelemetry_otlp::MetricExporter::builder()
+            .with_tonic()
+            .build()
+            .map_err(|e| format!("failed to create OTLP metric exporter: {}", e))?;
+
+        let reader = opentelemetry_sdk::metrics::PeriodicReader::builder(exporter)
+            .with_interval(std::time::Duration::from_secs(5))
+            .build();
+        provider = provider.with_reader(reader);
+    }
+
+    if let Some(address) = &metrics_cfg.prometheus_address {
+        provider = with_prometheus(provider, address)?;
+    }
+
+    global::set_meter_provider(provider.build());
+    Ok(())
+}
+
+#[cfg(feature = "prometheus")]
+fn with_prometheus(
+    provider: opentelemetry_sdk::metrics::MeterProviderBuilder,
+    address: &str,
+) -> Result<opentelemetry_sdk::metrics::MeterProviderBuilder, String> {
+    use prometheus::{Encoder, TextEncoder};
+
+    let registry = prometheus::Registry::new();
+    let exporter = opentelemetry_prometheus::exporter()
+        .with_registry(registry.clone())
+        .build()
+        .map_err(|e| format!("failed to create Prometheus exporter: {}", e))?;
+
+    let address: std::net::SocketAddr = address
+        .parse()
+        .map_err(|e| format!("invalid Prometheus address {}: {}", address, e))?;
+
+    let app = axum::Router::new().route(
+        "/metrics",
+        axum::routing::get(move || {
+            let registry = registry.clone();
+            async move {
+                let mut body = Vec::new();
+                TextEncoder::new()
+                    .encode(&registry.gather(), &mut body)
+                    .map(|_| body)
+                    .map_err(|e| (axum::http::StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))
+            }
+        }),
+    );
+
+    // Bound here so a busy address stops the startup
+    let listener = std::net::TcpListener::bind(address)
+        .and_then(|listener| {
+            listener.set_nonblocking(true)?;
+            tokio::net::TcpListener::from_std(listener)
+        })
+        .map_err(|e| format!("failed to bind Prometheus endpoint {}: {}", address, e))?;
+    tracing::info!("Prometheus metrics available on http://{}/metrics", address);
+    tokio::spawn(async move {
+        if let Err(e) = axum::serve(listener, app).await {
+            tracing::error!("Prometheus endpoint failed: {}", e);
+        }
+    });
+
+    Ok(provider.with_reader(exporter))
+}
+
+#[cfg(not(feature = "prometheus"))]
+fn with_prometheus(
+    _provider: opentelemetry_sdk::metrics::MeterProviderBuilder,
+    _address: &str,
+) -> Result<opentelemetry_sdk::metrics::MeterProviderBuilder, String> {
+    Err("The Prometheus endpoint is not compiled in, rebuild with `--features prometheus`".into())
+}
+
+const DURATION_BOUNDARIES_MS: [f64; 19] = [
+    0.1, 0.25, 0.5, 1., 2.5, 5., 10., 15., 20., 30., 40., 50., 75., 100., 150., 200., 300., 500.,
+    1000.,
+];
+
+const DETECTION_BOUNDARIES: [f64; 9] = [0., 1., 2., 5., 10., 20., 50., 100., 300.];
+
+const LATENCY_DELTA_BOUNDARIES_MS: [f64; 17] = [
+    -500., -200., -100., -50., -20., -10., -5., -1., 0., 1., 5., 10., 20., 50., 100., 200., 500.,
+];
+
+#[derive(Debug)]
+pub struct Metrics {
+    model: KeyValue,
+    decode_duration: Histogram<f64>,
+    preprocess_duration: Histogram<f64>,
+    inference_duration: Histogram<f64>,
+    nms_duration: Histogram<f64>,
+    session_busy_time: Counter<f64>,
+    // Only the ort sessions have a queue, tract shares a single plan
+    #[cfg(feature = "ort")]
+    session_queue_depth: opentelemetry::metrics::UpDownCounter<i64>,
+    detections_per_frame: Histogram<u64>,
+    errors: Counter<u64>,
+    rejected_requests: Counter<u64>,
+    cache_lookups: Counter<u64>,
+    shadow_frames: Counter<u64>,
+    shadow_boxes: Counter<u64>,
+    shadow_latency_delta: Histogram<f64>,
+    recorded_requests: Counter<u64>,
+}
+
+impl Metrics {
+    pub fn new(model_name: &str) -> Self {
+        let meter = global::meter("yolo_prediction");
+
+        let duration_histogram = |name: &'static str, description: &'static str| {
+            meter
+                .f64_histogram(name)
+                .with_unit("ms")
+                .with_boundaries(DURATION_BOUNDARIES_MS.to_vec())
+                .with_description(description)
+                .build()
+        };
+
+        Metrics {
+            model: KeyValue::new("model", model_name.to_string()),
+            decode_duration: duration_histogram(
+                "decode_duration_ms",
+                "Duration of image decoding in milliseconds",
+            ),
+            preprocess_duration: duration_histogram(
+                "preprocess_duration_ms",
+                "Duration of resizing and tensor conversion in milliseconds",
+            ),
+            inference_duration: duration_histogram(
+                "inference_duration_ms",
+                "Duration of model inference in milliseconds",
+            ),
+            nms_duration: duration_histogram(
+                "nms_duration_ms",
+                "Duration of non-maximum suppression in milliseconds",
+            ),
+            session_busy_time: meter
+                .f64_counter("session_busy_time_ms")
+                .with_unit("ms")
+                .with_description("Time each inference session spent running the model")
+                .build(),
+            #[cfg(feature = "ort")]
+            session_queue_depth: meter
+                .i64_up_down_counter("session_queue_depth")
+                .with_description("Requests waiting for each inference session")
+                .build(),
+            detections_per_frame: meter
+                .u64_histogram("detections_per_frame")
+                .with_boundaries(DETECTION_BOUNDARIES.to_vec())
+                .with_description("Detections returned for each frame")
+                .build(),
+            errors: meter
+                .u64_counter("errors_total")
+                .with_description("Failed requests by gRPC status code")
+                .build(),
+            rejected_requests: meter
+                .u64_counter("rejected_requests_total")
+                .with_description("Requests rejected by rate limiting or load shedding")
+                .build(),
+            cache_lookups: meter
+                .u64_counter("result_cache_lookups_total")
+                .with_description("Result cache lookups by outcome, hit or miss")
+                .build(),
+            shadow_frames: meter
+                .u64_counter("shadow_frames_total")
+                .with_description(
+                    "Frames mirrored to the shadow model by outcome: agree, disagree, error or skipped",
+                )
+                .build(),
+            shadow_boxes: meter
+                .u64_counter("shadow_boxes_total")
+                .with_description(
+                    "Boxes compared with the primary model: matched, class_mismatch, primary_only or shadow_only",
+                )
+                .build(),
+            shadow_latency_delta: meter
+                .f64_histogram("shadow_latency_delta_ms")
+                .with_unit("ms")
+                .with_boundaries(LATENCY_DELTA_BOUNDARIES_MS.to_vec())
+                .with_description("Shadow model latency minus the primary's, in milliseconds")
+                .build(),
+            recorded_requests: meter
+                .u64_counter("recorded_requests_total")
+                .with_description(
+                    "Requests sent to the recorder by outcome: written, failed, or dropped when it lags behind",
+                )
+                .build(),
+        }
+    }
+
+    fn attributes(&self, extra: Option<KeyValue>) -> Vec<KeyValue> {
+        std::iter::once(self.model.clone()).chain(extra).collect()
+    }
+
+    pub fn record_decode_duration(&self, duration: Duration) {
+        self.decode_duration
+            .record(duration.as_secs_f64() * 1000., &self.attributes(None));
+    }
+
+    pub fn record_preprocess_duration(&self, duration: Duration) {
+        self.preprocess_duration
+            .record(duration.as_secs_f64() * 1000., &self.attributes(None));
+    }
+
+    pub fn record_inference_duration(&self, duration: Duration) {
+        self.inference_duration
+            .record(duration.as_secs_f64() * 1000., &self.attributes(None));
+    }
+
+    pub fn record_nms_duration(&self, duration: Duration) {
+        self.nms_duration
+            .record(duration.as_secs_f64() * 1000., &self.attributes(None));
+    }
+
+    pub fn record_session_busy_time(&self, session: usize, duration: Duration) {
+        let attributes = self.attributes(Some(KeyValue::new("session", session as i64)));
+        self.session_busy_time
+            .add(duration.as_secs_f64() * 1000., &attributes);
+    }
+
+    /// `delta` is +1 when a request starts waiting for the session, -1 once it got it.
+    #[cfg(feature = "ort")]
+    pub fn record_session_queue_change(&self, session: usize, delta: i64) {
+        let attributes = self.attributes(Some(KeyValue::new("session", session as i64)));
+        self.session_queue_depth.add(delta, &attributes);
+    }
+
+    pub fn record_detections(&self, count: usize) {
+        self.detections_per_frame
+            .record(count as u64, &self.attributes(None));
+    }
+
+    pub fn record_error(&self, code: Code) {
+        let attributes = self.attributes(Some(KeyValue::new("code", format!("{:?}", code))));
+        self.errors.add(1, &attributes);
+    }
+
+    pub fn record_rejected_request(&self, reason: &str, client: &str) {
+        let attributes = vec![
+            self.model.clone(),
+            KeyValue::new("reason", reason.to_string()),
+            KeyValue::new("client", client.to_string()),
+        ];
+        self.rejected_requests.add(1, &attributes);
+    }
+
+    pub fn record_cache_lookup(&self, hit: bool) {
+        let result = if hit { "hit" } else { "miss" };
+        let attributes = self.attributes(Some(KeyValue::new("result", result)));
+        self.cache_lookups.add(1, &attributes);
+    }
+
+    pub fn record_shadow_frame(&self, result: &'static str) {
+        let attributes = self.attributes(Some(KeyValue::new("result", result)));
+        self.shadow_frames.add(1, &attributes);
+    }
+
+    pub fn record_shadow_boxes(&self, result: &'static str, count: usize) {
+        let attributes = self.attributes(Some(KeyValue::new("result", result)));
+        self.shadow_boxes.add(count as u64, &attributes);
+    }
+
+    pub fn record_recorded_request(&self, result: &'static str) {
+        let attributes = self.attributes(Some(KeyValue::new("result", result)));
+        self.recorded_requests.add(1, &attributes);
+    }
+
+    pub fn record_shadow_latency_delta(&self, shadow: Duration, primary: Duration) {
+        let delta = shadow.as_secs_f64() - primary.as_secs_f64();
+        self.shadow_latency_delta
+            .record(delta * 1000., &self.attributes(None));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use opentelemetry::trace::TraceContextExt;
+
+    #[test]
+    fn test_extract_context() {
+        global::set_text_map_propagator(TraceContextPropagator::new());
+
+        let mut metadata = MetadataMap::new();
+        metadata.insert(
+            "traceparent",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01"
+                .parse()
+                .unwrap(),
+        );
+
+        let context = extract_context(&metadata);
+        let span_context = context.span().span_context().clone();
+
+        assert!(span_context.is_remote());
+        assert_eq!(
+            span_context.trace_id().to_string(),
+            "4bf92f3577b34da6a3ce929d0e0e4736"
+        );
+        assert!(!extract_context(&MetadataMap::new())
+            .span()
+            .span_context()
+            .is_valid());
+    }
+
+    #[cfg(feature = "prometheus")]
+    #[test]
+    fn test_init_metrics_busy_address() {
+        let busy = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let metrics_cfg = MetricsConfig {
+            otlp: false,
+            prometheus_address: Some(busy.local_addr().unwrap().to_string()),
+        };
+
+        let err = init_metrics(&metrics_cfg).unwrap_err();
+        assert!(err.starts_with("failed to bind Prometheus endpoint"));
+    }
+}
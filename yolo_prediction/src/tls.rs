@@ -0,0 +1,200 @@
+use crate::config::TlsConfig;
+use rustls::{
+    crypto::ring,
+    pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer},
+    server::WebPkiClientVerifier,
+    RootCertStore, ServerConfig,
+};
+use std::{
+    io,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+    time::{Duration, SystemTime},
+};
+use tokio::{
+    net::{TcpListener, TcpStream},
+    sync::mpsc,
+    time::timeout,
+};
+use tokio_rustls::{server::TlsStream, TlsAcceptor};
+use tokio_stream::wrappers::ReceiverStream;
+
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub type TlsIncoming = ReceiverStream<io::Result<TlsStream<TcpStream>>>;
+
+fn load_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>, String> {
+    let certs = CertificateDer::pem_file_iter(path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| format!("Failed to read certificates from {:?}: {}", path, e))?;
+
+    if certs.is_empty() {
+        return Err(format!("No certificate found in {:?}", path));
+    }
+    Ok(certs)
+}
+
+/// Builds the rustls server config from the PEM files on disk. A client CA turns
+/// on mutual TLS: connections without a certificate signed by it are refused.
+pub fn load_server_config(tls_cfg: &TlsConfig) -> Result<Arc<ServerConfig>, String> {
+    let provider = Arc::new(ring::default_provider());
+
+    let certs = load_certs(&tls_cfg.cert_file)?;
+    let key = PrivateKeyDer::from_pem_file(&tls_cfg.key_file).map_err(|e| {
+        format!(
+            "Failed to read private key from {:?}: {}",
+            tls_cfg.key_file, e
+        )
+    })?;
+
+    let builder = ServerConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()
+        .map_err(|e| e.to_string())?;
+
+    let builder = match &tls_cfg.client_ca_file {
+        Some(client_ca_file) => {
+            let mut roots = RootCertStore::empty();
+            for cert in load_certs(client_ca_file)? {
+                roots
+                    .add(cert)
+                    .map_err(|e| format!("Invalid client CA {:?}: {}", client_ca_file, e))?;
+            }
+            let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider)
+                .build()
+                .map_err(|e| format!("Invalid client CA {:?}: {}", client_ca_file, e))?;
+            builder.with_client_cert_verifier(verifier)
+        }
+        None => builder.with_no_client_auth(),
+    };
+
+    let mut server_config = builder
+        .with_single_cert(certs, key)
+        .map_err(|e| format!("Invalid server certificate or key: {}", e))?;
+    server_config.alpn_protocols = vec![b"h2".to_vec()];
+
+    Ok(Arc::new(server_config))
+}
+
+/// Modification times of every file the TLS config reads, used to detect rotations.
+fn modified_times(tls_cfg: &TlsConfig) -> Vec<Option<SystemTime>> {
+    let files: Vec<&PathBuf> = [&tls_cfg.cert_file, &tls_cfg.key_file]
+        .into_iter()
+        .chain(&tls_cfg.client_ca_file)
+        .collect();
+
+    files
+        .into_iter()
+        .map(|path| std::fs::metadata(path).and_then(|m| m.modified()).ok())
+        .collect()
+}
+
+/// Polls the certificate files and swaps in a new config when they change. New
+/// connections pick it up, established ones keep the certificate they negotiated.
+/// A failed reload (e.g. a cert written before its key) keeps the current config
+/// and is retried on the next tick.
+fn spawn_reload_task(tls_cfg: TlsConfig, current: Arc<RwLock<Arc<ServerConfig>>>) {
+    tokio::spawn(async move {
+        let mut interval =
+            tokio::time::interval(Duration::from_secs(tls_cfg.reload_interval_secs.max(1)));
+        let mut last_modified = modified_times(&tls_cfg);
+
+        loop {
+            interval.tick().await;
+
+            let modified = modified_times(&tls_cfg);
+            if modified == last_modified {
+                continue;
+            }
+
+            match load_server_config(&tls_cfg) {
+                Ok(server_config) => {
+                    *current.write().unwrap() = server_config;
+                    last_modified = modified;
+                    tracing::info!("Reloaded TLS certificates from {:?}", tls_cfg.cert_file);
+                }
+                Err(e) => tracing::warn!("Failed to reload TLS certificates: {}", e),
+            }
+        }
+    });
+}
+
+/// Accepts TCP connections and yields them once the TLS handshake completes.
+/// Handshakes run concurrently so a slow or failing client does not block others.
+pub fn incoming(listener: TcpListener, tls_cfg: TlsConfig) -> Result<TlsIncoming, String> {
+    let current = Arc::new(RwLock::new(load_server_config(&tls_cfg)?));
+    if tls_cfg.reload_interval_secs > 0 {
+        spawn_reload_task(tls_cfg, current.clone());
+    }
+
+    let (tx, rx) = mpsc::channel(128);
+    tokio::spawn(async move {
+        loop {
+            let (stream, peer_addr) = tokio::select! {
+                _ = tx.closed() => break,
+                accepted = listener.accept() => match accepted {
+                    Ok(accepted) => accepted,
+                    Err(e) => {
+                        tracing::warn!("Failed to accept connection: {}", e);
+                        continue;
+                    }
+                },
+            };
+
+            let _ = stream.set_nodelay(true);
+            let acceptor = TlsAcceptor::from(current.read().unwrap().clone());
+            let tx = tx.clone();
+            tokio::spawn(async move {
+                match timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                    Ok(Ok(tls_stream)) => {
+                        let _ = tx.send(Ok(tls_stream)).await;
+                    }
+                    Ok(Err(e)) => tracing::debug!("TLS handshake with {} failed: {}", peer_addr, e),
+                    Err(_) => tracing::debug!("TLS handshake with {} timed out", peer_addr),
+                }
+            });
+        }
+    });
+
+    Ok(ReceiverStream::new(rx))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rcgen::{generate_simple_self_signed, CertifiedKey};
+
+    fn write_self_signed(name: &str) -> (PathBuf, PathBuf) {
+        let CertifiedKey { cert, signing_key } =
+            generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let dir = std::env::temp_dir();
+        let cert_file = dir.join(format!("yolo_prediction_test_{}.crt", name));
+        let key_file = dir.join(format!("yolo_prediction_test_{}.key", name));
+        std::fs::write(&cert_file, cert.pem()).unwrap();
+        std::fs::write(&key_file, signing_key.serialize_pem()).unwrap();
+        (cert_file, key_file)
+    }
+
+    #[test]
+    fn test_load_server_config() {
+        let (cert_file, key_file) = write_self_signed("server");
+        let (client_ca_file, other_key_file) = write_self_signed("client_ca");
+
+        let mut tls_cfg = TlsConfig {
+            cert_file,
+            key_file,
+            client_ca_file: None,
+            reload_interval_secs: 0,
+        };
+        assert!(load_server_config(&tls_cfg).is_ok());
+
+        tls_cfg.client_ca_file = Some(client_ca_file);
+        assert!(load_server_config(&tls_cfg).is_ok());
+
+        // Key that does not belong to the certificate
+        tls_cfg.key_file = other_key_file;
+        assert!(load_server_config(&tls_cfg).is_err());
+
+        tls_cfg.key_file = PathBuf::from("does_not_exist.key");
+        assert!(load_server_config(&tls_cfg).is_err());
+    }
+}
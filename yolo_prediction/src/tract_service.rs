@@ -0,0 +1,128 @@
+use crate::{
+    config::{ImageConfig, ModelConfig, Validatable},
+    error::ServiceError,
+    extras::{wants_scores, CropOptions},
+    model_service::{Deadline, ModelService},
+    postprocessing::{decode_detections, non_max_suppression},
+    preprocessing::transform_image_frame,
+    settings::LiveSettings,
+    telemetry::Metrics,
+};
+use image::GenericImageView;
+use ndarray::{Array, Ix4};
+use std::{sync::Arc, time::Instant};
+use tonic::async_trait;
+use tract_onnx::prelude::*;
+use yolo_proto::{ImageFrame, PredictionBatch};
+
+type TractModel = TypedRunnableModel;
+
+/// Pure-Rust inference backend. The optimized plan is immutable and can be run
+/// concurrently, so a single instance is shared across requests.
+#[derive(Clone)]
+pub struct TractModelService {
+    model: Arc<TractModel>,
+    settings: Arc<LiveSettings>,
+    image_config: ImageConfig,
+    metrics: Arc<Metrics>,
+}
+
+impl TractModelService {
+    pub fn new(
+        model_config: &ModelConfig,
+        image_config: &ImageConfig,
+        settings: Arc<LiveSettings>,
+        metrics: Arc<Metrics>,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let model = tract_onnx::onnx()
+            .model_for_path(model_config.get_path())?
+            .with_input_fact(0, f32::fact([1, 3, 640, 640]).into())?
+            .into_optimized()?
+            .into_runnable()?;
+
+        tracing::info!("Loaded tract model from {:?}", model_config.get_path());
+
+        Ok(Self {
+            model,
+            settings,
+            image_config: image_config.clone(),
+            metrics,
+        })
+    }
+
+    pub fn run_inference(
+        &self,
+        input: &Array<f32, Ix4>,
+    ) -> Result<ndarray::ArrayD<f32>, ServiceError> {
+        let input_data: Vec<f32> = input.iter().copied().collect();
+        let tensor = Tensor::from_shape(input.shape(), &input_data)
+            .map_err(|e| ServiceError::Inference(format!("failed to build tensor: {}", e)))?;
+
+        // The plan is shared, so it is reported as a single session without a queue
+        let started = Instant::now();
+        let outputs =
+            tracing::info_span!("inference").in_scope(|| self.model.run(tvec!(tensor.into())));
+        let elapsed = started.elapsed();
+        self.metrics.record_session_busy_time(0, elapsed);
+        self.metrics.record_inference_duration(elapsed);
+        let outputs =
+            outputs.map_err(|e| ServiceError::Inference(format!("inference failed: {}", e)))?;
+
+        let output = outputs[0]
+            .to_plain_array_view::<f32>()
+            .map_err(|e| ServiceError::Inference(format!("failed to extract tensor: {}", e)))?;
+
+        ndarray::ArrayD::from_shape_vec(output.shape(), output.iter().copied().collect())
+            .map_err(|e| ServiceError::Inference(format!("invalid tensor shape: {}", e)))
+    }
+}
+
+#[async_trait]
+impl ModelService for TractModelService {
+    async fn predict(
+        &self,
+        frame: ImageFrame,
+        deadline: Deadline,
+    ) -> Result<PredictionBatch, ServiceError> {
+        let crop_options =
+            CropOptions::from_request(frame.extras.as_ref(), &self.image_config.crops)?;
+        let keep_scores = wants_scores(frame.extras.as_ref());
+
+        deadline.check("preprocessing")?;
+        let (input, image) = transform_image_frame(&frame, &self.image_config, &self.metrics)?;
+        let (img_width, img_height) = image.dimensions();
+        // The decoded image is only kept around for the crops
+        let crop_source = crop_options.map(|crop_options| (crop_options, image));
+
+        deadline.check("inference")?;
+        let outputs = self.run_inference(&input)?;
+
+        let settings = self.settings.current();
+        let boxes = decode_detections(
+            &outputs,
+            img_width,
+            img_height,
+            &settings.class_filter,
+            keep_scores,
+        );
+
+        let started = Instant::now();
+        let mut detections = tracing::info_span!("nms")
+            .in_scope(|| non_max_suppression(boxes, settings.nms_iou_threshold));
+        self.metrics.record_nms_duration(started.elapsed());
+
+        if let Some((crop_options, image)) = crop_source {
+            tracing::info_span!("crops")
+                .in_scope(|| crop_options.apply(&image, &mut detections))?;
+        }
+
+        let prediction_batch = PredictionBatch {
+            detections,
+            image_width: img_width,
+            image_height: img_height,
+            ..Default::default()
+        };
+
+        Ok(prediction_batch)
+    }
+}
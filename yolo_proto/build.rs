@@ -8,6 +8,13 @@ fn main() -> Result<(), Box<dyn Error>> {
     tonic_prost_build::configure()
         .build_server(true)
         .file_descriptor_set_path(&descriptor_path)
-        .compile_protos(&["proto/yolo_service.proto"], &["proto"])?;
+        .compile_protos(
+            &[
+                "proto/yolo_service.proto",
+                "proto/google/rpc/status.proto",
+                "proto/google/rpc/error_details.proto",
+            ],
+            &["proto"],
+        )?;
     Ok(())
 }
@@ -0,0 +1,73 @@
+use crate::google::rpc::{BadRequest, ErrorInfo, Status as RpcStatus};
+use prost::Message;
+use prost_types::Any;
+use tonic::{metadata::MetadataMap, Code, Status};
+
+const ERROR_INFO_TYPE_URL: &str = "type.googleapis.com/google.rpc.ErrorInfo";
+const BAD_REQUEST_TYPE_URL: &str = "type.googleapis.com/google.rpc.BadRequest";
+
+/// Standard error details carried by a gRPC status, encoded as a `google.rpc.Status`
+/// in the `grpc-status-details-bin` trailer.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ErrorDetails {
+    pub error_info: Option<ErrorInfo>,
+    pub bad_request: Option<BadRequest>,
+}
+
+impl ErrorDetails {
+    /// Decodes the details of a status, detail types other than `ErrorInfo` and
+    /// `BadRequest` are ignored.
+    pub fn from_status(status: &Status) -> Self {
+        let mut details = Self::default();
+        let Ok(rpc_status) = RpcStatus::decode(status.details()) else {
+            return details;
+        };
+
+        for any in rpc_status.details {
+            match any.type_url.as_str() {
+                ERROR_INFO_TYPE_URL => {
+                    details.error_info = ErrorInfo::decode(any.value.as_slice()).ok();
+                }
+                BAD_REQUEST_TYPE_URL => {
+                    details.bad_request = BadRequest::decode(any.value.as_slice()).ok();
+                }
+                _ => {}
+            }
+        }
+        details
+    }
+
+    /// `ErrorInfo.reason`, such as `IMAGE_TOO_LARGE`
+    pub fn reason(&self) -> Option<&str> {
+        self.error_info.as_ref().map(|info| info.reason.as_str())
+    }
+
+    /// Builds a status carrying these details next to the custom metadata.
+    pub fn into_status(self, code: Code, message: String, metadata: MetadataMap) -> Status {
+        let any = |type_url: &str, value: Vec<u8>| Any {
+            type_url: type_url.to_string(),
+            value,
+        };
+        let details = self
+            .error_info
+            .map(|info| any(ERROR_INFO_TYPE_URL, info.encode_to_vec()))
+            .into_iter()
+            .chain(
+                self.bad_request
+                    .map(|bad_request| any(BAD_REQUEST_TYPE_URL, bad_request.encode_to_vec())),
+            )
+            .collect();
+
+        let rpc_status = RpcStatus {
+            code: code as i32,
+            message: message.clone(),
+            details,
+        };
+        Status::with_details_and_metadata(
+            code,
+            message,
+            rpc_status.encode_to_vec().into(),
+            metadata,
+        )
+    }
+}
@@ -1,2 +1,12 @@
 tonic::include_proto!("yolo_service");
 pub const FILE_DESCRIPTOR_SET: &[u8] = tonic::include_file_descriptor_set!("yolo");
+
+pub mod google {
+    pub mod rpc {
+        tonic::include_proto!("google.rpc");
+    }
+}
+
+mod error_details;
+
+pub use error_details::ErrorDetails;